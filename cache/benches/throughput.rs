@@ -0,0 +1,273 @@
+//! Throughput and latency of [`InRedisCache`] against twilight's
+//! [`InMemoryCache`] under realistic loads.
+//!
+//! Needs the Redis server from `docker-compose.yml` on `127.0.0.1:6379`. The
+//! Redis cache is stored in database 15, which is flushed before and after the
+//! run. Run every workload with
+//!
+//! ```text
+//! cargo bench --features testing --bench throughput
+//! ```
+//!
+//! or some of them by passing their names, like `-- guilds messages`. The
+//! workloads are:
+//!
+//! - `guilds`: a burst of `GuildCreate` events, as after connecting a shard.
+//! - `messages`: a firehose of message creates, edits and deletes.
+//! - `chunks`: member chunks of a large guild.
+//!
+//! Every event is applied to both caches and timed on its own. For each
+//! backend and event type the number of events per second and the p50 and
+//! p99 latency are printed.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    time::{Duration, Instant},
+};
+
+use cache::{
+    testing::{
+        self, GuildBuilder, MemberBuilder, MemberChunkBuilder, MessageBuilder,
+        MessageUpdateBuilder, PresenceBuilder, RoleBuilder, TextChannelBuilder,
+    },
+    Config, InRedisCache,
+};
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_model::gateway::{event::Event, payload::incoming::MessageDelete};
+
+/// Guilds in the `GuildCreate` burst.
+const GUILDS: usize = 1_000;
+const CHANNELS_PER_GUILD: usize = 10;
+const ROLES_PER_GUILD: usize = 10;
+const MEMBERS_PER_GUILD: usize = 25;
+
+/// Messages created in the firehose, every fifth is edited and every tenth
+/// deleted.
+const MESSAGES: usize = 20_000;
+const FIREHOSE_CHANNELS: usize = 20;
+
+/// Members of the chunked guild and members per chunk, like Discord sends.
+const CHUNKED_MEMBERS: usize = 50_000;
+const CHUNK_SIZE: usize = 1_000;
+
+/// Redis node and database of the Redis cache, only used by the benchmark.
+const NODE: &str = "redis://127.0.0.1/15";
+
+/// Hands out unique IDs.
+#[derive(Default)]
+struct Ids(u64);
+
+impl Ids {
+    fn next(&mut self) -> u64 {
+        self.0 += 1;
+
+        self.0
+    }
+}
+
+fn guild_burst(ids: &mut Ids) -> Vec<Event> {
+    (0..GUILDS)
+        .map(|_| {
+            let guild_id = testing::guild_id(ids.next());
+            let members = (0..MEMBERS_PER_GUILD)
+                .map(|_| MemberBuilder::new(guild_id, testing::user_id(ids.next())).build())
+                .collect::<Vec<_>>();
+            let owner_id = members[0].user.id;
+
+            let mut guild = GuildBuilder::new(guild_id, owner_id).members(members);
+
+            for position in 0..CHANNELS_PER_GUILD {
+                guild = guild.channel(
+                    TextChannelBuilder::new(guild_id, testing::channel_id(ids.next()))
+                        .position(position as i64)
+                        .build(),
+                );
+            }
+
+            for position in 0..ROLES_PER_GUILD {
+                guild = guild.role(
+                    RoleBuilder::new(testing::role_id(ids.next()))
+                        .position(position as i64)
+                        .build(),
+                );
+            }
+
+            Event::GuildCreate(Box::new(guild.create()))
+        })
+        .collect()
+}
+
+fn message_firehose(ids: &mut Ids) -> Vec<Event> {
+    let guild_id = testing::guild_id(ids.next());
+    let channels = (0..FIREHOSE_CHANNELS)
+        .map(|_| testing::channel_id(ids.next()))
+        .collect::<Vec<_>>();
+    let authors = (0..100)
+        .map(|_| testing::user_id(ids.next()))
+        .collect::<Vec<_>>();
+
+    let mut events = Vec::with_capacity(MESSAGES * 13 / 10);
+
+    for n in 0..MESSAGES {
+        let channel_id = channels[n % channels.len()];
+        let author = authors[n % authors.len()];
+        let id = testing::message_id(ids.next());
+
+        events.push(Event::MessageCreate(Box::new(
+            MessageBuilder::new(guild_id, channel_id, id, author)
+                .content(format!("message {} in a busy channel", n))
+                .create(),
+        )));
+
+        if n % 5 == 0 {
+            events.push(Event::MessageUpdate(Box::new(
+                MessageUpdateBuilder::new(guild_id, channel_id, id)
+                    .content(format!("message {}, edited", n))
+                    .edited_timestamp(testing::timestamp(testing::TIMESTAMP_SECS + 60))
+                    .build(),
+            )));
+        }
+
+        if n % 10 == 0 {
+            events.push(Event::MessageDelete(MessageDelete {
+                channel_id,
+                guild_id: Some(guild_id),
+                id,
+            }));
+        }
+    }
+
+    events
+}
+
+fn member_chunks(ids: &mut Ids) -> Vec<Event> {
+    let guild_id = testing::guild_id(ids.next());
+    let chunk_count = (CHUNKED_MEMBERS / CHUNK_SIZE) as u32;
+
+    (0..chunk_count)
+        .map(|chunk_index| {
+            let mut chunk = MemberChunkBuilder::new(guild_id).index(chunk_index, chunk_count);
+
+            for _ in 0..CHUNK_SIZE {
+                let user_id = testing::user_id(ids.next());
+
+                chunk = chunk
+                    .member(MemberBuilder::new(guild_id, user_id).build())
+                    .presence(PresenceBuilder::new(guild_id, user_id).build());
+            }
+
+            Event::MemberChunk(chunk.build())
+        })
+        .collect()
+}
+
+/// Latencies of one backend, per event type.
+#[derive(Default)]
+struct Timings(BTreeMap<&'static str, Vec<Duration>>);
+
+impl Timings {
+    fn record(&mut self, event: &Event, elapsed: Duration) {
+        let kind = event.kind().name().unwrap_or("UNKNOWN");
+
+        self.0.entry(kind).or_default().push(elapsed);
+    }
+
+    fn report(&mut self, workload: &str, backend: &str) {
+        for (kind, latencies) in &mut self.0 {
+            latencies.sort_unstable();
+
+            let total = latencies.iter().sum::<Duration>();
+            let per_sec = latencies.len() as f64 / total.as_secs_f64();
+
+            println!(
+                "{:<10} {:<10} {:<16} {:>8} {:>14.0} {:>12?} {:>12?}",
+                workload,
+                backend,
+                kind,
+                latencies.len(),
+                per_sec,
+                percentile(latencies, 50),
+                percentile(latencies, 99),
+            );
+        }
+    }
+}
+
+/// Nearest-rank percentile of sorted latencies.
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    let rank = (sorted.len() * percent).div_ceil(100);
+
+    sorted[rank.saturating_sub(1)]
+}
+
+async fn run(workload: &str, events: &[Event]) {
+    let memory = InMemoryCache::new();
+
+    let mut config = Config::new();
+    config.node_mut().replace(NODE.to_owned());
+    let redis = InRedisCache::with_config(config);
+
+    let mut timings = Timings::default();
+
+    for event in events {
+        let start = Instant::now();
+        memory.update(event);
+        timings.record(event, start.elapsed());
+    }
+
+    timings.report(workload, "in-memory");
+
+    let mut timings = Timings::default();
+
+    for event in events {
+        let start = Instant::now();
+        redis.update(event).await;
+        timings.record(event, start.elapsed());
+    }
+
+    timings.report(workload, "redis");
+}
+
+#[tokio::main]
+async fn main() {
+    let selected = env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .collect::<Vec<_>>();
+    let wants = |workload: &str| selected.is_empty() || selected.iter().any(|s| s == workload);
+
+    let mut ids = Ids::default();
+
+    flush();
+
+    println!(
+        "{:<10} {:<10} {:<16} {:>8} {:>14} {:>12} {:>12}",
+        "workload", "backend", "event", "events", "events/sec", "p50", "p99"
+    );
+
+    if wants("guilds") {
+        run("guilds", &guild_burst(&mut ids)).await;
+    }
+
+    if wants("messages") {
+        run("messages", &message_firehose(&mut ids)).await;
+    }
+
+    if wants("chunks") {
+        run("chunks", &member_chunks(&mut ids)).await;
+    }
+
+    flush();
+}
+
+/// Remove everything from the database of the benchmark.
+fn flush() {
+    let mut con = redis::Client::open(NODE)
+        .and_then(|client| client.get_connection())
+        .expect("Redis from docker-compose.yml is running");
+
+    redis::cmd("FLUSHDB")
+        .query::<()>(&mut con)
+        .expect("flushing the benchmark database");
+}
@@ -0,0 +1,244 @@
+//! Compression of large stored values.
+//!
+//! A compressed value starts with [`MARKER`], a byte MessagePack never uses,
+//! followed by the algorithm it was compressed with. Anything else is an
+//! uncompressed MessagePack value, so values stored before compression was
+//! enabled, or below the threshold, are read as they are.
+
+use std::borrow::Cow;
+
+use log::warn;
+
+use crate::{CacheError, InRedisCache};
+
+/// First byte of a compressed value.
+const MARKER: u8 = 0xc1;
+
+const LZ4: u8 = 1;
+const ZSTD: u8 = 2;
+
+/// Algorithm large values are compressed with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compression {
+    /// LZ4, which is very fast but compresses less.
+    Lz4,
+    /// Zstandard at a compression level from 1 to 22, where 3 is a good
+    /// balance between speed and ratio.
+    Zstd(i32),
+}
+
+/// Compresses the values of a store which are larger than a threshold.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Compressor {
+    compression: Option<Compression>,
+    threshold: usize,
+}
+
+impl Compressor {
+    pub(crate) const fn new(compression: Option<Compression>, threshold: usize) -> Self {
+        Self {
+            compression,
+            threshold,
+        }
+    }
+
+    pub(crate) const fn is_enabled(&self) -> bool {
+        self.compression.is_some()
+    }
+
+    /// Compress an encoded value if it is larger than the threshold.
+    ///
+    /// The value is kept as it is if compressing doesn't make it smaller.
+    pub(crate) fn compress(&self, pack: Vec<u8>) -> Vec<u8> {
+        let compression = match self.compression {
+            Some(compression) if pack.len() > self.threshold => compression,
+            _ => return pack,
+        };
+
+        let compressed = match compression {
+            Compression::Lz4 => {
+                let mut compressed = vec![MARKER, LZ4];
+                compressed.extend(lz4_flex::compress_prepend_size(&pack));
+
+                compressed
+            }
+            Compression::Zstd(level) => match zstd::stream::encode_all(pack.as_slice(), level) {
+                Ok(payload) => {
+                    let mut compressed = Vec::with_capacity(payload.len() + 2);
+                    compressed.extend([MARKER, ZSTD]);
+                    compressed.extend(payload);
+
+                    compressed
+                }
+                Err(err) => {
+                    warn!("Failed to compress a value with zstd: {}", err);
+
+                    return pack;
+                }
+            },
+        };
+
+        if compressed.len() < pack.len() {
+            compressed
+        } else {
+            pack
+        }
+    }
+}
+
+/// Decompress a stored value, returning uncompressed values as they are.
+///
+/// This doesn't depend on the configured compression, values stay readable
+/// when it is changed or disabled.
+pub(crate) fn decompress(stored: &[u8]) -> Result<Cow<'_, [u8]>, CacheError> {
+    match stored {
+        [MARKER, LZ4, payload @ ..] => lz4_flex::decompress_size_prepended(payload)
+            .map(Cow::Owned)
+            .map_err(|_| CacheError::Decompression),
+        [MARKER, ZSTD, payload @ ..] => zstd::stream::decode_all(payload)
+            .map(Cow::Owned)
+            .map_err(|_| CacheError::Decompression),
+        [MARKER, ..] => Err(CacheError::Decompression),
+        _ => Ok(Cow::Borrowed(stored)),
+    }
+}
+
+impl InRedisCache {
+    /// Compress the values of the stores with `compressor`.
+    pub(crate) fn set_compressor(&mut self, compressor: Compressor) {
+        self.channels_guild.compressor = compressor;
+        self.channels_private.compressor = compressor;
+        self.channel_messages.compressor = compressor;
+        self.emojis.compressor = compressor;
+        self.groups.compressor = compressor;
+        self.guilds.compressor = compressor;
+        self.guild_channels.compressor = compressor;
+        self.guild_emojis.compressor = compressor;
+        self.guild_integrations.compressor = compressor;
+        self.guild_members.compressor = compressor;
+        self.guild_presences.compressor = compressor;
+        self.guild_presence_counts.compressor = compressor;
+        self.guild_roles.compressor = compressor;
+        self.guild_stage_instances.compressor = compressor;
+        self.guild_stickers.compressor = compressor;
+        self.integrations.compressor = compressor;
+        self.members.compressor = compressor;
+        self.messages.compressor = compressor;
+        self.message_reactions.compressor = compressor;
+        self.presences.compressor = compressor;
+        self.reaction_users.compressor = compressor;
+        self.roles.compressor = compressor;
+        self.stage_instances.compressor = compressor;
+        self.stickers.compressor = compressor;
+        self.unavailable_guilds.compressor = compressor;
+        self.users.compressor = compressor;
+        self.user_guilds.compressor = compressor;
+        self.user_messages.compressor = compressor;
+        self.user_reactions.compressor = compressor;
+        self.user_private_channels.compressor = compressor;
+        self.voice_state_channels.compressor = compressor;
+        self.voice_state_guilds.compressor = compressor;
+        self.voice_states.compressor = compressor;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An encoded value which compresses well.
+    fn repetitive(len: usize) -> Vec<u8> {
+        b"twilight ".iter().copied().cycle().take(len).collect()
+    }
+
+    /// An encoded value which doesn't compress at all.
+    fn random(len: usize) -> Vec<u8> {
+        let mut pack = vec![0; len];
+        getrandom::getrandom(&mut pack).unwrap();
+
+        // Make sure the value isn't mistaken for a compressed one.
+        pack[0] = 0x92;
+
+        pack
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let pack = repetitive(4096);
+
+        for compression in [Compression::Lz4, Compression::Zstd(3)] {
+            let compressed = Compressor::new(Some(compression), 256).compress(pack.clone());
+
+            assert_eq!(compressed[0], MARKER);
+            assert!(compressed.len() < pack.len());
+            assert_eq!(decompress(&compressed).unwrap(), pack.as_slice());
+        }
+
+        assert_eq!(
+            Compressor::new(Some(Compression::Lz4), 256).compress(pack.clone())[1],
+            LZ4,
+        );
+        assert_eq!(
+            Compressor::new(Some(Compression::Zstd(3)), 256).compress(pack)[1],
+            ZSTD,
+        );
+    }
+
+    #[test]
+    fn test_threshold() {
+        let compressor = Compressor::new(Some(Compression::Lz4), 256);
+
+        let small = repetitive(256);
+        assert_eq!(compressor.compress(small.clone()), small);
+
+        let large = repetitive(257);
+        assert_eq!(compressor.compress(large.clone())[0], MARKER);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let pack = repetitive(4096);
+
+        assert!(!Compressor::default().is_enabled());
+        assert_eq!(Compressor::default().compress(pack.clone()), pack);
+    }
+
+    #[test]
+    fn test_keep_if_not_smaller() {
+        let pack = random(1024);
+
+        for compression in [Compression::Lz4, Compression::Zstd(3)] {
+            let compressor = Compressor::new(Some(compression), 0);
+
+            assert_eq!(compressor.compress(pack.clone()), pack);
+        }
+    }
+
+    #[test]
+    fn test_decompress_uncompressed() {
+        let pack = rmp_serde::to_vec(&("twilight", 7)).unwrap();
+
+        assert!(matches!(decompress(&pack).unwrap(), Cow::Borrowed(_)));
+        assert_eq!(decompress(&pack).unwrap(), pack.as_slice());
+    }
+
+    #[test]
+    fn test_decompress_marker() {
+        assert!(matches!(
+            decompress(&[MARKER]),
+            Err(CacheError::Decompression)
+        ));
+        assert!(matches!(
+            decompress(&[MARKER, 7, 1, 2, 3]),
+            Err(CacheError::Decompression)
+        ));
+        assert!(matches!(
+            decompress(&[MARKER, LZ4, 1, 2, 3]),
+            Err(CacheError::Decompression)
+        ));
+        assert!(matches!(
+            decompress(&[MARKER, ZSTD, 1, 2, 3]),
+            Err(CacheError::Decompression)
+        ));
+    }
+}
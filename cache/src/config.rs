@@ -1,4 +1,9 @@
+use std::time::Duration;
+
 use bitflags::bitflags;
+use twilight_model::id::{ChannelId, GuildId, UserId};
+
+use crate::{compress::Compression, crypt::MessageEncryption};
 
 bitflags! {
     /// A set of bitflags which can be used to specify what resource to process
@@ -39,13 +44,73 @@ bitflags! {
     }
 }
 
-/// Configuration for an [`InMemoryCache`].
+/// How cached entities are laid out in Redis.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeyLayout {
+    /// Every resource is a single hash, mapping IDs to encoded entities.
+    ///
+    /// Updating an entity reads, changes and writes back the whole entity.
+    /// Presence counts use [`Hash`] anyway, run
+    /// [`InRedisCache::migrate_key_layout`] once to move them over.
+    ///
+    /// [`Hash`]: Self::Hash
+    /// [`InRedisCache::migrate_key_layout`]: crate::InRedisCache::migrate_key_layout
+    Blob,
+    /// Every entity is its own hash, with a field per attribute.
+    ///
+    /// Updating an entity only writes the fields which changed.
+    Hash,
+}
+
+/// A rule limiting which resources are cached, on top of the enabled
+/// [`ResourceType`]s.
+///
+/// Every rule applies to the resource types it is given. A rule only looks at
+/// the IDs a resource has: a guild rule never skips resources outside of
+/// guilds, like messages in private channels, and a channel rule never skips
+/// resources without a channel, like members.
+///
+/// Rules only decide what is added to the cache, removals are always applied.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CacheRule {
+    /// Only cache the resources in these guilds.
+    OnlyGuilds(ResourceType, Vec<GuildId>),
+    /// Don't cache the resources in these guilds.
+    SkipGuilds(ResourceType, Vec<GuildId>),
+    /// Only cache the resources in these channels.
+    OnlyChannels(ResourceType, Vec<ChannelId>),
+    /// Don't cache the resources in these channels.
+    SkipChannels(ResourceType, Vec<ChannelId>),
+    /// Don't cache the resources of these users.
+    SkipUsers(ResourceType, Vec<UserId>),
+    /// Don't cache the resources in guilds with more members than this.
+    ///
+    /// Uses the cached member count of the guild, which members joining and
+    /// leaving keep current. Guilds with an unknown member count are not
+    /// skipped.
+    MaxGuildMembers(ResourceType, u64),
+}
+
+/// Configuration for an [`InRedisCache`].
 ///
-/// [`InMemoryCache`]: crate::InMemoryCache
+/// [`InRedisCache`]: crate::InRedisCache
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Config {
     pub(super) resource_types: ResourceType,
+    pub(super) key_layout: KeyLayout,
+    pub(super) node: Option<String>,
+    pub(super) cluster_nodes: Vec<String>,
+    pub(super) replica_nodes: Vec<String>,
+    pub(super) replica_max_lag: Option<Duration>,
+    pub(super) read_your_writes: bool,
+    pub(super) compression: Option<Compression>,
+    pub(super) compression_threshold: usize,
     pub(super) message_cache_size: usize,
+    pub(super) message_revision_size: usize,
+    pub(super) deleted_message_cache_size: usize,
+    pub(super) message_archive_ttl: Option<usize>,
+    pub(super) message_encryption: Option<MessageEncryption>,
+    pub(super) rules: Vec<CacheRule>,
 }
 
 impl Config {
@@ -55,10 +120,183 @@ impl Config {
     pub const fn new() -> Self {
         Self {
             resource_types: ResourceType::all(),
+            key_layout: KeyLayout::Blob,
+            node: None,
+            cluster_nodes: Vec::new(),
+            replica_nodes: Vec::new(),
+            replica_max_lag: Some(Duration::from_secs(1)),
+            read_your_writes: false,
+            compression: None,
+            compression_threshold: 1024,
             message_cache_size: 100,
+            message_revision_size: 0,
+            deleted_message_cache_size: 0,
+            message_archive_ttl: None,
+            message_encryption: None,
+            rules: Vec::new(),
         }
     }
 
+    /// Returns the layout entities are stored with.
+    ///
+    /// Defaults to [`KeyLayout::Blob`]. Existing entries can be moved to
+    /// [`KeyLayout::Hash`] with [`InRedisCache::migrate_key_layout`].
+    ///
+    /// [`InRedisCache::migrate_key_layout`]: crate::InRedisCache::migrate_key_layout
+    pub const fn key_layout(&self) -> KeyLayout {
+        self.key_layout
+    }
+
+    /// Returns a mutable reference to the layout entities are stored with.
+    pub fn key_layout_mut(&mut self) -> &mut KeyLayout {
+        &mut self.key_layout
+    }
+
+    /// Returns the nodes of the Redis Cluster the cache is stored in.
+    ///
+    /// Defaults to none, storing the cache on the single Redis node of
+    /// [`node`]. Only some nodes of a cluster have to be listed, the
+    /// others are discovered through them.
+    ///
+    /// In a cluster the keys of a guild's members, presences, voice states,
+    /// integrations and messages are sharded by the guild, and the sets of an
+    /// entity share the slot of its ID. Existing keys of a single node are
+    /// not moved over.
+    ///
+    /// [`node`]: Self::node
+    pub fn cluster_nodes(&self) -> &[String] {
+        &self.cluster_nodes
+    }
+
+    /// Returns a mutable reference to the nodes of the Redis Cluster the
+    /// cache is stored in.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use cache::Config;
+    ///
+    /// let mut config = Config::new();
+    /// config.cluster_nodes_mut().extend([
+    ///     "redis://10.0.0.1:6379".to_owned(),
+    ///     "redis://10.0.0.2:6379".to_owned(),
+    /// ]);
+    /// ```
+    pub fn cluster_nodes_mut(&mut self) -> &mut Vec<String> {
+        &mut self.cluster_nodes
+    }
+
+    /// Returns the URL of the single Redis node the cache is stored in.
+    ///
+    /// Defaults to `redis://127.0.0.1`. The URL can select a database, like
+    /// `redis://127.0.0.1/1`. Not used in a Redis Cluster.
+    pub fn node(&self) -> &str {
+        self.node.as_deref().unwrap_or("redis://127.0.0.1")
+    }
+
+    /// Returns a mutable reference to the URL of the Redis node the cache is
+    /// stored in, `None` for the default.
+    pub fn node_mut(&mut self) -> &mut Option<String> {
+        &mut self.node
+    }
+
+    /// Returns the replicas of the primary Redis node reads are served from.
+    ///
+    /// Defaults to none, reading from the primary. Reads through the stores
+    /// are spread over the replicas, while events are applied on the primary
+    /// only. Not used in a Redis Cluster.
+    pub fn replica_nodes(&self) -> &[String] {
+        &self.replica_nodes
+    }
+
+    /// Returns a mutable reference to the replicas reads are served from.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use cache::Config;
+    /// use std::time::Duration;
+    ///
+    /// let mut config = Config::new();
+    /// config.replica_nodes_mut().push("redis://10.0.0.3:6379".to_owned());
+    /// *config.replica_max_lag_mut() = Some(Duration::from_millis(200));
+    /// ```
+    pub fn replica_nodes_mut(&mut self) -> &mut Vec<String> {
+        &mut self.replica_nodes
+    }
+
+    /// Returns how far a replica may lag behind the primary and still serve
+    /// reads.
+    ///
+    /// Defaults to 1 second. Reads fall back to the primary while every
+    /// replica lags behind further. `None` reads from replicas however stale
+    /// they are.
+    pub const fn replica_max_lag(&self) -> Option<Duration> {
+        self.replica_max_lag
+    }
+
+    /// Returns a mutable reference to the maximum lag of replicas.
+    pub fn replica_max_lag_mut(&mut self) -> &mut Option<Duration> {
+        &mut self.replica_max_lag
+    }
+
+    /// Returns whether reads in a [`InRedisCache::session`] see the writes
+    /// made earlier in the session.
+    ///
+    /// Defaults to `false`. Reads in a session which wrote to the cache go to
+    /// the primary until a replica has caught up with the write.
+    ///
+    /// [`InRedisCache::session`]: crate::InRedisCache::session
+    pub const fn read_your_writes(&self) -> bool {
+        self.read_your_writes
+    }
+
+    /// Returns a mutable reference to whether sessions read their own writes.
+    pub fn read_your_writes_mut(&mut self) -> &mut bool {
+        &mut self.read_your_writes
+    }
+
+    /// Returns the algorithm values larger than the
+    /// [compression threshold] are compressed with.
+    ///
+    /// Defaults to `None`, storing values uncompressed. Compressed values
+    /// stay readable when this is changed or disabled. The compression ratio
+    /// of every store is reported to the [`MetricsRecorder`].
+    ///
+    /// [compression threshold]: Self::compression_threshold
+    /// [`MetricsRecorder`]: crate::MetricsRecorder
+    pub const fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    /// Returns a mutable reference to the compression algorithm.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache::{Compression, Config};
+    ///
+    /// let mut config = Config::new();
+    /// *config.compression_mut() = Some(Compression::Zstd(3));
+    /// *config.compression_threshold_mut() = 512;
+    /// ```
+    pub fn compression_mut(&mut self) -> &mut Option<Compression> {
+        &mut self.compression
+    }
+
+    /// Returns the size in bytes above which encoded values are compressed.
+    ///
+    /// Defaults to 1024. With the [`KeyLayout::Hash`] layout the size of
+    /// every field counts on its own.
+    pub const fn compression_threshold(&self) -> usize {
+        self.compression_threshold
+    }
+
+    /// Returns a mutable reference to the compression threshold.
+    pub fn compression_threshold_mut(&mut self) -> &mut usize {
+        &mut self.compression_threshold
+    }
+
     /// Returns an immutable reference to the message cache size.
     ///
     /// Defaults to 100.
@@ -70,6 +308,80 @@ impl Config {
     pub fn message_cache_size_mut(&mut self) -> &mut usize {
         &mut self.message_cache_size
     }
+
+    /// Returns the number of previous revisions kept for each edited message.
+    ///
+    /// Revisions are removed with their message, unless
+    /// [`message_archive_ttl`] is set, then they expire after it instead.
+    ///
+    /// Defaults to 0, which disables the edit history.
+    ///
+    /// [`message_archive_ttl`]: Self::message_archive_ttl
+    pub const fn message_revision_size(&self) -> usize {
+        self.message_revision_size
+    }
+
+    /// Returns a mutable reference to the number of previous revisions kept
+    /// for each edited message.
+    pub fn message_revision_size_mut(&mut self) -> &mut usize {
+        &mut self.message_revision_size
+    }
+
+    /// Returns the number of deleted messages archived per channel.
+    ///
+    /// Defaults to 0, which disables the deleted message archive.
+    pub const fn deleted_message_cache_size(&self) -> usize {
+        self.deleted_message_cache_size
+    }
+
+    /// Returns a mutable reference to the number of deleted messages archived
+    /// per channel.
+    pub fn deleted_message_cache_size_mut(&mut self) -> &mut usize {
+        &mut self.deleted_message_cache_size
+    }
+
+    /// Returns the number of seconds archived revisions and deleted messages
+    /// are kept after their last change.
+    ///
+    /// Defaults to `None`, keeping them until they are pushed out by newer
+    /// entries.
+    pub const fn message_archive_ttl(&self) -> Option<usize> {
+        self.message_archive_ttl
+    }
+
+    /// Returns a mutable reference to the message archive time to live.
+    pub fn message_archive_ttl_mut(&mut self) -> &mut Option<usize> {
+        &mut self.message_archive_ttl
+    }
+
+    /// Returns the keys the content, attachments and embeds of messages are
+    /// encrypted with before they are stored.
+    ///
+    /// Defaults to `None`, storing messages in plaintext. Reads through the
+    /// cache decrypt messages transparently.
+    pub const fn message_encryption(&self) -> Option<&MessageEncryption> {
+        self.message_encryption.as_ref()
+    }
+
+    /// Returns a mutable reference to the message encryption keys.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use cache::{Config, MessageEncryption};
+    ///
+    /// let mut config = Config::new();
+    /// *config.message_encryption_mut() = Some(MessageEncryption::new(1, [0; 32]));
+    ///
+    /// // Later, rotate to a new key, keeping the old one for existing messages:
+    /// if let Some(encryption) = config.message_encryption_mut() {
+    ///     encryption.rotate(2, [1; 32]);
+    /// }
+    /// ```
+    pub fn message_encryption_mut(&mut self) -> &mut Option<MessageEncryption> {
+        &mut self.message_encryption
+    }
+
     /// Returns an immutable reference to the resource types enabled.
     ///
     /// Defaults to all resource types.
@@ -81,6 +393,38 @@ impl Config {
     pub fn resource_types_mut(&mut self) -> &mut ResourceType {
         &mut self.resource_types
     }
+
+    /// Returns the rules limiting which resources are cached.
+    ///
+    /// Defaults to no rules, caching every resource of the enabled types.
+    pub fn rules(&self) -> &[CacheRule] {
+        &self.rules
+    }
+
+    /// Returns a mutable reference to the rules limiting which resources are
+    /// cached.
+    ///
+    /// # Examples
+    ///
+    /// Only cache the messages of a staff guild and skip members in guilds
+    /// with more than 50 000 members:
+    ///
+    /// ```no_run
+    /// use cache::{CacheRule, Config, ResourceType};
+    /// use twilight_model::id::GuildId;
+    ///
+    /// let mut config = Config::new();
+    /// config.rules_mut().extend([
+    ///     CacheRule::OnlyGuilds(
+    ///         ResourceType::MESSAGE,
+    ///         vec![GuildId::new(1).expect("non zero")],
+    ///     ),
+    ///     CacheRule::MaxGuildMembers(ResourceType::MEMBER, 50_000),
+    /// ]);
+    /// ```
+    pub fn rules_mut(&mut self) -> &mut Vec<CacheRule> {
+        &mut self.rules
+    }
 }
 
 impl Default for Config {
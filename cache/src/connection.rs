@@ -0,0 +1,188 @@
+//! Connections to a single Redis node or a Redis Cluster.
+//!
+//! In a cluster every key belongs to one of 16384 slots, and a command or
+//! transaction touching several keys is only allowed if they are all in the
+//! same slot. Only the part of a key between `{` and `}`, its hash tag, is
+//! used to find its slot, so the stores put the ID everything related to an
+//! entity hangs off into the hash tag, like the guild of a member.
+
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
+use mobc::{Connection, Manager};
+use redis::{
+    aio::{self, ConnectionLike},
+    Cmd, Pipeline, RedisError, RedisFuture, ToRedisArgs, Value,
+};
+
+use crate::CacheError;
+
+/// Opens connections for the pools of the stores.
+#[derive(Clone)]
+pub(crate) struct RedisManager(Target);
+
+#[derive(Clone)]
+enum Target {
+    Node(redis::Client),
+    Cluster(Arc<redis_cluster_async::Client>),
+}
+
+impl RedisManager {
+    /// Connect to a single Redis node.
+    pub fn new(connection_str: &str) -> Self {
+        Self(Target::Node(redis::Client::open(connection_str).unwrap()))
+    }
+
+    /// Connect to a Redis Cluster, discovering it through some of its nodes.
+    pub fn cluster(nodes: &[String]) -> Self {
+        let client =
+            redis_cluster_async::Client::open(nodes.iter().map(String::as_str).collect()).unwrap();
+
+        Self(Target::Cluster(Arc::new(client)))
+    }
+
+    /// Whether the connections are to a Redis Cluster.
+    pub const fn is_cluster(&self) -> bool {
+        matches!(self.0, Target::Cluster(_))
+    }
+}
+
+#[async_trait::async_trait]
+impl Manager for RedisManager {
+    type Connection = RedisConnection;
+    type Error = RedisError;
+
+    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        match &self.0 {
+            Target::Node(client) => Ok(RedisConnection::Node(client.get_async_connection().await?)),
+            Target::Cluster(client) => Ok(RedisConnection::Cluster(client.get_connection().await?)),
+        }
+    }
+
+    async fn check(&self, mut con: Self::Connection) -> Result<Self::Connection, Self::Error> {
+        redis::cmd("PING").query_async::<_, ()>(&mut con).await?;
+
+        Ok(con)
+    }
+}
+
+/// A connection to a single node, or to a cluster which routes every
+/// command to the node serving the slot of its keys.
+pub(crate) enum RedisConnection {
+    Node(aio::Connection),
+    Cluster(redis_cluster_async::Connection),
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            Self::Node(con) => con.req_packed_command(cmd),
+            Self::Cluster(con) => con.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            Self::Node(con) => con.req_packed_commands(cmd, offset, count),
+            Self::Cluster(con) => con.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Self::Node(con) => con.get_db(),
+            Self::Cluster(con) => con.get_db(),
+        }
+    }
+}
+
+/// A pooled connection which may be watching keys for a transaction.
+///
+/// `UNWATCH` takes no key, so a cluster connection sends it to any node and
+/// the keys may stay watched. A connection still watching when it is
+/// dropped, after an error or being cancelled, or a cluster connection
+/// which stops watching without `EXEC`, is closed instead of going back to
+/// the pool, where it would abort the next transaction.
+pub(crate) struct WatchedConnection {
+    con: Option<Connection<RedisManager>>,
+    watching: bool,
+}
+
+impl WatchedConnection {
+    pub(crate) const fn new(con: Connection<RedisManager>) -> Self {
+        Self {
+            con: Some(con),
+            watching: false,
+        }
+    }
+
+    /// Watch `keys`, which have to be in the same slot in a cluster.
+    pub(crate) async fn watch(&mut self, keys: impl ToRedisArgs) -> Result<(), CacheError> {
+        self.watching = true;
+
+        redis::cmd("WATCH")
+            .arg(keys)
+            .query_async::<_, ()>(&mut ***self)
+            .await?;
+
+        Ok(())
+    }
+
+    /// A transaction was executed, which stops watching whether or not it
+    /// was aborted.
+    pub(crate) fn executed(&mut self) {
+        self.watching = false;
+    }
+
+    /// Stop watching without executing a transaction.
+    pub(crate) async fn unwatch(mut self) -> Result<(), CacheError> {
+        if let RedisConnection::Node(_) = &**self {
+            redis::cmd("UNWATCH")
+                .query_async::<_, ()>(&mut **self)
+                .await?;
+
+            self.watching = false;
+        }
+
+        Ok(())
+    }
+}
+
+impl Deref for WatchedConnection {
+    type Target = Connection<RedisManager>;
+
+    fn deref(&self) -> &Self::Target {
+        self.con.as_ref().expect("connection taken")
+    }
+}
+
+impl DerefMut for WatchedConnection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.con.as_mut().expect("connection taken")
+    }
+}
+
+impl Drop for WatchedConnection {
+    fn drop(&mut self) {
+        if !self.watching {
+            return;
+        }
+
+        // Taking the raw connection out makes the pool close it.
+        if let Some(con) = self.con.take() {
+            drop(con.into_inner());
+        }
+    }
+}
+
+/// Key of a hash tag, putting every key containing it into the same slot.
+pub(crate) fn hash_tag(id: impl std::fmt::Display) -> String {
+    format!("{{{}}}", id)
+}
@@ -0,0 +1,365 @@
+//! Encryption of message content at rest.
+//!
+//! The content, attachments and embeds of a [`CachedMessage`] are sealed
+//! together with XChaCha20-Poly1305 before the message is written, bound to
+//! the ID of the message. Everything else about the message stays readable,
+//! so reactions and the like can still be updated without decrypting it.
+//!
+//! Messages read through [`InRedisCache::message`] and the archives are
+//! decrypted, while the raw stores return them as they are stored.
+
+use std::{collections::BTreeMap, fmt};
+
+use chacha20poly1305::{
+    aead::{Aead, NewAead, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use futures::{pin_mut, TryStreamExt};
+use log::error;
+use serde::{Deserialize, Serialize};
+use twilight_model::{
+    channel::{embed::Embed, Attachment},
+    id::MessageId,
+};
+
+use crate::{model::CachedMessage, CacheError, InRedisCache};
+
+/// Keys used to encrypt message content.
+///
+/// New content is encrypted with the current key. Older keys are kept to
+/// decrypt content written before the last rotation, until
+/// [`InRedisCache::reencrypt_messages`] moved everything to the current key.
+///
+/// [`InRedisCache::reencrypt_messages`]: crate::InRedisCache::reencrypt_messages
+#[derive(Clone, Eq, PartialEq)]
+pub struct MessageEncryption {
+    current: u8,
+    keys: BTreeMap<u8, [u8; 32]>,
+}
+
+impl MessageEncryption {
+    /// Encrypt with `key`, which is referred to by `key_id` in stored
+    /// messages.
+    pub fn new(key_id: u8, key: [u8; 32]) -> Self {
+        Self {
+            current: key_id,
+            keys: BTreeMap::from([(key_id, key)]),
+        }
+    }
+
+    /// Encrypt new content with another key, keeping the previous ones to
+    /// decrypt existing content.
+    pub fn rotate(&mut self, key_id: u8, key: [u8; 32]) -> &mut Self {
+        self.keys.insert(key_id, key);
+        self.current = key_id;
+
+        self
+    }
+
+    /// Add a key which is only used to decrypt existing content.
+    pub fn add_decryption_key(&mut self, key_id: u8, key: [u8; 32]) -> &mut Self {
+        self.keys.insert(key_id, key);
+
+        self
+    }
+
+    /// Remove a key which no stored content uses anymore, returning whether
+    /// it was removed.
+    ///
+    /// The current key can't be removed.
+    pub fn remove_key(&mut self, key_id: u8) -> bool {
+        key_id != self.current && self.keys.remove(&key_id).is_some()
+    }
+
+    /// ID of the key new content is encrypted with.
+    pub const fn current_key_id(&self) -> u8 {
+        self.current
+    }
+
+    fn cipher(&self, key_id: u8) -> Result<XChaCha20Poly1305, CacheError> {
+        let key = self.keys.get(&key_id).ok_or(CacheError::Encryption)?;
+
+        Ok(XChaCha20Poly1305::new(Key::from_slice(key)))
+    }
+}
+
+impl fmt::Debug for MessageEncryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MessageEncryption")
+            .field("current", &self.current)
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// Encrypted content of a message.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct Sealed {
+    key_id: u8,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct SealedFields {
+    attachments: Vec<Attachment>,
+    content: String,
+    embeds: Vec<Embed>,
+}
+
+impl MessageEncryption {
+    /// Encrypt the content of a message, leaving it empty in the message.
+    pub(crate) fn seal(&self, message: &mut CachedMessage) -> Result<(), CacheError> {
+        if message.sealed.is_some() {
+            return Ok(());
+        }
+
+        let fields = SealedFields {
+            attachments: std::mem::take(&mut message.attachments),
+            content: std::mem::take(&mut message.content),
+            embeds: std::mem::take(&mut message.embeds),
+        };
+
+        let plaintext = rmp_serde::to_vec_named(&fields)?;
+        message.sealed = Some(self.encrypt(&plaintext, &aad(message.id()))?);
+
+        Ok(())
+    }
+
+    /// Decrypt the content of a message back into it.
+    pub(crate) fn unseal(&self, message: &mut CachedMessage) -> Result<(), CacheError> {
+        let sealed = match &message.sealed {
+            Some(sealed) => sealed,
+            None => return Ok(()),
+        };
+
+        let plaintext = self.decrypt(sealed, &aad(message.id()))?;
+        let fields: SealedFields = rmp_serde::from_read(&*plaintext)?;
+
+        message.attachments = fields.attachments;
+        message.content = fields.content;
+        message.embeds = fields.embeds;
+        message.sealed = None;
+
+        Ok(())
+    }
+
+    /// Encrypt `plaintext` with the current key, bound to `aad`.
+    pub(crate) fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<Sealed, CacheError> {
+        let mut nonce = [0; 24];
+        getrandom::getrandom(&mut nonce).map_err(|_| CacheError::Encryption)?;
+
+        let ciphertext = self
+            .cipher(self.current)?
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| CacheError::Encryption)?;
+
+        Ok(Sealed {
+            key_id: self.current,
+            nonce: nonce.to_vec(),
+            ciphertext,
+        })
+    }
+
+    /// Decrypt what [`encrypt`] sealed with the same `aad`.
+    ///
+    /// [`encrypt`]: Self::encrypt
+    pub(crate) fn decrypt(&self, sealed: &Sealed, aad: &[u8]) -> Result<Vec<u8>, CacheError> {
+        if sealed.nonce.len() != 24 {
+            return Err(CacheError::Encryption);
+        }
+
+        self.cipher(sealed.key_id)?
+            .decrypt(
+                XNonce::from_slice(&sealed.nonce),
+                Payload {
+                    msg: &sealed.ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| CacheError::Encryption)
+    }
+}
+
+/// Binds the content to its message, so it can't be moved to another one.
+pub(crate) fn aad(message_id: MessageId) -> [u8; 8] {
+    message_id.get().to_be_bytes()
+}
+
+impl InRedisCache {
+    /// Encrypt the content of a message before writing it, if enabled.
+    pub(crate) fn seal_message(&self, message: &mut CachedMessage) -> Result<(), CacheError> {
+        match self.config.message_encryption() {
+            Some(encryption) => encryption.seal(message),
+            None => Ok(()),
+        }
+    }
+
+    /// Decrypt the content of a message which was read, logging failures.
+    pub(crate) fn unseal_message(&self, mut message: CachedMessage) -> Option<CachedMessage> {
+        if message.sealed.is_none() {
+            return Some(message);
+        }
+
+        let encryption = match self.config.message_encryption() {
+            Some(encryption) => encryption,
+            None => {
+                error!("Message {} is encrypted but no keys are set", message.id());
+
+                return None;
+            }
+        };
+
+        match encryption.unseal(&mut message) {
+            Ok(()) => Some(message),
+            Err(err) => {
+                error!("Failed to decrypt message {}: {}", message.id(), err);
+
+                None
+            }
+        }
+    }
+
+    /// Encrypt all cached messages with the current key, returning the
+    /// number of changed messages.
+    ///
+    /// Run this after [`MessageEncryption::rotate`] before removing old keys.
+    /// Archived revisions and deleted messages are not changed, so keep the
+    /// old keys until they expired.
+    pub async fn reencrypt_messages(&self) -> Result<usize, CacheError> {
+        let encryption = match self.config.message_encryption() {
+            Some(encryption) => encryption,
+            None => return Ok(0),
+        };
+
+        let ids = self.messages.keys();
+        pin_mut!(ids);
+
+        let mut changed = 0;
+
+        while let Some(id) = ids.try_next().await? {
+            let modified = self
+                .messages
+                .modify(id, |message| {
+                    let outdated = match &message.sealed {
+                        Some(sealed) => sealed.key_id != encryption.current,
+                        None => true,
+                    };
+
+                    outdated
+                        && encryption.unseal(message).is_ok()
+                        && encryption.seal(message).is_ok()
+                })
+                .await?;
+
+            if modified {
+                changed += 1;
+            }
+        }
+
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, MessageBuilder};
+
+    fn message(id: u64, content: &str) -> CachedMessage {
+        let message = MessageBuilder::new(
+            testing::guild_id(1),
+            testing::channel_id(2),
+            testing::message_id(id),
+            testing::user_id(3),
+        )
+        .content(content)
+        .create();
+
+        CachedMessage::from(message.0)
+    }
+
+    #[test]
+    fn test_seal_unseal() {
+        let encryption = MessageEncryption::new(1, [1; 32]);
+        let mut message = message(4, "secret");
+
+        encryption.seal(&mut message).unwrap();
+        assert!(message.content.is_empty());
+        assert_eq!(message.sealed.as_ref().map(|sealed| sealed.key_id), Some(1));
+
+        // Sealing twice doesn't lose the content.
+        encryption.seal(&mut message).unwrap();
+
+        encryption.unseal(&mut message).unwrap();
+        assert_eq!(message.content, "secret");
+        assert!(message.sealed.is_none());
+    }
+
+    #[test]
+    fn test_rotation() {
+        let mut encryption = MessageEncryption::new(1, [1; 32]);
+        let mut old = message(4, "old");
+        encryption.seal(&mut old).unwrap();
+
+        encryption.rotate(2, [2; 32]);
+        let mut new = message(5, "new");
+        encryption.seal(&mut new).unwrap();
+
+        // New content uses the new key, the old key still decrypts.
+        assert_eq!(encryption.current_key_id(), 2);
+        assert_eq!(new.sealed.as_ref().map(|sealed| sealed.key_id), Some(2));
+        encryption.unseal(&mut old).unwrap();
+        encryption.unseal(&mut new).unwrap();
+        assert_eq!(old.content, "old");
+        assert_eq!(new.content, "new");
+
+        // The current key can't be removed, and without the old key its
+        // content can't be decrypted anymore.
+        let mut old = message(4, "old");
+        MessageEncryption::new(1, [1; 32]).seal(&mut old).unwrap();
+        assert!(!encryption.remove_key(2));
+        assert!(encryption.remove_key(1));
+        assert!(matches!(
+            encryption.unseal(&mut old),
+            Err(CacheError::Encryption)
+        ));
+    }
+
+    #[test]
+    fn test_tampering() {
+        let encryption = MessageEncryption::new(1, [1; 32]);
+        let plaintext = b"content";
+        let sealed = encryption.encrypt(plaintext, b"aad").unwrap();
+
+        assert_eq!(encryption.decrypt(&sealed, b"aad").unwrap(), plaintext);
+
+        let mut tampered = sealed.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert!(encryption.decrypt(&tampered, b"aad").is_err());
+
+        let mut tampered = sealed.clone();
+        tampered.nonce[0] ^= 1;
+        assert!(encryption.decrypt(&tampered, b"aad").is_err());
+
+        let mut tampered = sealed.clone();
+        tampered.nonce.pop();
+        assert!(encryption.decrypt(&tampered, b"aad").is_err());
+
+        // Bound to its message.
+        assert!(encryption.decrypt(&sealed, b"other").is_err());
+
+        // Sealed content moved to another message fails to decrypt.
+        let mut original = message(4, "secret");
+        encryption.seal(&mut original).unwrap();
+        let mut moved = message(5, "");
+        moved.sealed = original.sealed.take();
+        assert!(encryption.unseal(&mut moved).is_err());
+    }
+}
@@ -0,0 +1,560 @@
+//! Ordered application of gateway events.
+//!
+//! Updating the cache is asynchronous, so two events for the same guild which
+//! are passed to [`InRedisCache::update`] concurrently may be applied in any
+//! order, e.g. a `MemberUpdate` before the `MemberAdd` it belongs to. The
+//! [`Dispatcher`] keeps one sequential queue per guild (or channel, for
+//! events outside of guilds) and applies different queues in parallel.
+//!
+//! `Ready` touches every guild of its shard, so it is a barrier instead: the
+//! events dispatched before it are applied first, and the ones dispatched
+//! after it wait until it was applied.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    oneshot, watch, Mutex as AsyncMutex, RwLock,
+};
+use twilight_model::{
+    application::interaction::Interaction,
+    channel::Channel,
+    gateway::event::Event,
+    id::{ChannelId, GuildId},
+};
+
+use crate::InRedisCache;
+
+/// Key of the queue an event is applied in.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum QueueKey {
+    Channel(ChannelId),
+    /// Events which are not tied to a guild or channel, like `UserUpdate`.
+    Global,
+    Guild(GuildId),
+}
+
+impl QueueKey {
+    fn from_parts(guild_id: Option<GuildId>, channel_id: ChannelId) -> Self {
+        match guild_id {
+            Some(guild_id) => Self::Guild(guild_id),
+            None => Self::Channel(channel_id),
+        }
+    }
+
+    fn from_channel(channel: &Channel) -> Self {
+        match channel {
+            Channel::Guild(c) => Self::from_parts(c.guild_id(), c.id()),
+            _ => Self::Channel(channel.id()),
+        }
+    }
+
+    fn from_event(event: &Event) -> Self {
+        use Event::*;
+
+        match event {
+            BanAdd(v) => Self::Guild(v.guild_id),
+            BanRemove(v) => Self::Guild(v.guild_id),
+            ChannelCreate(v) => Self::from_channel(&v.0),
+            ChannelDelete(v) => Self::from_channel(&v.0),
+            ChannelPinsUpdate(v) => Self::from_parts(v.guild_id, v.channel_id),
+            ChannelUpdate(v) => Self::from_channel(&v.0),
+            GuildCreate(v) => Self::Guild(v.id),
+            GuildDelete(v) => Self::Guild(v.id),
+            GuildEmojisUpdate(v) => Self::Guild(v.guild_id),
+            GuildIntegrationsUpdate(v) => Self::Guild(v.guild_id),
+            GuildUpdate(v) => Self::Guild(v.id),
+            IntegrationCreate(v) => v.guild_id.map_or(Self::Global, Self::Guild),
+            IntegrationDelete(v) => Self::Guild(v.guild_id),
+            IntegrationUpdate(v) => v.guild_id.map_or(Self::Global, Self::Guild),
+            InteractionCreate(v) => match &v.0 {
+                Interaction::ApplicationCommand(command) => {
+                    Self::from_parts(command.guild_id, command.channel_id)
+                }
+                interaction => interaction.guild_id().map_or(Self::Global, Self::Guild),
+            },
+            MemberAdd(v) => Self::Guild(v.guild_id),
+            MemberChunk(v) => Self::Guild(v.guild_id),
+            MemberRemove(v) => Self::Guild(v.guild_id),
+            MemberUpdate(v) => Self::Guild(v.guild_id),
+            MessageCreate(v) => Self::from_parts(v.guild_id, v.channel_id),
+            MessageDelete(v) => Self::from_parts(v.guild_id, v.channel_id),
+            MessageDeleteBulk(v) => Self::from_parts(v.guild_id, v.channel_id),
+            MessageUpdate(v) => Self::from_parts(v.guild_id, v.channel_id),
+            PresenceUpdate(v) => Self::Guild(v.guild_id),
+            ReactionAdd(v) => Self::from_parts(v.guild_id, v.channel_id),
+            ReactionRemove(v) => Self::from_parts(v.guild_id, v.channel_id),
+            ReactionRemoveAll(v) => Self::from_parts(v.guild_id, v.channel_id),
+            ReactionRemoveEmoji(v) => Self::Guild(v.guild_id),
+            RoleCreate(v) => Self::Guild(v.guild_id),
+            RoleDelete(v) => Self::Guild(v.guild_id),
+            RoleUpdate(v) => Self::Guild(v.guild_id),
+            StageInstanceCreate(v) => Self::Guild(v.guild_id),
+            StageInstanceDelete(v) => Self::Guild(v.guild_id),
+            StageInstanceUpdate(v) => Self::Guild(v.guild_id),
+            ThreadCreate(v) => Self::from_channel(&v.0),
+            ThreadDelete(v) => Self::from_channel(&v.0),
+            ThreadListSync(v) => Self::Guild(v.guild_id),
+            ThreadUpdate(v) => Self::from_channel(&v.0),
+            TypingStart(v) => Self::from_parts(v.guild_id, v.channel_id),
+            UnavailableGuild(v) => Self::Guild(v.id),
+            VoiceStateUpdate(v) => v.0.guild_id.map_or(Self::Global, Self::Guild),
+            WebhooksUpdate(v) => Self::Guild(v.guild_id),
+            _ => Self::Global,
+        }
+    }
+}
+
+/// Whether an event has to be applied on its own, after the events
+/// dispatched before it and before the ones dispatched after it.
+fn is_barrier(event: &Event) -> bool {
+    matches!(event, Event::Ready(_))
+}
+
+// Events are the common case, boxing them for `Pause` isn't worth it.
+#[allow(clippy::large_enum_variant)]
+enum Job {
+    /// An event waiting to be applied, with a way to tell the caller it was.
+    Apply(Event, oneshot::Sender<()>),
+    /// A barrier: tell it once the events before were applied, then wait
+    /// until it was applied itself.
+    Pause(oneshot::Sender<()>, watch::Receiver<bool>),
+}
+
+struct Queue {
+    tx: mpsc::Sender<Job>,
+    /// Held by the task running the queue for as long as it runs.
+    slot: Arc<AsyncMutex<()>>,
+}
+
+#[derive(Debug, Default)]
+struct Metrics {
+    applied: AtomicU64,
+    full_waits: AtomicU64,
+    full_wait_nanos: AtomicU64,
+    queued: AtomicU64,
+    queues: AtomicU64,
+}
+
+/// Snapshot of the backpressure metrics of a [`Dispatcher`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DispatcherStats {
+    /// Total number of events applied to the cache.
+    pub applied: u64,
+    /// Number of times an event had to wait because its queue was full.
+    pub full_waits: u64,
+    /// Total time spent waiting on full queues.
+    pub full_wait_time: Duration,
+    /// Number of events currently waiting to be applied.
+    pub queued: u64,
+    /// Number of currently running queues.
+    pub queues: u64,
+}
+
+/// Applies events to an [`InRedisCache`] in order per guild.
+///
+/// # Examples
+///
+/// Apply the events of a cluster, running the event handler once the cache
+/// is up to date:
+///
+/// ```ignore
+/// let dispatcher = Dispatcher::new(Arc::new(InRedisCache::new()));
+///
+/// while let Some((shard_id, event)) = events.next().await {
+///     let applied = dispatcher.dispatch(event.clone()).await;
+///
+///     tokio::spawn(async move {
+///         applied.await.ok();
+///         handle(event, shard_id).await;
+///     });
+/// }
+/// ```
+pub struct Dispatcher {
+    cache: Arc<InRedisCache>,
+    capacity: usize,
+    idle_timeout: Duration,
+    metrics: Arc<Metrics>,
+    queues: Arc<Mutex<HashMap<QueueKey, Queue>>>,
+    /// Held for reading while a queue applies an event, and for writing
+    /// while a barrier is applied.
+    gate: Arc<RwLock<()>>,
+}
+
+impl Dispatcher {
+    /// Create a new dispatcher with room for 1024 waiting events per queue.
+    pub fn new(cache: Arc<InRedisCache>) -> Self {
+        Self::with_capacity(cache, 1024)
+    }
+
+    /// Create a new dispatcher with room for `capacity` waiting events per
+    /// queue.
+    ///
+    /// Once a queue is full, [`dispatch`] waits until there is room again.
+    ///
+    /// [`dispatch`]: Self::dispatch
+    pub fn with_capacity(cache: Arc<InRedisCache>, capacity: usize) -> Self {
+        Self {
+            cache,
+            capacity,
+            idle_timeout: Duration::from_secs(60),
+            metrics: Arc::new(Metrics::default()),
+            queues: Arc::new(Mutex::new(HashMap::new())),
+            gate: Arc::new(RwLock::new(())),
+        }
+    }
+
+    /// Cache the dispatcher applies events to.
+    pub fn cache(&self) -> &Arc<InRedisCache> {
+        &self.cache
+    }
+
+    /// Queue an event to be applied to the cache.
+    ///
+    /// Events with the same guild (or channel, for events outside of guilds)
+    /// are applied in the order they are dispatched in. The returned receiver
+    /// resolves once the event has been applied.
+    ///
+    /// A `Ready` is applied before this returns, once every event dispatched
+    /// before it was applied.
+    pub async fn dispatch(&self, event: Event) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+
+        if is_barrier(&event) {
+            self.apply_barrier(&event).await;
+            tx.send(()).ok();
+
+            return rx;
+        }
+
+        let key = QueueKey::from_event(&event);
+        let mut job = Job::Apply(event, tx);
+
+        loop {
+            let queue = self.queue(key);
+
+            self.metrics.queued.fetch_add(1, Ordering::Relaxed);
+
+            let result = match queue.try_send(job) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(full)) => {
+                    let start = Instant::now();
+                    let result = queue.send(full).await.map_err(|err| err.0);
+
+                    self.metrics.full_waits.fetch_add(1, Ordering::Relaxed);
+                    self.metrics
+                        .full_wait_nanos
+                        .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+
+                    result
+                }
+                Err(TrySendError::Closed(closed)) => Err(closed),
+            };
+
+            match result {
+                Ok(()) => return rx,
+                // The queue shut down for being idle right before we sent to
+                // it, try again with a fresh one.
+                Err(returned) => {
+                    self.metrics.queued.fetch_sub(1, Ordering::Relaxed);
+                    job = returned;
+                }
+            }
+        }
+    }
+
+    /// Pause every queue once it applied the events it has, then apply
+    /// `event` and resume them.
+    async fn apply_barrier(&self, event: &Event) {
+        let queues = self
+            .queues
+            .lock()
+            .expect("dispatcher queues poisoned")
+            .values()
+            .map(|queue| (queue.tx.clone(), Arc::clone(&queue.slot)))
+            .collect::<Vec<_>>();
+
+        let (resume, paused) = watch::channel(false);
+        let mut reached = Vec::with_capacity(queues.len());
+
+        for (tx, slot) in queues {
+            let (reached_tx, reached_rx) = oneshot::channel();
+
+            match tx.send(Job::Pause(reached_tx, paused.clone())).await {
+                Ok(()) => reached.push(reached_rx),
+                // The queue is shutting down for being idle, it is done once
+                // it released its slot.
+                Err(_) => drop(slot.lock().await),
+            }
+        }
+
+        for reached in reached {
+            // An error means the queue ended, after the events before.
+            reached.await.ok();
+        }
+
+        // Queues started since wait for the barrier before applying events.
+        let _gate = self.gate.write().await;
+
+        self.cache.update(event).await;
+        self.metrics.applied.fetch_add(1, Ordering::Relaxed);
+
+        resume.send(true).ok();
+    }
+
+    /// Record an event received on a shard, if the cache has an
+    /// [`EventRecorder`], and queue it like [`dispatch`].
+    ///
+    /// Events are recorded in the order they are dispatched in.
+    ///
+    /// [`EventRecorder`]: crate::EventRecorder
+    /// [`dispatch`]: Self::dispatch
+    pub async fn dispatch_from_shard(&self, shard_id: u64, event: Event) -> oneshot::Receiver<()> {
+        self.cache.record(shard_id, &event).await;
+
+        self.dispatch(event).await
+    }
+
+    /// Current backpressure metrics.
+    pub fn stats(&self) -> DispatcherStats {
+        DispatcherStats {
+            applied: self.metrics.applied.load(Ordering::Relaxed),
+            full_waits: self.metrics.full_waits.load(Ordering::Relaxed),
+            full_wait_time: Duration::from_nanos(
+                self.metrics.full_wait_nanos.load(Ordering::Relaxed),
+            ),
+            queued: self.metrics.queued.load(Ordering::Relaxed),
+            queues: self.metrics.queues.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Get the queue of a key, starting a new one if there is none.
+    fn queue(&self, key: QueueKey) -> mpsc::Sender<Job> {
+        let mut queues = self.queues.lock().expect("dispatcher queues poisoned");
+
+        let slot = match queues.get(&key) {
+            Some(queue) if !queue.tx.is_closed() => return queue.tx.clone(),
+            // The queue is shutting down, the new one has to wait for it.
+            Some(queue) => Arc::clone(&queue.slot),
+            None => Arc::new(AsyncMutex::new(())),
+        };
+
+        let (tx, rx) = mpsc::channel(self.capacity);
+        queues.insert(
+            key,
+            Queue {
+                tx: tx.clone(),
+                slot: Arc::clone(&slot),
+            },
+        );
+        self.metrics.queues.fetch_add(1, Ordering::Relaxed);
+
+        tokio::spawn(run_queue(
+            key,
+            rx,
+            slot,
+            Arc::clone(&self.cache),
+            Arc::clone(&self.metrics),
+            Arc::clone(&self.queues),
+            Arc::clone(&self.gate),
+            self.idle_timeout,
+        ));
+
+        tx
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_queue(
+    key: QueueKey,
+    mut rx: mpsc::Receiver<Job>,
+    slot: Arc<AsyncMutex<()>>,
+    cache: Arc<InRedisCache>,
+    metrics: Arc<Metrics>,
+    queues: Arc<Mutex<HashMap<QueueKey, Queue>>>,
+    gate: Arc<RwLock<()>>,
+    idle_timeout: Duration,
+) {
+    // Wait for a previous queue of the same key to finish the events it
+    // still had, so they are not applied concurrently with ours.
+    let _slot = slot.lock_owned().await;
+
+    loop {
+        let (event, applied) = match tokio::time::timeout(idle_timeout, rx.recv()).await {
+            Ok(Some(Job::Apply(event, applied))) => (event, applied),
+            Ok(Some(Job::Pause(reached, mut resume))) => {
+                reached.send(()).ok();
+
+                // Resumed, or the barrier was dropped before applying.
+                while !*resume.borrow() {
+                    if resume.changed().await.is_err() {
+                        break;
+                    }
+                }
+
+                continue;
+            }
+            Ok(None) => break,
+            Err(_) => {
+                // Stop taking new events but apply the ones which were sent
+                // in the meantime, the next dispatch starts a new queue.
+                rx.close();
+
+                continue;
+            }
+        };
+
+        metrics.queued.fetch_sub(1, Ordering::Relaxed);
+
+        let gate = gate.read().await;
+        cache.update(&event).await;
+        drop(gate);
+
+        metrics.applied.fetch_add(1, Ordering::Relaxed);
+        applied.send(()).ok();
+    }
+
+    let mut queues = queues.lock().expect("dispatcher queues poisoned");
+
+    if matches!(queues.get(&key), Some(queue) if queue.tx.is_closed()) {
+        queues.remove(&key);
+    }
+
+    metrics.queues.fetch_sub(1, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{self, GuildBuilder, MemberBuilder, ReadyBuilder, UserBuilder},
+        Config, ResourceType,
+    };
+    use twilight_model::gateway::payload::incoming::BanAdd;
+
+    #[tokio::test]
+    async fn test_ready_waits_for_queues() {
+        // Nothing but the current user, so no event needs Redis.
+        let mut config = Config::new();
+        *config.resource_types_mut() = ResourceType::USER_CURRENT;
+        let dispatcher = Dispatcher::new(Arc::new(InRedisCache::with_config(config)));
+
+        let mut applied = Vec::new();
+
+        for guild in 1..=20 {
+            for user in 1..=5 {
+                let event = Event::BanAdd(BanAdd {
+                    guild_id: testing::guild_id(guild),
+                    user: UserBuilder::new(testing::user_id(user)).build(),
+                });
+
+                applied.push(dispatcher.dispatch(event).await);
+            }
+        }
+
+        let ready = ReadyBuilder::new(testing::user_id(1))
+            .name("ready")
+            .guild(testing::guild_id(1));
+        dispatcher
+            .dispatch(Event::Ready(Box::new(ready.build())))
+            .await;
+
+        // Everything dispatched before was applied before `Ready` was.
+        for mut rx in applied {
+            assert_eq!(rx.try_recv(), Ok(()));
+        }
+        assert_eq!(dispatcher.stats().applied, 101);
+        assert_eq!(dispatcher.stats().queued, 0);
+        assert_eq!(
+            dispatcher.cache.current_user().map(|user| user.name),
+            Some("ready".to_owned())
+        );
+
+        // The paused queues resume afterwards.
+        let event = Event::BanAdd(BanAdd {
+            guild_id: testing::guild_id(1),
+            user: UserBuilder::new(testing::user_id(1)).build(),
+        });
+        dispatcher
+            .dispatch(event)
+            .await
+            .await
+            .expect("event not applied");
+        assert_eq!(dispatcher.stats().applied, 102);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the Redis server from docker-compose.yml"]
+    async fn test_guild_order() {
+        let dispatcher = Dispatcher::new(Arc::new(InRedisCache::new()));
+        let guild_id = testing::guild_id(2_800_001);
+        let user_id = testing::user_id(2_800_002);
+
+        let mut last = None;
+        let member = MemberBuilder::new(guild_id, user_id);
+        dispatcher
+            .dispatch(Event::MemberAdd(Box::new(member.clone().add())))
+            .await;
+
+        for nick in 0..50 {
+            let update = member.clone().nick(format!("nick {}", nick)).update();
+            last = Some(
+                dispatcher
+                    .dispatch(Event::MemberUpdate(Box::new(update)))
+                    .await,
+            );
+        }
+
+        last.expect("no update").await.expect("event not applied");
+
+        let member = dispatcher
+            .cache
+            .member(guild_id, user_id)
+            .await
+            .expect("member not read")
+            .expect("member not cached");
+        assert_eq!(member.nick(), Some("nick 49"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the Redis server from docker-compose.yml"]
+    async fn test_ready_before_guild_create() {
+        let dispatcher = Dispatcher::new(Arc::new(InRedisCache::new()));
+        let guild_id = testing::guild_id(2_800_011);
+        let owner_id = testing::user_id(2_800_012);
+
+        let ready = ReadyBuilder::new(testing::user_id(2_800_013)).guild(guild_id);
+        let ready = dispatcher
+            .dispatch(Event::Ready(Box::new(ready.build())))
+            .await;
+        let guild = GuildBuilder::new(guild_id, owner_id).create();
+        let guild = dispatcher
+            .dispatch(Event::GuildCreate(Box::new(guild)))
+            .await;
+
+        // Dispatched without waiting in between, like a shard does, the
+        // `GuildCreate` is applied after `Ready` marked the guild unavailable.
+        ready.await.expect("ready not applied");
+        guild.await.expect("guild not applied");
+
+        let cached = dispatcher
+            .cache
+            .guild(guild_id)
+            .await
+            .expect("guild not read")
+            .expect("guild not cached");
+        assert!(!cached.unavailable());
+        assert!(!dispatcher
+            .cache
+            .is_guild_unavailable(guild_id)
+            .await
+            .expect("unavailable guilds not read"));
+    }
+}
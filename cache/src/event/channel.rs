@@ -1,5 +1,6 @@
-use crate::{config::ResourceType, GuildResource, InRedisCache, UpdateCache};
-use log::info;
+use crate::{config::ResourceType, filter::Scope, GuildResource, InRedisCache, UpdateCache};
+use log::{error, info};
+use std::borrow::Cow;
 use twilight_model::{
     channel::{Channel, Group, GuildChannel, PrivateChannel},
     gateway::payload::incoming::{ChannelCreate, ChannelDelete, ChannelPinsUpdate, ChannelUpdate},
@@ -13,23 +14,35 @@ impl InRedisCache {
         guild_channels: impl IntoIterator<Item = GuildChannel>,
     ) {
         let mut conv = Vec::new();
+        let mut ids = Vec::new();
         for channel in guild_channels {
             let c = GuildResource {
                 guild_id,
                 value: self.replace_channels_guild_id(guild_id, channel),
             };
             // conv.add((channel.id().get(), &channel));
+            ids.push(c.value.id().get());
             conv.push((c.value.id().get(), c))
         }
 
+        if conv.is_empty() {
+            return;
+        }
+
         self.channels_guild.insert_multiple(conv).await;
+        self.guild_channels
+            .insert_multiple(guild_id.get(), ids)
+            .await
+            .ok();
     }
 
     pub(crate) async fn cache_guild_channel(&self, guild_id: GuildId, channel: GuildChannel) {
         let channel = self.replace_channels_guild_id(guild_id, channel);
 
-        // TODO cache
-        // self.guild_channels.entry(guild_id).or_default().insert(id);
+        self.guild_channels
+            .insert(guild_id.get(), &channel.id().get())
+            .await
+            .ok();
 
         self.channels_guild
             .insert(
@@ -79,11 +92,33 @@ impl InRedisCache {
     }
 
     async fn cache_private_channel(&self, private_channel: PrivateChannel) {
+        if self.wants(ResourceType::USER) {
+            for recipient in &private_channel.recipients {
+                self.user_private_channels
+                    .insert(recipient.id.get(), &private_channel.id.get())
+                    .await
+                    .ok();
+                self.cache_user(Cow::Borrowed(recipient), None).await;
+            }
+        }
+
         self.channels_private
             .insert(private_channel.id.get(), private_channel)
             .await;
     }
 
+    pub(crate) async fn delete_private_channel(&self, private_channel: &PrivateChannel) {
+        self.channels_private.delete(private_channel.id.get()).await;
+
+        for recipient in &private_channel.recipients {
+            self.user_private_channels
+                .remove(recipient.id.get(), private_channel.id.get())
+                .await
+                .ok();
+            self.release_user(recipient.id).await.ok();
+        }
+    }
+
     /// Delete a guild channel from the cache.
     ///
     /// The guild channel data itself and the channel entry in its guild's list
@@ -116,11 +151,17 @@ impl UpdateCache for ChannelCreate {
             }
             Channel::Guild(c) => {
                 if let Some(gid) = c.guild_id() {
-                    cache.cache_guild_channel(gid, c.clone()).await;
+                    let scope = cache.channel_scope(Some(gid), c.id()).await;
+
+                    if cache.wants_in(ResourceType::CHANNEL, &scope) {
+                        cache.cache_guild_channel(gid, c.clone()).await;
+                    }
                 }
             }
             Channel::Private(c) => {
-                cache.cache_private_channel(c.clone()).await;
+                if cache.wants_in(ResourceType::CHANNEL, &Scope::channel(c.id)) {
+                    cache.cache_private_channel(c.clone()).await;
+                }
             }
         }
     }
@@ -145,7 +186,17 @@ impl UpdateCache for ChannelDelete {
                     .await;
             }
             Channel::Private(ref c) => {
-                cache.channels_private.delete(c.id.get()).await;
+                cache.delete_private_channel(c).await;
+            }
+        }
+
+        if cache.wants(ResourceType::REACTION) {
+            if let Err(err) = cache.clear_channel_reaction_users(self.0.id()).await {
+                error!(
+                    "Failed to clear reactions in channel {}: {}",
+                    self.0.id(),
+                    err
+                );
             }
         }
     }
@@ -200,11 +251,17 @@ impl UpdateCache for ChannelUpdate {
             }
             Channel::Guild(c) => {
                 if let Some(gid) = c.guild_id() {
-                    cache.cache_guild_channel(gid, c).await;
+                    let scope = cache.channel_scope(Some(gid), c.id()).await;
+
+                    if cache.wants_in(ResourceType::CHANNEL, &scope) {
+                        cache.cache_guild_channel(gid, c).await;
+                    }
                 }
             }
             Channel::Private(c) => {
-                cache.cache_private_channel(c).await;
+                if cache.wants_in(ResourceType::CHANNEL, &Scope::channel(c.id)) {
+                    cache.cache_private_channel(c).await;
+                }
             }
         }
     }
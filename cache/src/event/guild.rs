@@ -1,53 +1,74 @@
 use crate::{
     config::ResourceType,
+    filter::Scope,
     model::{CachedGuild, CachedPresence},
-    InRedisCache, RedisHashMapCache, RedisSetCache, UpdateCache,
+    FieldChanges, InRedisCache, RedisHashMapCache, RedisSetCache, UpdateCache,
 };
+use futures::{pin_mut, TryStreamExt};
+use log::error;
 use std::{collections::HashSet, hash::Hash};
 use twilight_model::{
+    channel::GuildChannel,
     gateway::payload::incoming::{GuildCreate, GuildDelete, GuildUpdate},
     guild::Guild,
-    id::GuildId,
+    id::{ChannelId, GuildId, UserId},
 };
 
 impl InRedisCache {
-    async fn cache_guild(&self, guild: Guild) {
+    pub(crate) async fn cache_guild(&self, guild: Guild) {
+        let scope = Scope::guild(guild.id).with_member_count(guild.member_count);
+
         // The map and set creation needs to occur first, so caching states and
         // objects always has a place to put them.
         if self.wants(ResourceType::CHANNEL) {
-            self.cache_guild_channels(guild.id, guild.channels).await;
-            self.cache_guild_channels(guild.id, guild.threads).await;
+            let wanted = |channel: &GuildChannel| {
+                self.wants_in(ResourceType::CHANNEL, &scope.with_channel(channel.id()))
+            };
+
+            self.cache_guild_channels(guild.id, guild.channels.into_iter().filter(wanted))
+                .await;
+            self.cache_guild_channels(guild.id, guild.threads.into_iter().filter(wanted))
+                .await;
         }
 
-        if self.wants(ResourceType::EMOJI) {
+        if self.wants_in(ResourceType::EMOJI, &scope) {
             self.cache_emojis(guild.id, guild.emojis).await;
         }
 
+        // Filtered per user, for rules skipping users.
         if self.wants(ResourceType::MEMBER) {
-            self.cache_members(guild.id, guild.members).await;
+            let members = guild.members.into_iter().filter(|member| {
+                self.wants_in(ResourceType::MEMBER, &scope.with_user(member.user.id))
+            });
+
+            self.cache_members(guild.id, members).await;
         }
 
         if self.wants(ResourceType::PRESENCE) {
-            self.cache_presences(
-                guild.id,
-                guild.presences.into_iter().map(CachedPresence::from),
-            )
-            .await;
+            let presences = guild
+                .presences
+                .into_iter()
+                .map(CachedPresence::from)
+                .filter(|presence| {
+                    self.wants_in(ResourceType::PRESENCE, &scope.with_user(presence.user_id()))
+                });
+
+            self.cache_presences(guild.id, presences).await;
         }
 
-        if self.wants(ResourceType::ROLE) {
+        if self.wants_in(ResourceType::ROLE, &scope) {
             self.cache_roles(guild.id, guild.roles).await;
         }
 
-        if self.wants(ResourceType::STICKER) {
+        if self.wants_in(ResourceType::STICKER, &scope) {
             // self.cache_stickers(guild.id, guild.stickers).await;
         }
 
-        if self.wants(ResourceType::VOICE_STATE) {
+        if self.wants_in(ResourceType::VOICE_STATE, &scope) {
             // self.cache_voice_states(guild.voice_states).await;
         }
 
-        if self.wants(ResourceType::STAGE_INSTANCE) {
+        if self.wants_in(ResourceType::STAGE_INSTANCE, &scope) {
             self.cache_stage_instances(guild.id, guild.stage_instances)
                 .await;
         }
@@ -99,11 +120,19 @@ impl InRedisCache {
 #[async_trait::async_trait]
 impl UpdateCache for GuildCreate {
     async fn update(&self, cache: &InRedisCache) {
-        if !cache.wants(ResourceType::GUILD) {
+        let scope = Scope::guild(self.0.id).with_member_count(self.0.member_count);
+
+        if !cache.wants_in(ResourceType::GUILD, &scope) {
             return;
         }
 
+        let members_complete = self
+            .0
+            .member_count
+            .map_or(false, |count| self.0.members.len() as u64 >= count);
+
         cache.cache_guild(self.0.clone()).await;
+        cache.readiness.guild_created(self.0.id, members_complete);
     }
 }
 
@@ -120,26 +149,79 @@ impl UpdateCache for GuildDelete {
         ) where
             Hv: serde::de::DeserializeOwned + serde::Serialize,
         {
-            if let Some(res) = from.get(guild_id).await.ok() {
-                for cid in res {
-                    target.delete(cid).await;
+            let ids = from.iter(guild_id);
+            pin_mut!(ids);
+
+            loop {
+                match ids.try_next().await {
+                    Ok(Some(cid)) => {
+                        target.delete(cid).await;
+                    }
+                    Ok(None) => break,
+                    // Keep the index, so what wasn't reached yet can still be
+                    // found and removed.
+                    Err(err) => {
+                        error!(
+                            "Failed to remove the {} of guild {}: {}",
+                            target.name, guild_id, err
+                        );
+
+                        return;
+                    }
                 }
             }
-            // if let Some((_, ids)) = guild_map.remove(&guild_id) {
-            //     for id in ids {
-            //         container.remove(&id);
-            //     }
-            // }
+
+            from.delete(guild_id).await.ok();
         }
 
         if !cache.wants(ResourceType::GUILD) {
             return;
         }
 
+        // The guild is in an outage rather than removed, keep its data until
+        // it's available again.
+        if self.unavailable {
+            cache.unavailable_guild(self.id).await;
+
+            return;
+        }
+
         let id = self.id.get();
 
+        cache.readiness.guild_removed(self.id);
         cache.guilds.delete(id).await;
 
+        // Forget who reacted to the messages of the guild, which stay cached
+        // like in twilight's in-memory cache.
+        if cache.wants(ResourceType::REACTION) {
+            let channel_ids = cache.guild_channels.iter(id);
+            pin_mut!(channel_ids);
+
+            loop {
+                let channel_id = match channel_ids.try_next().await {
+                    Ok(Some(channel_id)) => channel_id,
+                    Ok(None) => break,
+                    Err(err) => {
+                        error!("Failed to clear reactions in guild {}: {}", id, err);
+
+                        break;
+                    }
+                };
+
+                let channel_id = match ChannelId::new(channel_id) {
+                    Some(channel_id) => channel_id,
+                    None => continue,
+                };
+
+                if let Err(err) = cache.clear_channel_reaction_users(channel_id).await {
+                    error!(
+                        "Failed to clear reactions in channel {}: {}",
+                        channel_id, err
+                    );
+                }
+            }
+        }
+
         if cache.wants(ResourceType::CHANNEL) {
             remove_ids(&cache.channels_guild, &cache.guild_channels, id).await;
         }
@@ -156,29 +238,46 @@ impl UpdateCache for GuildDelete {
             remove_ids(&cache.stickers, &cache.guild_stickers, id).await;
         }
 
-        if cache.wants(ResourceType::VOICE_STATE) {
-            // Clear out a guilds voice states when a guild leaves
-            cache.voice_state_guilds.delete(id).await;
-        }
-
-        if cache.wants(ResourceType::MEMBER) {
-            if let Ok(members) = cache.guild_members.get(id).await {
-                for mid in members {
-                    cache.members.delete((id, mid)).await;
+        // Everyone with a member, presence or voice state in the guild. Users
+        // in more than one of them are removed more than once, which is
+        // cheaper than remembering everyone of a huge guild.
+        for users in [
+            &cache.guild_members,
+            &cache.guild_presences,
+            &cache.voice_state_guilds,
+        ] {
+            let user_ids = users.iter(id);
+            pin_mut!(user_ids);
+
+            let walked = loop {
+                match user_ids.try_next().await {
+                    Ok(Some(user_id)) => {
+                        if let Some(user_id) = UserId::new(user_id) {
+                            cache.remove_member(self.id, user_id).await;
+                        }
+                    }
+                    Ok(None) => break true,
+                    Err(err) => {
+                        error!(
+                            "Failed to remove the {} of guild {}: {}",
+                            users.prefix, id, err
+                        );
+
+                        break false;
+                    }
                 }
-            }
+            };
 
-            cache.guild_members.delete(id).await;
-        }
-
-        if cache.wants(ResourceType::PRESENCE) {
-            if let Ok(presences) = cache.guild_presences.get(id).await {
-                for mid in presences {
-                    cache.presences.delete((id, mid)).await;
-                }
+            // An index which wasn't walked completely is kept, so the users
+            // which weren't reached yet can still be found and removed.
+            if walked {
+                users.delete(id).await.ok();
             }
+        }
 
-            cache.guild_presences.delete(id).await;
+        // The counts go with the presences they count.
+        if let Ok(0) = cache.guild_presences.size(id).await {
+            cache.guild_presence_counts.delete(id).await;
         }
     }
 }
@@ -190,36 +289,41 @@ impl UpdateCache for GuildUpdate {
             return;
         }
 
-        if let Some(mut guild) = cache.guilds.get(self.0.id.get()).await {
-            guild.afk_channel_id = self.afk_channel_id;
-            guild.afk_timeout = self.afk_timeout;
-            guild.banner = self.banner.clone();
-            guild.default_message_notifications = self.default_message_notifications;
-            guild.description = self.description.clone();
-            guild.features = self.features.clone();
-            guild.icon = self.icon.clone();
-            guild.max_members = self.max_members;
-            guild.max_presences = Some(self.max_presences.unwrap_or(25000));
-            guild.mfa_level = self.mfa_level;
-            guild.name = self.name.clone();
-            guild.nsfw_level = self.nsfw_level;
-            guild.owner = self.owner;
-            guild.owner_id = self.owner_id;
-            guild.permissions = self.permissions;
-            guild.preferred_locale = self.preferred_locale.clone();
-            guild.premium_tier = self.premium_tier;
-            guild
-                .premium_subscription_count
-                .replace(self.premium_subscription_count.unwrap_or_default());
-            guild.splash = self.splash.clone();
-            guild.system_channel_id = self.system_channel_id;
-            guild.verification_level = self.verification_level;
-            guild.vanity_url_code = self.vanity_url_code.clone();
-            guild.widget_channel_id = self.widget_channel_id;
-            guild.widget_enabled = self.widget_enabled;
-
-            cache.guilds.insert(self.0.id.get(), guild);
-        };
+        let mut changes = FieldChanges::new();
+
+        changes
+            .set("afk_channel_id", &self.afk_channel_id)
+            .set("afk_timeout", &self.afk_timeout)
+            .set("banner", &self.banner)
+            .set(
+                "default_message_notifications",
+                &self.default_message_notifications,
+            )
+            .set("description", &self.description)
+            .set("features", &self.features)
+            .set("icon", &self.icon)
+            .set("max_members", &self.max_members)
+            .set("max_presences", &Some(self.max_presences.unwrap_or(25000)))
+            .set("mfa_level", &self.mfa_level)
+            .set("name", &self.name)
+            .set("nsfw_level", &self.nsfw_level)
+            .set("owner", &self.owner)
+            .set("owner_id", &self.owner_id)
+            .set("permissions", &self.permissions)
+            .set("preferred_locale", &self.preferred_locale)
+            .set("premium_tier", &self.premium_tier)
+            .set(
+                "premium_subscription_count",
+                &Some(self.premium_subscription_count.unwrap_or_default()),
+            )
+            .set("splash", &self.splash)
+            .set("system_channel_id", &self.system_channel_id)
+            .set("verification_level", &self.verification_level)
+            .set("vanity_url_code", &self.vanity_url_code)
+            .set("widget_channel_id", &self.widget_channel_id)
+            .set("widget_enabled", &self.widget_enabled);
+
+        cache.guilds.update_fields(self.0.id.get(), &changes).await;
     }
 }
 
@@ -1,4 +1,5 @@
-use crate::{config::ResourceType, model::CachedMember, InRedisCache, UpdateCache};
+use crate::{config::ResourceType, model::CachedMember, FieldChanges, InRedisCache, UpdateCache};
+use log::error;
 use std::borrow::Cow;
 use twilight_model::{
     application::interaction::application_command::InteractionMember,
@@ -30,7 +31,8 @@ impl InRedisCache {
 
         let user_id = member.user.id;
 
-        self.cache_user(Cow::Owned(member.user), Some(guild_id));
+        self.cache_user(Cow::Owned(member.user), Some(guild_id))
+            .await;
         let cached = CachedMember {
             deaf: Some(member.deaf),
             guild_id,
@@ -111,12 +113,88 @@ impl InRedisCache {
 
         self.members.insert(id, cached).await;
     }
+
+    /// Remove everything cached about a member of a guild: the member, their
+    /// presence and voice state, and the link between the user and the guild.
+    ///
+    /// Roles are only stored on the member, there is no index of the members
+    /// of a role to clean up. The user is removed as well once nothing
+    /// references them anymore.
+    pub(crate) async fn remove_member(&self, guild_id: GuildId, user_id: UserId) {
+        let id = (guild_id.get(), user_id.get());
+
+        self.members.delete(id).await;
+        self.guild_members
+            .remove(guild_id.get(), user_id.get())
+            .await
+            .ok();
+
+        if let Err(err) = self.write_presences(guild_id, vec![(user_id, None)]).await {
+            error!(
+                "Failed to remove presence of {} in {}: {}",
+                user_id, guild_id, err
+            );
+        }
+
+        self.guild_presences
+            .remove(guild_id.get(), user_id.get())
+            .await
+            .ok();
+
+        if let Some(voice_state) = self.voice_states.get(id).await {
+            if let Some(channel_id) = voice_state.channel_id {
+                self.voice_state_channels
+                    .remove(channel_id.get(), id)
+                    .await
+                    .ok();
+            }
+
+            self.voice_states.delete(id).await;
+        }
+
+        self.voice_state_guilds
+            .remove(guild_id.get(), user_id.get())
+            .await
+            .ok();
+
+        self.unlink_user_guild(user_id, guild_id).await.ok();
+    }
+
+    /// Count a member joining or leaving in the member count of a cached
+    /// guild.
+    async fn count_member(&self, guild_id: GuildId, joined: bool) {
+        self.guilds
+            .modify(guild_id.get(), |guild| match &mut guild.member_count {
+                Some(count) if joined => {
+                    *count += 1;
+
+                    true
+                }
+                Some(count) => {
+                    *count = count.saturating_sub(1);
+
+                    true
+                }
+                None => false,
+            })
+            .await
+            .ok();
+    }
 }
 
 #[async_trait::async_trait]
 impl UpdateCache for MemberAdd {
     async fn update(&self, cache: &InRedisCache) {
-        if !cache.wants(ResourceType::MEMBER) {
+        if cache.wants(ResourceType::GUILD) {
+            cache.count_member(self.guild_id, true).await;
+        }
+
+        let scope = cache
+            .guild_scope(self.guild_id)
+            .await
+            .with_user(self.0.user.id);
+
+        if !cache.wants_in(ResourceType::MEMBER, &scope) {
             return;
         }
 
@@ -132,94 +210,207 @@ impl UpdateCache for MemberAdd {
 #[async_trait::async_trait]
 impl UpdateCache for MemberChunk {
     async fn update(&self, cache: &InRedisCache) {
-        if !cache.wants(ResourceType::MEMBER) {
-            return;
+        let scope = cache.guild_scope(self.guild_id).await;
+        let members = self
+            .members
+            .iter()
+            .filter(|member| cache.wants_in(ResourceType::MEMBER, &scope.with_user(member.user.id)))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if !members.is_empty() {
+            cache
+                .guild_members
+                .insert_multiple(
+                    self.guild_id.get(),
+                    members.iter().map(|member| member.user.id.get()).collect(),
+                )
+                .await;
+
+            cache.cache_members(self.guild_id, members).await;
         }
 
-        if self.members.is_empty() {
-            return;
-        }
+        cache.readiness.chunk_applied(
+            self.guild_id,
+            self.nonce.clone(),
+            self.chunk_index,
+            self.chunk_count,
+        );
+    }
+}
 
-        cache
-            .cache_members(self.guild_id, self.members.clone())
-            .await;
+#[async_trait::async_trait]
+impl UpdateCache for MemberRemove {
+    async fn update(&self, cache: &InRedisCache) {
+        if cache.wants(ResourceType::GUILD) {
+            cache.count_member(self.guild_id, false).await;
+        }
 
-        cache
-            .guild_members
-            .insert_multiple(
-                self.guild_id.get(),
-                self.members
-                    .iter()
-                    .map(|member| member.user.id.get())
-                    .collect(),
-            )
-            .await;
+        // Done regardless of the wanted resource types, anything left behind
+        // would never be removed.
+        cache.remove_member(self.guild_id, self.user.id).await;
     }
 }
 
 #[async_trait::async_trait]
-impl UpdateCache for MemberRemove {
+impl UpdateCache for MemberUpdate {
     async fn update(&self, cache: &InRedisCache) {
         if !cache.wants(ResourceType::MEMBER) {
             return;
         }
 
+        let mut changes = FieldChanges::new();
+
+        if let Some(deaf) = self.deaf {
+            changes.set("deaf", &Some(deaf));
+        }
+
+        if let Some(mute) = self.mute {
+            changes.set("mute", &Some(mute));
+        }
+
+        changes
+            .set("nick", &self.nick)
+            .set("roles", &self.roles)
+            .set("joined_at", &Some(self.joined_at))
+            .set("pending", &self.pending);
+
         cache
             .members
-            .delete((self.guild_id.get(), self.user.id.get()))
+            .update_fields((self.guild_id.get(), self.user.id.get()), &changes)
             .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        model::CachedPresence,
+        testing::{self, MemberBuilder, PresenceBuilder, VoiceStateBuilder},
+    };
+
+    fn member(guild_id: GuildId, user_id: UserId) -> Member {
+        MemberBuilder::new(guild_id, user_id).build()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the Redis server from docker-compose.yml"]
+    async fn test_member_remove_cascade() {
+        let cache = InRedisCache::new();
+        let guild_id = testing::guild_id(3_200_001);
+        let channel_id = testing::channel_id(3_200_002);
+        let user_id = testing::user_id(3_200_003);
+        let id = (guild_id.get(), user_id.get());
 
         cache
-            .guild_members
-            .remove(self.guild_id.get(), self.user.id.get())
+            .cache_member(guild_id, member(guild_id, user_id))
+            .await;
+        cache
+            .cache_presences(
+                guild_id,
+                [CachedPresence::from(
+                    PresenceBuilder::new(guild_id, user_id).build(),
+                )],
+            )
             .await;
+        cache
+            .voice_states
+            .insert(
+                id,
+                VoiceStateBuilder::new(guild_id, channel_id, user_id).build(),
+            )
+            .await;
+        cache
+            .voice_state_channels
+            .insert(channel_id.get(), &id)
+            .await
+            .unwrap();
+        cache
+            .voice_state_guilds
+            .insert(guild_id.get(), &user_id.get())
+            .await
+            .unwrap();
 
-        // TODO: optimize this
-        if let Some(mut user_guilds) = cache.user_guilds.get(self.user.id.get()).await.ok() {
-            if let Some(index) = user_guilds.iter().position(|id| id == &self.guild_id.get()) {
-                user_guilds.remove(index);
-            }
+        assert!(cache.members.includes(id).await);
+        assert!(cache.users.includes(user_id.get()).await);
 
-            if user_guilds.is_empty() {
-                cache.users.delete(self.user.id.get()).await;
-                cache.user_guilds.delete(self.user.id.get()).await;
-            } else {
-                cache
-                    .user_guilds
-                    .remove(self.user.id.get(), self.guild_id.get())
-                    .await;
-            }
-        }
+        cache
+            .update(&MemberRemove {
+                guild_id,
+                user: member(guild_id, user_id).user,
+            })
+            .await;
+
+        assert!(!cache.members.includes(id).await);
+        assert!(!cache
+            .guild_members
+            .includes(guild_id.get(), user_id.get())
+            .await
+            .unwrap());
+        assert!(!cache.presences.includes(id).await);
+        assert!(!cache
+            .guild_presences
+            .includes(guild_id.get(), user_id.get())
+            .await
+            .unwrap());
+        assert!(!cache.voice_states.includes(id).await);
+        assert!(!cache
+            .voice_state_channels
+            .includes(channel_id.get(), id)
+            .await
+            .unwrap());
+        assert!(!cache
+            .voice_state_guilds
+            .includes(guild_id.get(), user_id.get())
+            .await
+            .unwrap());
+        assert!(!cache
+            .user_guilds
+            .includes(user_id.get(), guild_id.get())
+            .await
+            .unwrap());
+        assert!(!cache.users.includes(user_id.get()).await);
     }
-}
 
-#[async_trait::async_trait]
-impl UpdateCache for MemberUpdate {
-    async fn update(&self, cache: &InRedisCache) {
-        if !cache.wants(ResourceType::MEMBER) {
-            return;
-        }
+    #[tokio::test]
+    #[ignore = "needs the Redis server from docker-compose.yml"]
+    async fn test_guild_delete_cascade() {
+        use twilight_model::gateway::payload::incoming::GuildDelete;
 
-        let mut member = match cache
-            .members
-            .get((self.guild_id.get(), self.user.id.get()))
-            .await
-        {
-            Some(member) => member,
-            None => return,
-        };
+        let cache = InRedisCache::new();
+        let guild_id = testing::guild_id(3_200_011);
+        let user_ids = [testing::user_id(3_200_012), testing::user_id(3_200_013)];
 
-        member.deaf = self.deaf.or_else(|| member.deaf());
-        member.mute = self.mute.or_else(|| member.mute());
-        member.nick = self.nick.clone();
-        member.roles = self.roles.clone();
-        member.joined_at.replace(self.joined_at);
-        member.pending = self.pending;
+        cache
+            .cache_members(guild_id, user_ids.map(|user_id| member(guild_id, user_id)))
+            .await;
 
         cache
-            .members
-            .insert((self.guild_id.get(), self.user.id.get()), member)
+            .update(&GuildDelete {
+                id: guild_id,
+                unavailable: false,
+            })
             .await;
+
+        assert!(cache
+            .guild_members
+            .get(guild_id.get())
+            .await
+            .unwrap()
+            .is_empty());
+
+        for user_id in user_ids {
+            let id = (guild_id.get(), user_id.get());
+
+            assert!(!cache.members.includes(id).await);
+            assert!(!cache
+                .user_guilds
+                .includes(user_id.get(), guild_id.get())
+                .await
+                .unwrap());
+            assert!(!cache.users.includes(user_id.get()).await);
+        }
     }
 }
 
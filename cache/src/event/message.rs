@@ -1,27 +1,217 @@
-use crate::{config::ResourceType, model::CachedMessage, InRedisCache, UpdateCache};
+use crate::{
+    config::ResourceType, model::CachedMessage, CacheError, FieldChanges, InRedisCache, UpdateCache,
+};
+use log::error;
 use std::borrow::Cow;
-use twilight_model::gateway::payload::incoming::{
-    MessageCreate, MessageDelete, MessageDeleteBulk, MessageUpdate,
+use twilight_model::{
+    gateway::payload::incoming::{MessageCreate, MessageDelete, MessageDeleteBulk, MessageUpdate},
+    id::{ChannelId, GuildId, MessageId},
 };
 
+/// Key of a message in [`InRedisCache::messages`], which are sharded by their
+/// guild, or 0 for messages in private channels.
+pub(crate) fn message_key(guild_id: Option<GuildId>, message_id: MessageId) -> (u64, u64) {
+    (guild_id.map_or(0, GuildId::get), message_id.get())
+}
+
+impl InRedisCache {
+    /// Keep the current state of a message before it gets overwritten by an
+    /// edit.
+    async fn archive_message_revision(&self, guild_id: Option<GuildId>, message_id: MessageId) {
+        let size = self.config.message_revision_size();
+
+        if size == 0 {
+            return;
+        }
+
+        let id = message_id.get();
+
+        let message = match self.messages.get(message_key(guild_id, message_id)).await {
+            Some(message) => message,
+            None => return,
+        };
+
+        self.message_revisions.push(id, &message, size).await.ok();
+
+        if let Some(ttl) = self.config.message_archive_ttl() {
+            self.message_revisions.expire(id, ttl).await.ok();
+        }
+    }
+
+    /// Move a message into its channel's archive of deleted messages.
+    async fn archive_deleted_message(&self, message: &CachedMessage) {
+        let size = self.config.deleted_message_cache_size();
+
+        if size == 0 {
+            return;
+        }
+
+        let channel_id = message.channel_id().get();
+
+        self.channel_deleted_messages
+            .push(channel_id, message, size)
+            .await
+            .ok();
+
+        if let Some(ttl) = self.config.message_archive_ttl() {
+            self.channel_deleted_messages
+                .expire(channel_id, ttl)
+                .await
+                .ok();
+            self.message_revisions
+                .expire(message.id().get(), ttl)
+                .await
+                .ok();
+        }
+    }
+
+    /// Remove a message from the cache, releasing its author once nothing
+    /// else references them.
+    async fn delete_message(
+        &self,
+        guild_id: Option<GuildId>,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) {
+        self.channel_messages
+            .remove(channel_id.get(), message_id.get())
+            .await
+            .ok();
+        self.clear_reaction_users(message_id, None).await.ok();
+
+        let key = message_key(guild_id, message_id);
+
+        let message = match self.messages.get(key).await {
+            Some(message) => message,
+            None => return,
+        };
+
+        self.archive_deleted_message(&message).await;
+        self.messages.delete(key).await;
+
+        // Without a time to live nothing would ever remove them.
+        if self.config.message_archive_ttl().is_none() {
+            self.message_revisions.delete(message_id.get()).await.ok();
+        }
+
+        let author_id = message.author();
+
+        self.user_messages.remove(author_id.get(), key).await.ok();
+        self.release_user(author_id).await.ok();
+    }
+
+    /// Move messages cached under only their ID over to keys including their
+    /// guild, returning the number of moved messages.
+    ///
+    /// Messages used to be keyed by their ID alone. Those are not read
+    /// anymore, and the old members of [`user_messages`] are quarantined when
+    /// read, so run this once before updating a cache which was filled by an
+    /// older version. Does nothing in a cluster.
+    ///
+    /// [`user_messages`]: Self::user_messages
+    pub async fn migrate_message_keys(&self) -> Result<usize, CacheError> {
+        let moved = self
+            .messages
+            .migrate_keys(|_: &u64, message: &CachedMessage| {
+                message_key(message.guild_id(), message.id())
+            })
+            .await?;
+
+        for (id, key) in &moved {
+            let message = match self.messages.get(*key).await {
+                Some(message) => message,
+                None => continue,
+            };
+
+            self.user_messages
+                .replace(message.author().get(), id, key)
+                .await?;
+        }
+
+        Ok(moved.len())
+    }
+
+    /// Get a message by ID, with its content decrypted.
+    ///
+    /// Messages are stored by their guild, `guild_id` is `None` for messages
+    /// in private channels.
+    pub async fn message(
+        &self,
+        guild_id: Option<GuildId>,
+        message_id: MessageId,
+    ) -> Option<CachedMessage> {
+        let message = self.messages.get(message_key(guild_id, message_id)).await?;
+
+        self.unseal_message(message)
+    }
+
+    /// Previous revisions of an edited message, newest first.
+    ///
+    /// Only available if [`Config::message_revision_size`] is set.
+    ///
+    /// [`Config::message_revision_size`]: crate::Config::message_revision_size
+    pub async fn message_revisions(&self, message_id: MessageId) -> Vec<CachedMessage> {
+        self.message_revisions
+            .get(message_id.get())
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|message| self.unseal_message(message))
+            .collect()
+    }
+
+    /// Most recently deleted messages of a channel, newest first.
+    ///
+    /// Only available if [`Config::deleted_message_cache_size`] is set.
+    ///
+    /// [`Config::deleted_message_cache_size`]: crate::Config::deleted_message_cache_size
+    pub async fn deleted_messages(&self, channel_id: ChannelId) -> Vec<CachedMessage> {
+        self.channel_deleted_messages
+            .get(channel_id.get())
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|message| self.unseal_message(message))
+            .collect()
+    }
+}
+
 #[async_trait::async_trait]
 impl UpdateCache for MessageCreate {
     async fn update(&self, cache: &InRedisCache) {
-        if cache.wants(ResourceType::USER) {
-            // TODO cache
-            // cache.cache_user(Cow::Borrowed(&self.author), self.guild_id);
+        let scope = cache
+            .channel_scope(self.guild_id, self.channel_id)
+            .await
+            .with_user(self.author.id);
+        let wants_message = cache.wants_in(ResourceType::MESSAGE, &scope);
+
+        // Link the message to its author first, so the author is not swept
+        // before the message is cached.
+        if wants_message {
+            cache
+                .user_messages
+                .insert(self.author.id.get(), &message_key(self.guild_id, self.id))
+                .await
+                .ok();
+        }
+
+        // Not linked to the guild, the author may not be a member and
+        // removing the guild only unlinks its members. The message itself
+        // references the author.
+        if cache.wants_in(ResourceType::USER, &scope) {
+            cache.cache_user(Cow::Borrowed(&self.author), None).await;
         }
 
         if let (Some(member), Some(guild_id), true) = (
             &self.member,
             self.guild_id,
-            cache.wants(ResourceType::MEMBER),
+            cache.wants_in(ResourceType::MEMBER, &scope),
         ) {
             // TODO cache
             // cache.cache_borrowed_partial_member(guild_id, member, self.author.id)
         }
 
-        if !cache.wants(ResourceType::MESSAGE) {
+        if !wants_message {
             return;
         }
 
@@ -41,9 +231,18 @@ impl UpdateCache for MessageCreate {
             .insert(self.0.channel_id.get(), &self.0.id.get())
             .await;
 
+        let mut message = CachedMessage::from(self.0.clone());
+
+        // Never store the content in plaintext if it can't be encrypted.
+        if let Err(err) = cache.seal_message(&mut message) {
+            error!("Failed to encrypt message {}: {}", self.0.id, err);
+
+            return;
+        }
+
         cache
             .messages
-            .insert(self.0.id.get(), CachedMessage::from(self.0.clone()))
+            .insert(message_key(self.guild_id, self.id), message)
             .await;
     }
 }
@@ -55,10 +254,8 @@ impl UpdateCache for MessageDelete {
             return;
         }
 
-        cache.messages.delete(self.id.get()).await;
         cache
-            .channel_messages
-            .remove(self.channel_id.get(), self.id.get())
+            .delete_message(self.guild_id, self.channel_id, self.id)
             .await;
     }
 }
@@ -73,10 +270,8 @@ impl UpdateCache for MessageDeleteBulk {
         // let mut channel_messages = cache.channel_messages.entry(self.channel_id).or_default();
 
         for id in &self.ids {
-            cache.messages.delete(id.get()).await;
             cache
-                .channel_messages
-                .remove(self.channel_id.get(), id.get())
+                .delete_message(self.guild_id, self.channel_id, *id)
                 .await;
         }
     }
@@ -89,49 +284,288 @@ impl UpdateCache for MessageUpdate {
             return;
         }
 
-        if let Some(mut message) = cache.messages.get(self.id.get()).await {
-            if let Some(attachments) = &self.attachments {
-                message.attachments = attachments.clone();
-            }
+        cache.archive_message_revision(self.guild_id, self.id).await;
 
-            if let Some(content) = &self.content {
-                message.content = content.clone();
-            }
+        let key = message_key(self.guild_id, self.id);
 
-            if let Some(edited_timestamp) = self.edited_timestamp {
-                message.edited_timestamp.replace(edited_timestamp);
-            }
+        let mut changes = FieldChanges::new();
 
-            if let Some(embeds) = &self.embeds {
-                message.embeds = embeds.clone();
-            }
+        if let Some(attachments) = &self.attachments {
+            changes.set("attachments", attachments);
+        }
 
-            if let Some(mention_everyone) = self.mention_everyone {
-                message.mention_everyone = mention_everyone;
-            }
+        if let Some(content) = &self.content {
+            changes.set("content", content);
+        }
 
-            if let Some(mention_roles) = &self.mention_roles {
-                message.mention_roles = mention_roles.clone();
-            }
+        if let Some(edited_timestamp) = self.edited_timestamp {
+            changes.set("edited_timestamp", &Some(edited_timestamp));
+        }
 
-            if let Some(mentions) = &self.mentions {
-                message.mentions = mentions.iter().map(|x| x.id).collect::<Vec<_>>();
-            }
+        if let Some(embeds) = &self.embeds {
+            changes.set("embeds", embeds);
+        }
 
-            if let Some(pinned) = self.pinned {
-                message.pinned = pinned;
-            }
+        if let Some(mention_everyone) = self.mention_everyone {
+            changes.set("mention_everyone", &mention_everyone);
+        }
 
-            if let Some(timestamp) = self.timestamp {
-                message.timestamp = timestamp;
-            }
+        if let Some(mention_roles) = &self.mention_roles {
+            changes.set("mention_roles", mention_roles);
+        }
+
+        if let Some(mentions) = &self.mentions {
+            changes.set(
+                "mentions",
+                &mentions.iter().map(|x| x.id).collect::<Vec<_>>(),
+            );
+        }
+
+        if let Some(pinned) = self.pinned {
+            changes.set("pinned", &pinned);
+        }
+
+        if let Some(timestamp) = self.timestamp {
+            changes.set("timestamp", &timestamp);
+        }
+
+        if let Some(tts) = self.tts {
+            changes.set("tts", &tts);
+        }
+
+        let changes_sealed =
+            self.attachments.is_some() || self.content.is_some() || self.embeds.is_some();
+
+        match cache.config.message_encryption() {
+            // Encrypted fields can't be changed in place, so the message is
+            // decrypted, changed and encrypted again.
+            Some(encryption) if changes_sealed => {
+                cache
+                    .messages
+                    .modify(key, |message| {
+                        let changed = encryption
+                            .unseal(message)
+                            .and_then(|_| changes.apply_to(message))
+                            .and_then(|mut changed| {
+                                encryption.seal(&mut changed)?;
+
+                                Ok(changed)
+                            });
+
+                        match changed {
+                            Ok(changed) => {
+                                *message = changed;
 
-            if let Some(tts) = self.tts {
-                message.tts = tts;
+                                true
+                            }
+                            Err(err) => {
+                                error!("Failed to update message {}: {}", self.id, err);
+
+                                false
+                            }
+                        }
+                    })
+                    .await
+                    .ok();
+            }
+            _ => {
+                cache.messages.update_fields(key, &changes).await;
             }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{self, MessageBuilder, MessageUpdateBuilder},
+        Config, RedisHashMapCache, RedisListCache, RedisSetCache,
+    };
+
+    fn archiving_cache() -> InRedisCache {
+        let mut config = Config::new();
+        *config.message_revision_size_mut() = 2;
+        *config.deleted_message_cache_size_mut() = 2;
+
+        InRedisCache::with_config(config)
+    }
 
-            cache.messages.insert(self.id.get(), message).await;
+    fn contents(messages: &[CachedMessage]) -> Vec<&str> {
+        messages.iter().map(CachedMessage::content).collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the Redis server from docker-compose.yml"]
+    async fn test_message_revisions() {
+        let cache = archiving_cache();
+        let guild_id = testing::guild_id(2_600_001);
+        let channel_id = testing::channel_id(2_600_002);
+        let message_id = testing::message_id(2_600_003);
+        let author_id = testing::user_id(2_600_004);
+
+        cache
+            .message_revisions
+            .delete(message_id.get())
+            .await
+            .unwrap();
+
+        let message = MessageBuilder::new(guild_id, channel_id, message_id, author_id).content("0");
+        cache.update(&message.create()).await;
+
+        for content in ["1", "2", "3"] {
+            let update =
+                MessageUpdateBuilder::new(guild_id, channel_id, message_id).content(content);
+            cache.update(&update.build()).await;
         }
+
+        // Newest first, the oldest revision was pushed out.
+        let revisions = cache.message_revisions(message_id).await;
+        assert_eq!(contents(&revisions), ["2", "1"]);
+
+        // Without a time to live they are removed with the message.
+        cache
+            .update(&MessageDelete {
+                channel_id,
+                guild_id: Some(guild_id),
+                id: message_id,
+            })
+            .await;
+        assert!(cache.message_revisions(message_id).await.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the Redis server from docker-compose.yml"]
+    async fn test_deleted_messages() {
+        let cache = archiving_cache();
+        let guild_id = testing::guild_id(2_600_011);
+        let channel_id = testing::channel_id(2_600_012);
+        let author_id = testing::user_id(2_600_013);
+
+        cache
+            .channel_deleted_messages
+            .delete(channel_id.get())
+            .await
+            .unwrap();
+
+        let ids = (2_600_021..2_600_024)
+            .map(testing::message_id)
+            .collect::<Vec<_>>();
+
+        for (n, message_id) in ids.iter().enumerate() {
+            let message = MessageBuilder::new(guild_id, channel_id, *message_id, author_id)
+                .content(n.to_string());
+            cache.update(&message.create()).await;
+        }
+
+        cache
+            .update(&MessageDeleteBulk {
+                channel_id,
+                guild_id: Some(guild_id),
+                ids: ids.clone(),
+            })
+            .await;
+
+        for message_id in &ids {
+            assert!(cache.message(Some(guild_id), *message_id).await.is_none());
+        }
+
+        // Newest first, the oldest deletion was pushed out.
+        let deleted = cache.deleted_messages(channel_id).await;
+        assert_eq!(contents(&deleted), ["2", "1"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the Redis server from docker-compose.yml"]
+    async fn test_undecodable_revision() {
+        let cache = archiving_cache();
+        let guild_id = testing::guild_id(2_600_031);
+        let channel_id = testing::channel_id(2_600_032);
+        let message_id = testing::message_id(2_600_033);
+        let author_id = testing::user_id(2_600_034);
+
+        cache
+            .message_revisions
+            .delete(message_id.get())
+            .await
+            .unwrap();
+
+        let message = MessageBuilder::new(guild_id, channel_id, message_id, author_id).content("0");
+        cache.update(&message.create()).await;
+
+        let update = MessageUpdateBuilder::new(guild_id, channel_id, message_id).content("1");
+        cache.update(&update.build()).await;
+
+        // Written by something which is not a message.
+        let revisions =
+            RedisListCache::<u64, String>::new("redis://127.0.0.1", "message_revisions".into());
+        revisions
+            .push(message_id.get(), &"not a message".to_owned(), 2)
+            .await
+            .unwrap();
+
+        // The other revisions are still read, the broken one is set aside.
+        let revisions = cache.message_revisions(message_id).await;
+        assert_eq!(contents(&revisions), ["0"]);
+        assert_eq!(
+            cache
+                .message_revisions
+                .size(message_id.get())
+                .await
+                .unwrap(),
+            1
+        );
+
+        let quarantined = cache.message_revisions.quarantined().await.unwrap();
+        assert_eq!(
+            rmp_serde::from_read::<_, String>(&*quarantined[0].value).unwrap(),
+            "not a message"
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the Redis server from docker-compose.yml"]
+    async fn test_migrate_message_keys() {
+        let cache = InRedisCache::new();
+        let guild_id = testing::guild_id(3_400_001);
+        let channel_id = testing::channel_id(3_400_002);
+        let message_id = testing::message_id(3_400_003);
+        let author_id = testing::user_id(3_400_004);
+
+        // Stored the way older versions did, under only the message ID.
+        let messages =
+            RedisHashMapCache::<u64, CachedMessage>::new("redis://127.0.0.1", "messages".into());
+        let user_messages =
+            RedisSetCache::<u64, u64>::new("redis://127.0.0.1", "user_messages".into());
+
+        let message = MessageBuilder::new(guild_id, channel_id, message_id, author_id).create();
+        messages
+            .insert(message_id.get(), CachedMessage::from(message.0))
+            .await
+            .unwrap();
+        user_messages
+            .insert(author_id.get(), &message_id.get())
+            .await
+            .unwrap();
+
+        assert!(cache.migrate_message_keys().await.unwrap() >= 1);
+
+        assert!(!messages.includes(message_id.get()).await);
+        assert_eq!(
+            cache
+                .message(Some(guild_id), message_id)
+                .await
+                .map(|message| message.author()),
+            Some(author_id)
+        );
+        assert_eq!(
+            cache.user_messages.get(author_id.get()).await.unwrap(),
+            [message_key(Some(guild_id), message_id)]
+        );
+
+        // Moved messages are not moved again.
+        cache.migrate_message_keys().await.unwrap();
+        assert!(cache.message(Some(guild_id), message_id).await.is_some());
     }
 }
 
@@ -1,11 +1,18 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, sync::Arc, time::Duration};
 
+use futures::{pin_mut, TryStreamExt};
+use log::{debug, error};
+use tokio::task::JoinHandle;
 use twilight_model::{
-    id::GuildId,
+    gateway::payload::incoming::{Ready, UnavailableGuild, UserUpdate},
+    id::{GuildId, UserId},
     user::{CurrentUser, User},
 };
 
-use crate::InRedisCache;
+use crate::{
+    config::ResourceType, connection::WatchedConnection, CacheError, FieldChanges, InRedisCache,
+    UpdateCache, MAX_ATTEMPTS,
+};
 
 mod channel;
 mod emoji;
@@ -23,41 +30,359 @@ mod thread;
 // mod voice_state;
 
 impl InRedisCache {
-    // TODO: cache
-    // fn cache_current_user(&self, current_user: CurrentUser) {
-    //     self.current_user
-    //         .lock()
-    //         .expect("current user poisoned")
-    //         .replace(current_user);
-    // }
+    fn cache_current_user(&self, current_user: CurrentUser) {
+        self.current_user
+            .lock()
+            .expect("current user poisoned")
+            .replace(current_user);
+    }
 
     pub(crate) async fn cache_user(&self, user: Cow<'_, User>, guild_id: Option<GuildId>) {
-        match self.users.get(user.id.get()).await {
-            Some(u) if &u == user.as_ref() => {
-                if let Some(guild_id) = guild_id {
-                    self.user_guilds
-                        .insert(guild_id.get(), &user.id.get())
-                        .await;
-                }
+        let user_id = user.id;
 
-                return;
-            }
+        // Link the user to the guild before caching them, so a concurrent
+        // removal from their last guild can't remove them right after.
+        if let Some(guild_id) = guild_id {
+            self.user_guilds
+                .insert(user_id.get(), &guild_id.get())
+                .await
+                .ok();
+        }
+
+        match self.users.get(user_id.get()).await {
+            Some(u) if &u == user.as_ref() => return,
             Some(_) | None => {}
         }
-        let user = user.into_owned();
-        let user_id = user.id;
 
-        self.users.insert(user_id.get(), user).await;
+        self.users.insert(user_id.get(), user.into_owned()).await;
+    }
+
+    /// Remove the link between a user and a guild, removing the user once
+    /// nothing references them anymore.
+    pub(crate) async fn unlink_user_guild(
+        &self,
+        user_id: UserId,
+        guild_id: GuildId,
+    ) -> Result<(), CacheError> {
+        self.user_guilds
+            .remove(user_id.get(), guild_id.get())
+            .await?;
 
-        if let Some(guild_id) = guild_id {
-            self.user_guilds.insert(user_id.get(), &guild_id.get());
+        self.release_user(user_id).await.map(|_| ())
+    }
+
+    /// Remove a user if they share no cached guild, private channel or
+    /// message with the current user anymore, returning whether they were
+    /// removed.
+    pub(crate) async fn release_user(&self, user_id: UserId) -> Result<bool, CacheError> {
+        let references = [
+            self.user_guilds.get_key(user_id.get()),
+            self.user_messages.get_key(user_id.get()),
+            self.user_private_channels.get_key(user_id.get()),
+        ];
+        let mut con = self.user_guilds.get_con().await?;
+
+        // The user is in another slot than their references in a cluster, so
+        // they can't be deleted in the same transaction. Instead the
+        // references are checked again after the delete, restoring the user
+        // if they were referenced in between.
+        if self.users.cluster {
+            let (guilds, messages, private_channels): (usize, usize, usize) = redis::pipe()
+                .scard(&references[0])
+                .scard(&references[1])
+                .scard(&references[2])
+                .query_async(&mut *con)
+                .await?;
+
+            if guilds + messages + private_channels > 0 {
+                return Ok(false);
+            }
+
+            let user = match self.users.get(user_id.get()).await {
+                Some(user) => user,
+                None => return Ok(false),
+            };
+
+            if !self.users.delete(user_id.get()).await {
+                return Ok(false);
+            }
+
+            let (guilds, messages, private_channels): (usize, usize, usize) = redis::pipe()
+                .scard(&references[0])
+                .scard(&references[1])
+                .scard(&references[2])
+                .query_async(&mut *con)
+                .await?;
+
+            // Unless the reference cached the user again already.
+            if guilds + messages + private_channels > 0
+                && self.users.get(user_id.get()).await.is_none()
+            {
+                self.users.insert(user_id.get(), user).await;
+
+                return Ok(false);
+            }
+
+            return Ok(true);
+        }
+
+        let mut con = WatchedConnection::new(con);
+
+        for _ in 0..MAX_ATTEMPTS {
+            con.watch(&references[..]).await?;
+
+            let (guilds, messages, private_channels): (usize, usize, usize) = redis::pipe()
+                .scard(&references[0])
+                .scard(&references[1])
+                .scard(&references[2])
+                .query_async(&mut **con)
+                .await?;
+
+            if guilds + messages + private_channels > 0 {
+                con.unwatch().await?;
+
+                return Ok(false);
+            }
+
+            // Aborted if the user was referenced again since we started
+            // watching their references.
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            self.users.queue_delete(&mut pipe, &user_id.get());
+
+            let exec: Option<()> = pipe.query_async(&mut **con).await?;
+            con.executed();
+
+            if exec.is_some() {
+                return Ok(true);
+            }
+        }
+
+        Err(CacheError::Conflict)
+    }
+
+    /// Remove all users which are not referenced by anything anymore,
+    /// returning the number of removed users.
+    ///
+    /// Users are released as soon as their last reference is removed, this
+    /// catches the ones which were cached without a reference, like the
+    /// authors of interactions in private channels.
+    pub async fn sweep_users(&self) -> Result<usize, CacheError> {
+        let user_ids = self.users.keys();
+        pin_mut!(user_ids);
+
+        let mut removed = 0;
+
+        while let Some(user_id) = user_ids.try_next().await? {
+            if let Some(user_id) = UserId::new(user_id) {
+                if self.release_user(user_id).await? {
+                    removed += 1;
+                }
+            }
         }
+
+        Ok(removed)
+    }
+
+    /// Spawn a task which runs [`sweep_users`] every `period`.
+    ///
+    /// [`sweep_users`]: Self::sweep_users
+    pub fn sweep_users_every(self: &Arc<Self>, period: Duration) -> JoinHandle<()> {
+        let cache = Arc::clone(self);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+
+            loop {
+                interval.tick().await;
+
+                match cache.sweep_users().await {
+                    Ok(removed) => debug!("Swept {} unreferenced users", removed),
+                    Err(err) => error!("Failed to sweep users: {}", err),
+                }
+            }
+        })
     }
 
-    async fn unavailable_guild(&self, guild_id: GuildId) {
+    /// Mark a guild as unavailable until its next `GuildCreate`.
+    ///
+    /// The cached data of the guild is kept, it is only outdated while the
+    /// guild is unavailable.
+    pub(crate) async fn unavailable_guild(&self, guild_id: GuildId) {
+        self.readiness.guild_removed(guild_id);
+
         self.unavailable_guilds
             .insert("unavailable_guilds".into(), &guild_id.get())
+            .await
+            .ok();
+
+        let mut changes = FieldChanges::new();
+        changes.set("unavailable", &true);
+
+        self.guilds.update_fields(guild_id.get(), &changes).await;
+    }
+
+    /// IDs of the guilds which are unavailable, either because they have not
+    /// been received since `Ready` or because of an outage.
+    ///
+    /// Once all shards are connected, guilds in here after a while are most
+    /// likely in an outage rather than still loading.
+    pub async fn unavailable_guild_ids(&self) -> Result<Vec<GuildId>, CacheError> {
+        let ids = self
+            .unavailable_guilds
+            .get("unavailable_guilds".into())
+            .await?;
+
+        Ok(ids.into_iter().filter_map(GuildId::new).collect())
+    }
+
+    /// Whether a guild is unavailable, see [`unavailable_guild_ids`].
+    ///
+    /// [`unavailable_guild_ids`]: Self::unavailable_guild_ids
+    pub async fn is_guild_unavailable(&self, guild_id: GuildId) -> Result<bool, CacheError> {
+        self.unavailable_guilds
+            .includes("unavailable_guilds".into(), guild_id.get())
+            .await
+    }
+}
+
+#[async_trait::async_trait]
+impl UpdateCache for Ready {
+    async fn update(&self, cache: &InRedisCache) {
+        if cache.wants(ResourceType::USER_CURRENT) {
+            cache.cache_current_user(self.user.clone());
+        }
+
+        if cache.wants(ResourceType::GUILD) {
+            for guild in &self.guilds {
+                cache.unavailable_guild(guild.id).await;
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl UpdateCache for UnavailableGuild {
+    async fn update(&self, cache: &InRedisCache) {
+        if !cache.wants(ResourceType::GUILD) {
+            return;
+        }
+
+        cache.unavailable_guild(self.id).await;
+    }
+}
+
+#[async_trait::async_trait]
+impl UpdateCache for UserUpdate {
+    async fn update(&self, cache: &InRedisCache) {
+        if !cache.wants(ResourceType::USER_CURRENT) {
+            return;
+        }
+
+        cache.cache_current_user(self.0.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, GuildBuilder, MemberBuilder, ReadyBuilder, RoleBuilder};
+    use twilight_model::gateway::payload::incoming::GuildDelete;
+
+    #[tokio::test]
+    #[ignore = "needs the Redis server from docker-compose.yml"]
+    async fn test_ready() {
+        let cache = InRedisCache::new();
+        let guild_id = testing::guild_id(3_300_001);
+        let user_id = testing::user_id(3_300_002);
+
+        cache
+            .update(&ReadyBuilder::new(user_id).guild(guild_id).build())
             .await;
-        self.guilds.delete(guild_id.get());
+
+        assert_eq!(cache.current_user().map(|user| user.id), Some(user_id));
+        assert!(cache.is_guild_unavailable(guild_id).await.unwrap());
+        assert!(cache
+            .unavailable_guild_ids()
+            .await
+            .unwrap()
+            .contains(&guild_id));
+
+        cache
+            .update(&GuildBuilder::new(guild_id, user_id).create())
+            .await;
+
+        assert!(!cache.is_guild_unavailable(guild_id).await.unwrap());
+        assert!(!cache
+            .unavailable_guild_ids()
+            .await
+            .unwrap()
+            .contains(&guild_id));
+        assert!(!cache.guild(guild_id).await.unwrap().unwrap().unavailable());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the Redis server from docker-compose.yml"]
+    async fn test_unavailable_guild() {
+        let cache = InRedisCache::new();
+        let guild_id = testing::guild_id(3_300_011);
+        let user_id = testing::user_id(3_300_012);
+
+        // Left behind by a previous run.
+        cache.guilds.delete(guild_id.get()).await;
+
+        cache.update(&UnavailableGuild { id: guild_id }).await;
+
+        assert!(cache.is_guild_unavailable(guild_id).await.unwrap());
+        // Nothing to mark as unavailable yet.
+        assert!(cache.guild(guild_id).await.unwrap().is_none());
+
+        cache
+            .update(&GuildBuilder::new(guild_id, user_id).create())
+            .await;
+
+        assert!(!cache.is_guild_unavailable(guild_id).await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the Redis server from docker-compose.yml"]
+    async fn test_unavailable_guild_delete() {
+        let cache = InRedisCache::new();
+        let guild_id = testing::guild_id(3_300_021);
+        let user_id = testing::user_id(3_300_022);
+        let role_id = testing::role_id(3_300_023);
+
+        let guild = GuildBuilder::new(guild_id, user_id)
+            .member(MemberBuilder::new(guild_id, user_id).build())
+            .role(RoleBuilder::new(role_id).build())
+            .member_count(1);
+        cache.update(&guild.clone().create()).await;
+        assert!(cache.is_guild_ready(guild_id));
+
+        cache
+            .update(&GuildDelete {
+                id: guild_id,
+                unavailable: true,
+            })
+            .await;
+
+        // The data is kept, it is only outdated during the outage.
+        let cached = cache.guild(guild_id).await.unwrap().unwrap();
+        assert!(cached.unavailable());
+        assert!(
+            cache
+                .members
+                .includes((guild_id.get(), user_id.get()))
+                .await
+        );
+        assert!(cache.roles.includes(role_id.get()).await);
+        assert!(cache.is_guild_unavailable(guild_id).await.unwrap());
+        assert!(!cache.is_guild_ready(guild_id));
+
+        cache.update(&guild.create()).await;
+
+        assert!(!cache.guild(guild_id).await.unwrap().unwrap().unavailable());
+        assert!(!cache.is_guild_unavailable(guild_id).await.unwrap());
+        assert!(cache.is_guild_ready(guild_id));
     }
 }
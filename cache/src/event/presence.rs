@@ -1,4 +1,10 @@
-use crate::{config::ResourceType, model::CachedPresence, InRedisCache, UpdateCache};
+use crate::{
+    config::ResourceType,
+    connection::WatchedConnection,
+    model::{CachedPresence, PresenceCounts},
+    CacheError, InRedisCache, UpdateCache, MAX_ATTEMPTS,
+};
+use log::error;
 use twilight_model::{
     gateway::{payload::incoming::PresenceUpdate, presence::UserOrId},
     id::{GuildId, UserId},
@@ -17,40 +23,197 @@ impl InRedisCache {
         guild_id: GuildId,
         presences: impl IntoIterator<Item = CachedPresence>,
     ) {
-        let mut presences_to_cache = vec![];
-        let mut guild_presences = vec![];
+        let presences = presences
+            .into_iter()
+            .map(|presence| (presence.user_id(), Some(presence)))
+            .collect::<Vec<_>>();
+        let user_ids = presences
+            .iter()
+            .map(|(user_id, _)| user_id.get())
+            .collect::<Vec<_>>();
+
+        if let Err(err) = self.write_presences(guild_id, presences).await {
+            error!("Failed to cache presences in guild {}: {}", guild_id, err);
+        }
 
-        for presence in presences {
-            guild_presences.push(presence.user_id().get());
-            presences_to_cache.push(((guild_id.get(), presence.user_id().get()), presence));
+        self.guild_presences
+            .insert_multiple(guild_id.get(), user_ids)
+            .await
+            .ok();
+    }
+
+    /// Write the presences of members of a guild, removing the ones which
+    /// are `None`, and move the members between the presence counts of the
+    /// guild in the same transaction.
+    pub(crate) async fn write_presences(
+        &self,
+        guild_id: GuildId,
+        presences: Vec<(UserId, Option<CachedPresence>)>,
+    ) -> Result<(), CacheError> {
+        if presences.is_empty() {
+            return Ok(());
         }
 
-        self.presences
-            .insert_multiple(
-                // presences
-                //     .into_iter()
-                //     .map(|p| ((guild_id.get(), p.user_id().get()), p))
-                //     .collect(),
-                presences_to_cache,
-            )
+        let ids = presences
+            .iter()
+            .map(|(user_id, _)| (guild_id.get(), user_id.get()))
+            .collect::<Vec<_>>();
+        let mut con = self
+            .presences
+            .get_con()
+            .await
+            .ok_or(CacheError::RedisError)?;
+
+        // The counts are in another slot than the presences in a cluster,
+        // they may drift when a member's presence is written concurrently.
+        if self.presences.cluster {
+            let old = self.presences.get_multiple(&mut con, &ids).await?;
+
+            for (id, (_, presence)) in ids.iter().zip(&presences) {
+                match presence {
+                    Some(presence) => {
+                        self.presences.insert(*id, presence.clone()).await;
+                    }
+                    None => {
+                        self.presences.delete(*id).await;
+                    }
+                }
+            }
+
+            self.change_presence_counts(guild_id, |counts| {
+                for (old, (_, new)) in old.iter().zip(&presences) {
+                    counts.change(
+                        old.as_ref().map(CachedPresence::status),
+                        new.as_ref().map(CachedPresence::status),
+                    );
+                }
+            })
             .await;
 
-        self.guild_presences
-            .insert_multiple(guild_id.get(), guild_presences)
+            return Ok(());
+        }
+
+        // Every write of a presence writes the counts of its guild in the
+        // same transaction, so watching the counts notices any of them. They
+        // are in a key per guild, writes in other guilds don't conflict.
+        let counts_key = self.guild_presence_counts.watch_key(&guild_id.get());
+
+        let mut con = WatchedConnection::new(con);
+
+        for _ in 0..MAX_ATTEMPTS {
+            con.watch(&counts_key).await?;
+
+            let pipe: Result<_, CacheError> = async {
+                let old = self.presences.get_multiple(&mut con, &ids).await?;
+                let mut counts = self
+                    .guild_presence_counts
+                    .get_multiple(&mut con, &[guild_id.get()])
+                    .await?
+                    .pop()
+                    .flatten()
+                    .unwrap_or_default();
+
+                let mut pipe = redis::pipe();
+                pipe.atomic();
+
+                for ((id, old), (_, new)) in ids.iter().zip(&old).zip(&presences) {
+                    counts.change(
+                        old.as_ref().map(CachedPresence::status),
+                        new.as_ref().map(CachedPresence::status),
+                    );
+
+                    match new {
+                        Some(new) => self.presences.queue_insert(&mut pipe, id, new)?,
+                        None => self.presences.queue_delete(&mut pipe, id),
+                    }
+                }
+
+                self.guild_presence_counts
+                    .queue_insert(&mut pipe, &guild_id.get(), &counts)?;
+
+                Ok(pipe)
+            }
             .await;
+
+            let pipe = match pipe {
+                Ok(pipe) => pipe,
+                Err(err) => {
+                    con.unwatch().await?;
+
+                    return Err(err);
+                }
+            };
+
+            // Aborted if the presences of the guild were written since we
+            // started watching.
+            let exec: Option<()> = pipe.query_async(&mut **con).await?;
+            con.executed();
+
+            if exec.is_some() {
+                return Ok(());
+            }
+        }
+
+        Err(CacheError::Conflict)
     }
 
-    async fn cache_presence(&self, guild_id: GuildId, presence: CachedPresence) {
-        self.presences
-            .insert((guild_id.get(), presence.user_id().get()), presence)
+    /// Apply `change` to the presence counts of a guild, starting from zero
+    /// if it has none yet.
+    pub(crate) async fn change_presence_counts<F>(&self, guild_id: GuildId, change: F)
+    where
+        F: Fn(&mut PresenceCounts) + Sync,
+    {
+        let modified = self
+            .guild_presence_counts
+            .modify(guild_id.get(), |counts| {
+                change(counts);
+
+                true
+            })
             .await;
+
+        if let Ok(false) = modified {
+            let mut counts = PresenceCounts::default();
+            change(&mut counts);
+
+            self.guild_presence_counts
+                .insert(guild_id.get(), counts)
+                .await;
+        }
+    }
+
+    /// How many members of a guild are online, idle, do not disturb and
+    /// offline, as far as the cache knows.
+    ///
+    /// Online, idle and do not disturb members are counted from the cached
+    /// presences. Discord only sends the presences of members who are not
+    /// offline in large guilds, so everyone else in the member count of the
+    /// guild counts as offline. Returns `None` if the guild isn't cached.
+    pub async fn presence_counts(&self, guild_id: GuildId) -> Option<PresenceCounts> {
+        let guild = self.guilds.get(guild_id.get()).await?;
+        let mut counts = self
+            .guild_presence_counts
+            .get(guild_id.get())
+            .await
+            .unwrap_or_default();
+
+        if let Some(member_count) = guild.member_count() {
+            let present = counts.online + counts.idle + counts.dnd;
+
+            counts.offline = counts.offline.max(member_count.saturating_sub(present));
+        }
+
+        Some(counts)
     }
 }
 
 #[async_trait::async_trait]
 impl UpdateCache for PresenceUpdate {
     async fn update(&self, cache: &InRedisCache) {
-        if !cache.wants(ResourceType::PRESENCE) {
+        let user_id = presence_user_id(&self.user);
+        let scope = cache.guild_scope(self.guild_id).await.with_user(user_id);
+
+        if !cache.wants_in(ResourceType::PRESENCE, &scope) {
             return;
         }
 
@@ -59,9 +222,57 @@ impl UpdateCache for PresenceUpdate {
             client_status: self.client_status.clone(),
             guild_id: self.guild_id,
             status: self.status,
-            user_id: presence_user_id(&self.user),
+            user_id,
         };
 
-        cache.cache_presence(self.guild_id, presence).await;
+        cache.cache_presences(self.guild_id, [presence]).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, PresenceBuilder};
+    use futures::future;
+
+    #[tokio::test]
+    #[ignore = "needs the Redis server from docker-compose.yml"]
+    async fn test_concurrent_guilds() {
+        // The blob layout, where all presences share a key.
+        let cache = InRedisCache::new();
+        let guild_ids = (4_900_001..4_900_033)
+            .map(testing::guild_id)
+            .collect::<Vec<_>>();
+
+        for guild_id in &guild_ids {
+            cache.guild_presence_counts.delete(guild_id.get()).await;
+        }
+
+        let writes = guild_ids.iter().map(|guild_id| {
+            let presences = (1..=5)
+                .map(|user| {
+                    let user_id = testing::user_id(user);
+                    let presence = PresenceBuilder::new(*guild_id, user_id).build();
+
+                    (user_id, Some(CachedPresence::from(presence)))
+                })
+                .collect();
+
+            cache.write_presences(*guild_id, presences)
+        });
+
+        for result in future::join_all(writes).await {
+            result.expect("presences not written");
+        }
+
+        for guild_id in &guild_ids {
+            let counts = cache
+                .guild_presence_counts
+                .get(guild_id.get())
+                .await
+                .expect("counts not written");
+
+            assert_eq!(counts.online(), 5);
+        }
     }
 }
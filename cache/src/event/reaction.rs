@@ -1,86 +1,309 @@
-use crate::{config::ResourceType, InRedisCache, UpdateCache};
+use crate::{
+    config::ResourceType, event::message::message_key, CacheError, InRedisCache, UpdateCache,
+};
+use redis::Script;
 use twilight_model::{
-    channel::message::MessageReaction,
+    channel::{message::MessageReaction, ReactionType},
     gateway::payload::incoming::{
         ReactionAdd, ReactionRemove, ReactionRemoveAll, ReactionRemoveEmoji,
     },
+    id::{ChannelId, MessageId, UserId},
 };
 
-#[async_trait::async_trait]
-impl UpdateCache for ReactionAdd {
-    async fn update(&self, cache: &InRedisCache) {
-        if !cache.wants(ResourceType::REACTION) {
-            return;
+/// Add the emoji to the reactions of the message and the user to the users of
+/// the reaction.
+///
+/// `KEYS`: the reactions of the message, the users of the reaction.
+/// `ARGV`: the emoji, the user.
+const ADD_SCRIPT: &str = r"
+    redis.call('SADD', KEYS[1], ARGV[1])
+    return redis.call('SADD', KEYS[2], ARGV[2])
+";
+
+/// Remove the user from the users of the reaction, and the emoji from the
+/// reactions of the message once nobody is left.
+///
+/// `KEYS`: the users of the reaction, the reactions of the message.
+/// `ARGV`: the number of ways the user is stored, followed by them and the
+/// ways the emoji is stored.
+const REMOVE_SCRIPT: &str = r"
+    local users = tonumber(ARGV[1])
+    local removed = redis.call('SREM', KEYS[1], unpack(ARGV, 2, users + 1))
+
+    if redis.call('SCARD', KEYS[1]) == 0 then
+        redis.call('SREM', KEYS[2], unpack(ARGV, users + 2))
+    end
+
+    return removed
+";
+
+/// Custom emojis are identified by their ID, unicode emojis by themselves.
+fn emoji_key(emoji: &ReactionType) -> String {
+    match emoji {
+        ReactionType::Custom { id, .. } => id.to_string(),
+        ReactionType::Unicode { name } => name.clone(),
+    }
+}
+
+/// Key of the users who reacted to a message with an emoji, in
+/// [`InRedisCache::reaction_users`].
+fn reaction_key(message_id: u64, emoji: &str) -> String {
+    format!("{}:{}", message_id, emoji)
+}
+
+impl InRedisCache {
+    /// Get the users who reacted to a message with an emoji.
+    ///
+    /// Only reactions added while the cache was running are known, reactions
+    /// which were already on the message when it was cached are counted in
+    /// its [`MessageReaction`]s but not attributed to anybody.
+    pub async fn reacted_users(
+        &self,
+        message_id: MessageId,
+        emoji: &ReactionType,
+    ) -> Result<Vec<UserId>, CacheError> {
+        let key = reaction_key(message_id.get(), &emoji_key(emoji));
+
+        Ok(self
+            .reaction_users
+            .get(key)
+            .await?
+            .into_iter()
+            .filter_map(UserId::new)
+            .collect())
+    }
+
+    /// Whether a user reacted to a message with an emoji.
+    ///
+    /// See [`reacted_users`] for which reactions are known.
+    ///
+    /// [`reacted_users`]: Self::reacted_users
+    pub async fn has_reacted(
+        &self,
+        message_id: MessageId,
+        emoji: &ReactionType,
+        user_id: UserId,
+    ) -> Result<bool, CacheError> {
+        let key = reaction_key(message_id.get(), &emoji_key(emoji));
+
+        self.reaction_users.includes(key, user_id.get()).await
+    }
+
+    async fn add_reaction_user(
+        &self,
+        message_id: MessageId,
+        emoji: &ReactionType,
+        user_id: UserId,
+    ) -> Result<(), CacheError> {
+        let emoji = emoji_key(emoji);
+        let key = reaction_key(message_id.get(), &emoji);
+
+        // The sets are in different slots in a cluster, a concurrent removal
+        // of the last user may remove the emoji of the message in between.
+        if self.reaction_users.cluster {
+            self.message_reactions
+                .insert(message_id.get(), &emoji)
+                .await?;
+            self.reaction_users
+                .insert(key.clone(), &user_id.get())
+                .await?;
+        } else {
+            let mut con = self.reaction_users.get_con().await?;
+
+            Script::new(ADD_SCRIPT)
+                .key(self.message_reactions.get_key(message_id.get()))
+                .key(self.reaction_users.get_key(key.clone()))
+                .arg(self.message_reactions.pack(&emoji)?)
+                .arg(self.reaction_users.pack(&user_id.get())?)
+                .invoke_async::<_, ()>(&mut *con)
+                .await?;
         }
 
-        let mut message = match cache.messages.get(self.0.message_id.get()).await {
-            Some(message) => message,
-            None => return,
+        self.user_reactions.insert(user_id.get(), &key).await
+    }
+
+    /// Remove a user from the users of a reaction, returning whether they
+    /// were in it.
+    pub(crate) async fn remove_reaction_user(
+        &self,
+        key: String,
+        user_id: u64,
+    ) -> Result<bool, CacheError> {
+        self.user_reactions.remove(user_id, key.clone()).await?;
+
+        let (message_id, emoji) = match key.split_once(':') {
+            Some((message_id, emoji)) => match message_id.parse::<u64>() {
+                Ok(message_id) => (message_id, emoji.to_owned()),
+                Err(_) => return self.reaction_users.remove(key, user_id).await,
+            },
+            None => return self.reaction_users.remove(key, user_id).await,
         };
 
-        if let Some(reaction) = message
-            .reactions
-            .iter_mut()
-            .find(|r| r.emoji == self.0.emoji)
-        {
-            if !reaction.me {
-                // TODO: cache
-                // if let Some(current_user) = cache.current_user() {
-                //     if current_user.id == self.0.user_id {
-                //         reaction.me = true;
-                //     }
-                // }
+        // See `add_reaction_user`.
+        if self.reaction_users.cluster {
+            let removed = self.reaction_users.remove(key.clone(), user_id).await?;
+
+            if self.reaction_users.size(key).await? == 0 {
+                self.message_reactions.remove(message_id, emoji).await?;
             }
 
-            reaction.count += 1;
-        } else {
-            // TODO: cache
-            // let me = cache
-            //     .current_user()
-            //     .map(|user| user.id == self.0.user_id)
-            //     .unwrap_or_default();
-
-            // message.reactions.push(MessageReaction {
-            //     count: 1,
-            //     emoji: self.0.emoji.clone(),
-            //     me,
-            // });
+            return Ok(removed);
+        }
+
+        let users = self.reaction_users.member_packs(&user_id)?;
+        let emojis = self.message_reactions.member_packs(&emoji)?;
+        let mut con = self.reaction_users.get_con().await?;
+
+        let removed: usize = Script::new(REMOVE_SCRIPT)
+            .key(self.reaction_users.get_key(key))
+            .key(self.message_reactions.get_key(message_id))
+            .arg(users.len())
+            .arg(users)
+            .arg(emojis)
+            .invoke_async(&mut *con)
+            .await?;
+
+        Ok(removed > 0)
+    }
+
+    /// Forget who reacted to a message with an emoji, or with any emoji.
+    pub(crate) async fn clear_reaction_users(
+        &self,
+        message_id: MessageId,
+        emoji: Option<&ReactionType>,
+    ) -> Result<(), CacheError> {
+        let emojis = match emoji {
+            Some(emoji) => vec![emoji_key(emoji)],
+            None => self.message_reactions.get(message_id.get()).await?,
+        };
+
+        for emoji in emojis {
+            let key = reaction_key(message_id.get(), &emoji);
+
+            for user_id in self.reaction_users.get(key.clone()).await? {
+                self.user_reactions.remove(user_id, key.clone()).await?;
+            }
+
+            self.reaction_users.delete(key).await?;
+            self.message_reactions
+                .remove(message_id.get(), emoji)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Forget who reacted to the messages of a channel which was deleted.
+    ///
+    /// The messages stay cached, like in twilight's in-memory cache, but
+    /// their reactions can't change anymore.
+    pub(crate) async fn clear_channel_reaction_users(
+        &self,
+        channel_id: ChannelId,
+    ) -> Result<(), CacheError> {
+        for message_id in self.channel_messages.get(channel_id.get()).await? {
+            if let Some(message_id) = MessageId::new(message_id) {
+                self.clear_reaction_users(message_id, None).await?;
+            }
         }
+
+        Ok(())
     }
 }
 
 #[async_trait::async_trait]
-impl UpdateCache for ReactionRemove {
+impl UpdateCache for ReactionAdd {
     async fn update(&self, cache: &InRedisCache) {
         if !cache.wants(ResourceType::REACTION) {
             return;
         }
 
-        let mut message = match cache.messages.get(self.0.message_id.get()).await {
-            Some(message) => message,
-            None => return,
-        };
-
-        if let Some(reaction) = message
-            .reactions
-            .iter_mut()
-            .find(|r| r.emoji == self.0.emoji)
-        {
-            if reaction.me {
-                // TODO: Cache
-                // if let Some(current_user) = cache.current_user() {
-                //     if current_user.id == self.0.user_id {
-                //         reaction.me = false;
-                //     }
-                // }
-            }
+        let me = cache
+            .current_user()
+            .map(|user| user.id == self.0.user_id)
+            .unwrap_or_default();
+
+        let cached = cache
+            .messages
+            .modify(message_key(self.0.guild_id, self.0.message_id), |message| {
+                if let Some(reaction) = message
+                    .reactions
+                    .iter_mut()
+                    .find(|r| r.emoji == self.0.emoji)
+                {
+                    if me {
+                        reaction.me = true;
+                    }
+
+                    reaction.count += 1;
+                } else {
+                    message.reactions.push(MessageReaction {
+                        count: 1,
+                        emoji: self.0.emoji.clone(),
+                        me,
+                    });
+                }
+
+                true
+            })
+            .await;
+
+        // Like the counts, users are only known for reactions to cached
+        // messages, the tracking is removed together with the message.
+        if let Ok(true) = cached {
+            cache
+                .add_reaction_user(self.0.message_id, &self.0.emoji, self.0.user_id)
+                .await
+                .ok();
+        }
+    }
+}
 
-            if reaction.count > 1 {
-                reaction.count -= 1;
-            } else {
-                message.reactions.retain(|e| !(e.emoji == self.0.emoji));
-            }
+#[async_trait::async_trait]
+impl UpdateCache for ReactionRemove {
+    async fn update(&self, cache: &InRedisCache) {
+        if !cache.wants(ResourceType::REACTION) {
+            return;
         }
+
+        let key = reaction_key(self.0.message_id.get(), &emoji_key(&self.0.emoji));
+
+        cache
+            .remove_reaction_user(key, self.0.user_id.get())
+            .await
+            .ok();
+
+        let me = cache
+            .current_user()
+            .map(|user| user.id == self.0.user_id)
+            .unwrap_or_default();
+
+        cache
+            .messages
+            .modify(message_key(self.0.guild_id, self.0.message_id), |message| {
+                let reaction = match message
+                    .reactions
+                    .iter_mut()
+                    .find(|r| r.emoji == self.0.emoji)
+                {
+                    Some(reaction) => reaction,
+                    None => return false,
+                };
+
+                if me {
+                    reaction.me = false;
+                }
+
+                if reaction.count > 1 {
+                    reaction.count -= 1;
+                } else {
+                    message.reactions.retain(|e| !(e.emoji == self.0.emoji));
+                }
+
+                true
+            })
+            .await
+            .ok();
     }
 }
 
@@ -91,14 +314,17 @@ impl UpdateCache for ReactionRemoveAll {
             return;
         }
 
-        let mut message = match cache.messages.get(self.message_id.get()).await {
-            Some(message) => message,
-            None => return,
-        };
+        cache.clear_reaction_users(self.message_id, None).await.ok();
 
-        message.reactions.clear();
+        cache
+            .messages
+            .modify(message_key(self.guild_id, self.message_id), |message| {
+                message.reactions.clear();
 
-        cache.messages.insert(self.message_id.get(), message).await;
+                true
+            })
+            .await
+            .ok();
     }
 }
 
@@ -109,17 +335,110 @@ impl UpdateCache for ReactionRemoveEmoji {
             return;
         }
 
-        let mut message = match cache.messages.get(self.message_id.get()).await {
-            Some(message) => message,
-            None => return,
+        cache
+            .clear_reaction_users(self.message_id, Some(&self.emoji))
+            .await
+            .ok();
+
+        cache
+            .messages
+            .modify(
+                message_key(Some(self.guild_id), self.message_id),
+                |message| {
+                    let maybe_index = message.reactions.iter().position(|r| r.emoji == self.emoji);
+
+                    match maybe_index {
+                        Some(index) => {
+                            message.reactions.remove(index);
+
+                            true
+                        }
+                        None => false,
+                    }
+                },
+            )
+            .await
+            .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, MessageBuilder, ReactionBuilder, TextChannelBuilder};
+    use twilight_model::{channel::Channel, gateway::payload::incoming::ChannelDelete};
+
+    #[tokio::test]
+    #[ignore = "needs the Redis server from docker-compose.yml"]
+    async fn test_reaction_users() {
+        let cache = InRedisCache::new();
+        let guild_id = testing::guild_id(3_300_001);
+        let channel_id = testing::channel_id(3_300_002);
+        let message_id = testing::message_id(3_300_003);
+        let user_ids = [testing::user_id(3_300_004), testing::user_id(3_300_005)];
+        let emoji = testing::unicode_emoji("👍");
+
+        let reaction = |user_id| {
+            ReactionBuilder::new(guild_id, channel_id, message_id, user_id, emoji.clone())
         };
 
-        let maybe_index = message.reactions.iter().position(|r| r.emoji == self.emoji);
+        // Reactions to messages which are not cached are not tracked.
+        cache.update(&reaction(user_ids[0]).add()).await;
+        assert!(cache
+            .reacted_users(message_id, &emoji)
+            .await
+            .unwrap()
+            .is_empty());
+
+        cache
+            .update(&MessageBuilder::new(guild_id, channel_id, message_id, user_ids[0]).create())
+            .await;
 
-        if let Some(index) = maybe_index {
-            message.reactions.remove(index);
-            cache.messages.insert(self.message_id.get(), message).await;
+        for user_id in user_ids {
+            cache.update(&reaction(user_id).add()).await;
         }
+
+        let mut reacted = cache.reacted_users(message_id, &emoji).await.unwrap();
+        reacted.sort();
+        assert_eq!(reacted, user_ids);
+
+        cache.update(&reaction(user_ids[0]).remove()).await;
+        assert!(!cache
+            .has_reacted(message_id, &emoji, user_ids[0])
+            .await
+            .unwrap());
+        assert_eq!(
+            cache.message_reactions.get(message_id.get()).await.unwrap(),
+            ["👍"]
+        );
+
+        cache.update(&reaction(user_ids[1]).remove()).await;
+        assert!(cache
+            .message_reactions
+            .get(message_id.get())
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Deleting the channel forgets who reacted to its messages.
+        cache.update(&reaction(user_ids[1]).add()).await;
+        cache
+            .update(&ChannelDelete(Channel::Guild(
+                TextChannelBuilder::new(guild_id, channel_id).build(),
+            )))
+            .await;
+
+        assert!(cache
+            .reacted_users(message_id, &emoji)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(cache
+            .user_reactions
+            .get(user_ids[1].get())
+            .await
+            .unwrap()
+            .is_empty());
     }
 }
 
@@ -39,13 +39,15 @@ impl InRedisCache {
             .await;
 
         // Insert the role into the all roles map
-        self.roles.insert(
-            guild_id.get(),
-            GuildResource {
-                guild_id,
-                value: role,
-            },
-        );
+        self.roles
+            .insert(
+                role.id.get(),
+                GuildResource {
+                    guild_id,
+                    value: role,
+                },
+            )
+            .await;
     }
 
     async fn delete_role(&self, guild_id: GuildId, role_id: RoleId) {
@@ -58,7 +60,9 @@ impl InRedisCache {
 #[async_trait::async_trait]
 impl UpdateCache for RoleCreate {
     async fn update(&self, cache: &InRedisCache) {
-        if !cache.wants(ResourceType::ROLE) {
+        let scope = cache.guild_scope(self.guild_id).await;
+
+        if !cache.wants_in(ResourceType::ROLE, &scope) {
             return;
         }
 
@@ -80,7 +84,9 @@ impl UpdateCache for RoleDelete {
 #[async_trait::async_trait]
 impl UpdateCache for RoleUpdate {
     async fn update(&self, cache: &InRedisCache) {
-        if !cache.wants(ResourceType::ROLE) {
+        let scope = cache.guild_scope(self.guild_id).await;
+
+        if !cache.wants_in(ResourceType::ROLE, &scope) {
             return;
         }
 
@@ -0,0 +1,347 @@
+//! Read-through fallback for lookups which miss the cache.
+
+use std::{collections::HashMap, sync::Arc};
+
+use tokio::sync::{Mutex, MutexGuard};
+use twilight_model::{
+    channel::{Channel, GuildChannel},
+    guild::{Guild, Member},
+    id::{ChannelId, GuildId, UserId},
+};
+
+use crate::{
+    model::{CachedGuild, CachedMember},
+    CacheError, GuildResource, InRedisCache,
+};
+
+/// Error returned by a [`Fetcher`].
+pub type FetchError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Source for resources which are not in the cache yet.
+///
+/// Implemented for [`twilight_http::Client`] when the `http` feature is
+/// enabled. Tests can plug in their own implementation.
+#[async_trait::async_trait]
+pub trait Fetcher: Send + Sync {
+    /// Fetch a guild, returning `None` if it does not exist.
+    async fn guild(&self, guild_id: GuildId) -> Result<Option<Guild>, FetchError>;
+
+    /// Fetch a member of a guild, returning `None` if they are not a member.
+    async fn member(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+    ) -> Result<Option<Member>, FetchError>;
+
+    /// Fetch a channel, returning `None` if it does not exist.
+    async fn channel(&self, channel_id: ChannelId) -> Result<Option<Channel>, FetchError>;
+}
+
+#[cfg(feature = "http")]
+#[async_trait::async_trait]
+impl Fetcher for twilight_http::Client {
+    async fn guild(&self, guild_id: GuildId) -> Result<Option<Guild>, FetchError> {
+        let response = match self.guild(guild_id).exec().await {
+            Ok(response) => response,
+            Err(err) if is_not_found(&err) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Some(response.model().await?))
+    }
+
+    async fn member(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+    ) -> Result<Option<Member>, FetchError> {
+        let response = match self.guild_member(guild_id, user_id).exec().await {
+            Ok(response) => response,
+            Err(err) if is_not_found(&err) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Some(response.model().await?))
+    }
+
+    async fn channel(&self, channel_id: ChannelId) -> Result<Option<Channel>, FetchError> {
+        let response = match self.channel(channel_id).exec().await {
+            Ok(response) => response,
+            Err(err) if is_not_found(&err) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Some(response.model().await?))
+    }
+}
+
+#[cfg(feature = "http")]
+fn is_not_found(err: &twilight_http::Error) -> bool {
+    matches!(
+        err.kind(),
+        twilight_http::error::ErrorType::Response { status, .. } if status.raw() == 404
+    )
+}
+
+/// Key of a lookup which is currently being fetched.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum FetchKey {
+    Channel(ChannelId),
+    Guild(GuildId),
+    Member(GuildId, UserId),
+}
+
+/// Locks handed out to concurrent lookups of the same key, so only the first
+/// one hits the API and the others read its result from the cache.
+#[derive(Default)]
+pub(crate) struct InFlight(std::sync::Mutex<HashMap<FetchKey, Arc<Mutex<()>>>>);
+
+impl InFlight {
+    fn lock_for(&self, key: FetchKey) -> InFlightLock<'_> {
+        let lock = self
+            .0
+            .lock()
+            .expect("in flight fetches poisoned")
+            .entry(key)
+            .or_default()
+            .clone();
+
+        InFlightLock {
+            in_flight: self,
+            key,
+            lock,
+        }
+    }
+}
+
+/// The lock of a key, removed from [`InFlight`] once it is dropped by the
+/// last lookup holding or waiting for it, also if that lookup is cancelled.
+struct InFlightLock<'a> {
+    in_flight: &'a InFlight,
+    key: FetchKey,
+    lock: Arc<Mutex<()>>,
+}
+
+impl InFlightLock<'_> {
+    async fn lock(&self) -> MutexGuard<'_, ()> {
+        self.lock.lock().await
+    }
+}
+
+impl Drop for InFlightLock<'_> {
+    fn drop(&mut self) {
+        let mut map = match self.in_flight.0.lock() {
+            Ok(map) => map,
+            Err(_) => return,
+        };
+
+        // Two references: ours and the one in the map. Anyone else still
+        // waiting will re-read the cache once they get the lock.
+        if Arc::strong_count(&self.lock) <= 2 {
+            map.remove(&self.key);
+        }
+    }
+}
+
+impl InRedisCache {
+    /// Set the [`Fetcher`] used to fill the cache when a lookup misses.
+    pub fn set_fetcher(&mut self, fetcher: Arc<dyn Fetcher>) {
+        self.fetcher.replace(fetcher);
+    }
+
+    /// Get a guild by ID.
+    ///
+    /// If the guild is not cached and a [`Fetcher`] is set, it is fetched and
+    /// cached first.
+    pub async fn guild(&self, guild_id: GuildId) -> Result<Option<CachedGuild>, CacheError> {
+        if let Some(guild) = self.guilds.get(guild_id.get()).await {
+            return Ok(Some(guild));
+        }
+
+        let fetcher = match &self.fetcher {
+            Some(fetcher) => fetcher,
+            None => return Ok(None),
+        };
+
+        let key = FetchKey::Guild(guild_id);
+        let lock = self.in_flight.lock_for(key);
+        let _guard = lock.lock().await;
+
+        let result = match self.guilds.get(guild_id.get()).await {
+            Some(guild) => Ok(Some(guild)),
+            None => match fetcher.guild(guild_id).await {
+                Ok(Some(guild)) => {
+                    self.cache_guild(guild).await;
+
+                    Ok(self.guilds.get(guild_id.get()).await)
+                }
+                Ok(None) => Ok(None),
+                Err(err) => Err(CacheError::Fetch(err)),
+            },
+        };
+
+        result
+    }
+
+    /// Get a member of a guild by their user ID.
+    ///
+    /// If the member is not cached and a [`Fetcher`] is set, they are fetched
+    /// and cached first.
+    pub async fn member(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+    ) -> Result<Option<CachedMember>, CacheError> {
+        let id = (guild_id.get(), user_id.get());
+
+        if let Some(member) = self.members.get(id).await {
+            return Ok(Some(member));
+        }
+
+        let fetcher = match &self.fetcher {
+            Some(fetcher) => fetcher,
+            None => return Ok(None),
+        };
+
+        let key = FetchKey::Member(guild_id, user_id);
+        let lock = self.in_flight.lock_for(key);
+        let _guard = lock.lock().await;
+
+        let result = match self.members.get(id).await {
+            Some(member) => Ok(Some(member)),
+            None => match fetcher.member(guild_id, user_id).await {
+                Ok(Some(member)) => {
+                    self.cache_member(guild_id, member).await;
+
+                    Ok(self.members.get(id).await)
+                }
+                Ok(None) => Ok(None),
+                Err(err) => Err(CacheError::Fetch(err)),
+            },
+        };
+
+        result
+    }
+
+    /// Get a guild channel by ID.
+    ///
+    /// If the channel is not cached and a [`Fetcher`] is set, it is fetched
+    /// and cached first.
+    pub async fn guild_channel(
+        &self,
+        channel_id: ChannelId,
+    ) -> Result<Option<GuildResource<GuildChannel>>, CacheError> {
+        if let Some(channel) = self.channels_guild.get(channel_id.get()).await {
+            return Ok(Some(channel));
+        }
+
+        let fetcher = match &self.fetcher {
+            Some(fetcher) => fetcher,
+            None => return Ok(None),
+        };
+
+        let key = FetchKey::Channel(channel_id);
+        let lock = self.in_flight.lock_for(key);
+        let _guard = lock.lock().await;
+
+        let result = match self.channels_guild.get(channel_id.get()).await {
+            Some(channel) => Ok(Some(channel)),
+            None => match fetcher.channel(channel_id).await {
+                Ok(Some(Channel::Guild(channel))) => {
+                    if let Some(guild_id) = channel.guild_id() {
+                        self.cache_guild_channel(guild_id, channel).await;
+                    }
+
+                    Ok(self.channels_guild.get(channel_id.get()).await)
+                }
+                Ok(_) => Ok(None),
+                Err(err) => Err(CacheError::Fetch(err)),
+            },
+        };
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use futures::future;
+
+    use super::*;
+    use crate::testing::{self, GuildBuilder};
+
+    /// Serves guilds, counting how often it was asked for one.
+    #[derive(Default)]
+    struct CountingFetcher {
+        guilds: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl Fetcher for CountingFetcher {
+        async fn guild(&self, guild_id: GuildId) -> Result<Option<Guild>, FetchError> {
+            self.guilds.fetch_add(1, Ordering::SeqCst);
+
+            // Give the other lookups time to miss the cache as well.
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            Ok(Some(
+                GuildBuilder::new(guild_id, testing::user_id(3_500_002)).build(),
+            ))
+        }
+
+        async fn member(&self, _: GuildId, _: UserId) -> Result<Option<Member>, FetchError> {
+            Ok(None)
+        }
+
+        async fn channel(&self, _: ChannelId) -> Result<Option<Channel>, FetchError> {
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_lookup() {
+        let in_flight = InFlight::default();
+        let key = FetchKey::Guild(testing::guild_id(1));
+
+        let first = in_flight.lock_for(key);
+        let guard = first.lock().await;
+
+        // Gives up while the first lookup is still running.
+        let second = in_flight.lock_for(key);
+        let wait = tokio::time::timeout(Duration::from_millis(10), second.lock());
+        assert!(wait.await.is_err());
+
+        drop(guard);
+        drop(first);
+        assert_eq!(in_flight.0.lock().unwrap().len(), 1);
+
+        drop(second);
+        assert!(in_flight.0.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the Redis server from docker-compose.yml"]
+    async fn test_concurrent_misses_fetch_once() {
+        let fetcher = Arc::new(CountingFetcher::default());
+        let mut cache = InRedisCache::new();
+        cache.set_fetcher(fetcher.clone());
+
+        let guild_id = testing::guild_id(3_500_001);
+        cache.guilds.delete(guild_id.get()).await;
+
+        let guilds = future::join_all((0..10).map(|_| cache.guild(guild_id))).await;
+
+        assert_eq!(fetcher.guilds.load(Ordering::SeqCst), 1);
+
+        for guild in guilds {
+            assert_eq!(guild.unwrap().map(|guild| guild.id()), Some(guild_id));
+        }
+
+        assert!(cache.in_flight.0.lock().unwrap().is_empty());
+    }
+}
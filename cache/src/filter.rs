@@ -0,0 +1,226 @@
+//! Evaluation of the [`CacheRule`]s of the configuration.
+
+use twilight_model::id::{ChannelId, GuildId, UserId};
+
+use crate::{config::ResourceType, CacheRule, InRedisCache};
+
+/// IDs of a resource which is about to be cached, checked against the
+/// [`CacheRule`]s.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Scope {
+    guild_id: Option<GuildId>,
+    channel_id: Option<ChannelId>,
+    user_id: Option<UserId>,
+    member_count: Option<u64>,
+}
+
+impl Scope {
+    pub(crate) const fn guild(guild_id: GuildId) -> Self {
+        Self {
+            guild_id: Some(guild_id),
+            channel_id: None,
+            user_id: None,
+            member_count: None,
+        }
+    }
+
+    pub(crate) const fn channel(channel_id: ChannelId) -> Self {
+        Self {
+            guild_id: None,
+            channel_id: Some(channel_id),
+            user_id: None,
+            member_count: None,
+        }
+    }
+
+    pub(crate) const fn with_channel(mut self, channel_id: ChannelId) -> Self {
+        self.channel_id = Some(channel_id);
+
+        self
+    }
+
+    pub(crate) const fn with_user(mut self, user_id: UserId) -> Self {
+        self.user_id = Some(user_id);
+
+        self
+    }
+
+    pub(crate) const fn with_member_count(mut self, member_count: Option<u64>) -> Self {
+        self.member_count = member_count;
+
+        self
+    }
+}
+
+impl CacheRule {
+    fn allows(&self, resource_type: ResourceType, scope: &Scope) -> bool {
+        fn contains<T: PartialEq>(ids: &[T], id: Option<T>) -> Option<bool> {
+            id.map(|id| ids.contains(&id))
+        }
+
+        let (types, allowed) = match self {
+            Self::OnlyGuilds(types, ids) => (types, contains(ids, scope.guild_id)),
+            Self::SkipGuilds(types, ids) => (types, contains(ids, scope.guild_id).map(|c| !c)),
+            Self::OnlyChannels(types, ids) => (types, contains(ids, scope.channel_id)),
+            Self::SkipChannels(types, ids) => (types, contains(ids, scope.channel_id).map(|c| !c)),
+            Self::SkipUsers(types, ids) => (types, contains(ids, scope.user_id).map(|c| !c)),
+            Self::MaxGuildMembers(types, max) => {
+                (types, scope.member_count.map(|count| count <= *max))
+            }
+        };
+
+        // Rules don't apply to resources without the ID they look at.
+        !types.intersects(resource_type) || allowed.unwrap_or(true)
+    }
+}
+
+impl InRedisCache {
+    /// Scope of a resource in a guild, with the member count of the guild if
+    /// a rule needs it.
+    pub(crate) async fn guild_scope(&self, guild_id: GuildId) -> Scope {
+        let scope = Scope::guild(guild_id);
+
+        let needs_member_count = self
+            .config
+            .rules()
+            .iter()
+            .any(|rule| matches!(rule, CacheRule::MaxGuildMembers(..)));
+
+        if !needs_member_count {
+            return scope;
+        }
+
+        let member_count = self
+            .guilds
+            .get(guild_id.get())
+            .await
+            .and_then(|guild| guild.member_count());
+
+        scope.with_member_count(member_count)
+    }
+
+    /// Scope of a resource which may be in a guild.
+    pub(crate) async fn channel_scope(
+        &self,
+        guild_id: Option<GuildId>,
+        channel_id: ChannelId,
+    ) -> Scope {
+        match guild_id {
+            Some(guild_id) => self.guild_scope(guild_id).await.with_channel(channel_id),
+            None => Scope::channel(channel_id),
+        }
+    }
+
+    /// Determine whether a resource should be cached, checking both the
+    /// resource types and the rules of the configuration.
+    pub(crate) fn wants_in(&self, resource_type: ResourceType, scope: &Scope) -> bool {
+        self.wants(resource_type)
+            && self
+                .config
+                .rules()
+                .iter()
+                .all(|rule| rule.allows(resource_type, scope))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, Config};
+
+    #[test]
+    fn test_guild_rules() {
+        let only = CacheRule::OnlyGuilds(ResourceType::MEMBER, vec![testing::guild_id(1)]);
+        let skip = CacheRule::SkipGuilds(ResourceType::MEMBER, vec![testing::guild_id(1)]);
+        let in_guild = Scope::guild(testing::guild_id(1));
+        let in_other = Scope::guild(testing::guild_id(2));
+
+        assert!(only.allows(ResourceType::MEMBER, &in_guild));
+        assert!(!only.allows(ResourceType::MEMBER, &in_other));
+        assert!(!skip.allows(ResourceType::MEMBER, &in_guild));
+        assert!(skip.allows(ResourceType::MEMBER, &in_other));
+
+        // Other resource types and resources outside of guilds are left alone.
+        assert!(skip.allows(ResourceType::ROLE, &in_guild));
+        assert!(only.allows(
+            ResourceType::MEMBER,
+            &Scope::channel(testing::channel_id(1))
+        ));
+    }
+
+    #[test]
+    fn test_channel_rules() {
+        let only = CacheRule::OnlyChannels(ResourceType::MESSAGE, vec![testing::channel_id(1)]);
+        let skip = CacheRule::SkipChannels(ResourceType::MESSAGE, vec![testing::channel_id(1)]);
+        let guild = Scope::guild(testing::guild_id(1));
+
+        assert!(only.allows(
+            ResourceType::MESSAGE,
+            &guild.with_channel(testing::channel_id(1))
+        ));
+        assert!(!only.allows(
+            ResourceType::MESSAGE,
+            &guild.with_channel(testing::channel_id(2))
+        ));
+        assert!(!skip.allows(
+            ResourceType::MESSAGE,
+            &Scope::channel(testing::channel_id(1))
+        ));
+
+        // Resources without a channel are left alone.
+        assert!(only.allows(ResourceType::MESSAGE, &guild));
+    }
+
+    #[test]
+    fn test_skip_users() {
+        let rule = CacheRule::SkipUsers(
+            ResourceType::MEMBER | ResourceType::PRESENCE,
+            vec![testing::user_id(1)],
+        );
+        let guild = Scope::guild(testing::guild_id(1));
+
+        assert!(!rule.allows(ResourceType::MEMBER, &guild.with_user(testing::user_id(1))));
+        assert!(!rule.allows(
+            ResourceType::PRESENCE,
+            &guild.with_user(testing::user_id(1))
+        ));
+        assert!(rule.allows(ResourceType::MEMBER, &guild.with_user(testing::user_id(2))));
+
+        // A scope without the user can't be skipped, which is why members
+        // and presences are checked one user at a time.
+        assert!(rule.allows(ResourceType::MEMBER, &guild));
+    }
+
+    #[test]
+    fn test_max_guild_members() {
+        let rule = CacheRule::MaxGuildMembers(ResourceType::MEMBER, 100);
+        let guild = Scope::guild(testing::guild_id(1));
+
+        assert!(rule.allows(ResourceType::MEMBER, &guild.with_member_count(Some(100))));
+        assert!(!rule.allows(ResourceType::MEMBER, &guild.with_member_count(Some(101))));
+        assert!(rule.allows(ResourceType::MEMBER, &guild.with_member_count(None)));
+    }
+
+    #[tokio::test]
+    async fn test_wants_in() {
+        let mut config = Config::new();
+        config.resource_types = ResourceType::MEMBER | ResourceType::MESSAGE;
+        config.rules_mut().extend([
+            CacheRule::SkipUsers(ResourceType::MEMBER, vec![testing::user_id(1)]),
+            CacheRule::OnlyGuilds(ResourceType::all(), vec![testing::guild_id(1)]),
+        ]);
+
+        let cache = InRedisCache::with_config(config);
+        let guild = Scope::guild(testing::guild_id(1));
+
+        assert!(cache.wants_in(ResourceType::MEMBER, &guild.with_user(testing::user_id(2))));
+        assert!(cache.wants_in(ResourceType::MESSAGE, &guild.with_user(testing::user_id(1))));
+
+        // Every rule has to allow the resource.
+        assert!(!cache.wants_in(ResourceType::MEMBER, &guild.with_user(testing::user_id(1))));
+        assert!(!cache.wants_in(ResourceType::MESSAGE, &Scope::guild(testing::guild_id(2))));
+
+        // And its type has to be enabled.
+        assert!(!cache.wants_in(ResourceType::ROLE, &guild));
+    }
+}
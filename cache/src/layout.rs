@@ -0,0 +1,274 @@
+//! Encoding for the [`KeyLayout::Hash`] layout, where every entity is stored
+//! as its own Redis hash with a field per attribute.
+//!
+//! [`KeyLayout::Hash`]: crate::KeyLayout::Hash
+
+use rmpv::Value;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{compress, CacheError};
+
+/// Keys which can be used to name the hash of an entity.
+pub trait EntityKey: Sized {
+    /// Whether the entities are sharded by [`shard`] in a Redis Cluster.
+    ///
+    /// [`shard`]: Self::shard
+    const SHARDED: bool = false;
+
+    fn entity_key(&self) -> String;
+
+    fn from_entity_key(key: &str) -> Option<Self>;
+
+    /// ID of the shard the entity is stored in, usually the ID of its guild.
+    fn shard(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl EntityKey for u64 {
+    fn entity_key(&self) -> String {
+        self.to_string()
+    }
+
+    fn from_entity_key(key: &str) -> Option<Self> {
+        key.parse().ok()
+    }
+}
+
+/// Pairs of a guild ID and the ID of an entity in the guild, sharded by the
+/// guild.
+impl EntityKey for (u64, u64) {
+    const SHARDED: bool = true;
+
+    fn entity_key(&self) -> String {
+        format!("{}:{}", self.0, self.1)
+    }
+
+    fn from_entity_key(key: &str) -> Option<Self> {
+        let (first, second) = key.split_once(':')?;
+
+        Some((first.parse().ok()?, second.parse().ok()?))
+    }
+
+    fn shard(&self) -> Option<u64> {
+        Some(self.0)
+    }
+}
+
+/// Changes to individual fields of a cached entity.
+///
+/// Field names are the names of the fields of the cached model, e.g. `nick`
+/// for [`CachedMember::nick`].
+///
+/// [`CachedMember::nick`]: crate::model::CachedMember::nick
+#[derive(Debug, Default)]
+pub struct FieldChanges {
+    error: Option<rmp_serde::encode::Error>,
+    fields: Vec<(&'static str, Vec<u8>)>,
+}
+
+impl FieldChanges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set a field to a new value.
+    pub fn set<T: Serialize + ?Sized>(&mut self, field: &'static str, value: &T) -> &mut Self {
+        match rmp_serde::to_vec_named(value) {
+            Ok(pack) => self.fields.push((field, pack)),
+            Err(err) => {
+                self.error.get_or_insert(err);
+            }
+        }
+
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Encoded fields to write, failing if any value could not be encoded.
+    pub(crate) fn fields(&self) -> Result<&[(&'static str, Vec<u8>)], CacheError> {
+        match &self.error {
+            Some(err) => Err(CacheError::EncodeError(clone_encode_error(err))),
+            None => Ok(&self.fields),
+        }
+    }
+
+    /// Apply the changes to a whole value, as needed by the blob layout.
+    pub(crate) fn apply_to<V>(&self, value: &V) -> Result<V, CacheError>
+    where
+        V: DeserializeOwned + Serialize,
+    {
+        let mut fields = encode_fields(value)?;
+
+        for (name, pack) in self.fields()? {
+            match fields.iter_mut().find(|(field, _)| field == name) {
+                Some((_, old)) => old.clone_from(pack),
+                None => fields.push(((*name).to_owned(), pack.clone())),
+            }
+        }
+
+        decode_fields(&fields)
+    }
+}
+
+/// Split a value into its encoded fields.
+pub(crate) fn encode_fields<V: Serialize>(value: &V) -> Result<Vec<(String, Vec<u8>)>, CacheError> {
+    let pack = rmp_serde::to_vec_named(value)?;
+
+    let entries = match rmpv::decode::read_value(&mut &*pack)? {
+        Value::Map(entries) => entries,
+        _ => return Err(CacheError::NotAMap),
+    };
+
+    let mut fields = Vec::with_capacity(entries.len());
+
+    for (name, value) in entries {
+        let name = match name.as_str() {
+            Some(name) => name.to_owned(),
+            None => return Err(CacheError::NotAMap),
+        };
+
+        let mut pack = Vec::new();
+        rmpv::encode::write_value(&mut pack, &value).expect("writing to a vec can't fail");
+
+        fields.push((name, pack));
+    }
+
+    Ok(fields)
+}
+
+/// Join encoded fields back into a value, decompressing compressed ones.
+pub(crate) fn decode_fields<V: DeserializeOwned>(
+    fields: &[(String, Vec<u8>)],
+) -> Result<V, CacheError> {
+    let mut entries = Vec::with_capacity(fields.len());
+
+    for (name, pack) in fields {
+        entries.push((
+            Value::from(name.as_str()),
+            rmpv::decode::read_value(&mut &*compress::decompress(pack)?)?,
+        ));
+    }
+
+    let mut pack = Vec::new();
+    rmpv::encode::write_value(&mut pack, &Value::Map(entries))
+        .expect("writing to a vec can't fail");
+
+    Ok(rmp_serde::from_read(&*pack)?)
+}
+
+fn clone_encode_error(err: &rmp_serde::encode::Error) -> rmp_serde::encode::Error {
+    use serde::ser::Error;
+
+    rmp_serde::encode::Error::custom(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::compress::{Compression, Compressor};
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    struct Entity {
+        id: u64,
+        nick: Option<String>,
+        roles: Vec<u64>,
+    }
+
+    fn entity() -> Entity {
+        Entity {
+            id: 1,
+            nick: Some("twilight".to_owned()),
+            roles: vec![2, 3],
+        }
+    }
+
+    #[test]
+    fn test_fields_round_trip() {
+        let fields = encode_fields(&entity()).unwrap();
+        let names = fields
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+
+        assert_eq!(names, ["id", "nick", "roles"]);
+        assert_eq!(decode_fields::<Entity>(&fields).unwrap(), entity());
+    }
+
+    #[test]
+    fn test_decode_compressed_field() {
+        let mut fields = encode_fields(&Entity {
+            roles: (0..1_000).collect(),
+            ..entity()
+        })
+        .unwrap();
+        let compressor = Compressor::new(Some(Compression::Lz4), 64);
+
+        for (_, pack) in &mut fields {
+            *pack = compressor.compress(std::mem::take(pack));
+        }
+
+        assert_eq!(decode_fields::<Entity>(&fields).unwrap().roles.len(), 1_000);
+    }
+
+    #[test]
+    fn test_encode_not_a_map() {
+        assert!(matches!(encode_fields(&7_u64), Err(CacheError::NotAMap)));
+        assert!(matches!(
+            encode_fields(&(1_u64, 2_u64)),
+            Err(CacheError::NotAMap)
+        ));
+    }
+
+    #[test]
+    fn test_apply_to() {
+        let mut changes = FieldChanges::new();
+        assert!(changes.is_empty());
+
+        changes.set("nick", &None::<String>).set("roles", &[4_u64]);
+        assert!(!changes.is_empty());
+
+        assert_eq!(
+            changes.apply_to(&entity()).unwrap(),
+            Entity {
+                id: 1,
+                nick: None,
+                roles: vec![4],
+            }
+        );
+    }
+
+    #[test]
+    fn test_apply_to_wrong_type() {
+        let mut changes = FieldChanges::new();
+        changes.set("id", "not an id");
+
+        assert!(changes.apply_to(&entity()).is_err());
+    }
+
+    #[test]
+    fn test_entity_key() {
+        assert_eq!(7_u64.entity_key(), "7");
+        assert_eq!(u64::from_entity_key("7"), Some(7));
+        assert_eq!((1_u64, 2_u64).entity_key(), "1:2");
+        assert_eq!(<(u64, u64)>::from_entity_key("1:2"), Some((1, 2)));
+        assert_eq!((1_u64, 2_u64).shard(), Some(1));
+        assert_eq!(7_u64.shard(), None);
+    }
+
+    #[test]
+    fn test_from_malformed_entity_key() {
+        for key in ["", "a", "-1", "1:2", "18446744073709551616"] {
+            assert_eq!(u64::from_entity_key(key), None, "{:?}", key);
+        }
+
+        for key in ["", "1", "1:", ":2", "a:2", "1:b", "1:2:3", "1;2", "-1:2"] {
+            assert_eq!(<(u64, u64)>::from_entity_key(key), None, "{:?}", key);
+        }
+    }
+}
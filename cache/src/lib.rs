@@ -1,21 +1,31 @@
-use std::ops::Deref;
-
-use crate::{config::ResourceType, model::CachedGuild};
-use config::Config;
-use log::error;
-use mobc_redis::{
-    mobc::{Connection, Pool},
-    redis::{self, cmd, AsyncCommands, RedisError},
-    RedisConnectionManager,
+use std::{
+    collections::BTreeMap,
+    ops::Deref,
+    sync::{Arc, Mutex},
 };
-use model::{CachedEmoji, CachedMember, CachedMessage, CachedPresence};
+
+use crate::{
+    compress::Compressor,
+    connection::{RedisManager, WatchedConnection},
+    fetch::InFlight,
+    layout::EntityKey,
+    metrics::Metrics,
+    model::CachedGuild,
+    ready::Readiness,
+    replica::Replicas,
+};
+use futures::{stream, Stream, TryStreamExt};
+use log::{error, warn};
+use mobc::{Connection, Pool};
+use model::{CachedEmoji, CachedMember, CachedMessage, CachedPresence, PresenceCounts};
+use redis::{self, cmd, AsyncCommands, RedisError};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use twilight_model::{
     channel::{message::Sticker, Group, GuildChannel, PrivateChannel, StageInstance},
     gateway::event::Event,
     guild::{GuildIntegration, Role},
     id::GuildId,
-    user::User,
+    user::{CurrentUser, User},
     voice::VoiceState,
 };
 
@@ -49,17 +59,49 @@ use twilight_model::{
 #[derive(Debug, thiserror::Error)]
 pub enum CacheError {
     #[error("Was not able to get redids db pool")]
-    FailedToGetPool(mobc_redis::mobc::Error<RedisError>),
+    FailedToGetPool(mobc::Error<RedisError>),
     #[error("Redis command error")]
     RedisError,
     #[error("Decode error: {0}")]
     DecodeError(#[from] rmp_serde::decode::Error),
     #[error("Encode error: {0}")]
     EncodeError(#[from] rmp_serde::encode::Error),
+    #[error("Fetch error: {0}")]
+    Fetch(fetch::FetchError),
+    #[error("Value decode error: {0}")]
+    ValueDecodeError(#[from] rmpv::decode::Error),
+    #[error("Value can't be stored as a hash, it is not a map")]
+    NotAMap,
+    #[error("Value kept changing while trying to update it")]
+    Conflict,
+    #[error("Failed to encrypt or decrypt a value")]
+    Encryption,
+    #[error("Failed to decompress a value")]
+    Decompression,
+    #[error("Missing from the cache: {0}")]
+    Incomplete(rebuild::Missing),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
 }
 
-impl From<mobc_redis::mobc::Error<RedisError>> for CacheError {
-    fn from(err: mobc_redis::mobc::Error<RedisError>) -> Self {
+/// Number of entries requested per `SSCAN`/`HSCAN` by the streaming
+/// iterators.
+pub const SCAN_COUNT: usize = 500;
+
+/// Field and value pairs returned by `HSCAN`.
+type ScannedFields = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Fields of an entity of the [`KeyLayout::Hash`] layout.
+type EntityFields = Vec<(String, Vec<u8>)>;
+
+/// How often a read-modify-write update is retried when another writer
+/// changed the value in the meantime.
+pub const MAX_ATTEMPTS: usize = 10;
+
+impl From<mobc::Error<RedisError>> for CacheError {
+    fn from(err: mobc::Error<RedisError>) -> Self {
         Self::FailedToGetPool(err)
     }
 }
@@ -70,18 +112,17 @@ impl From<RedisError> for CacheError {
     }
 }
 
-pub struct RedisPool(Pool<RedisConnectionManager>);
+pub struct RedisPool(Pool<RedisManager>);
 
 impl RedisPool {
     pub fn new(connection_str: &str) -> Self {
-        let client = redis::Client::open(connection_str).unwrap();
-        let manager = RedisConnectionManager::new(client);
+        let manager = RedisManager::new(connection_str);
         let pool = Pool::builder().max_open(200).build(manager);
 
         Self(pool)
     }
 
-    async fn get_con(&self) -> Option<Connection<RedisConnectionManager>> {
+    async fn get_con(&self) -> Option<Connection<RedisManager>> {
         match self.0.get().await {
             Ok(con) => Some(con),
             Err(_) => None,
@@ -95,42 +136,97 @@ where
     V: DeserializeOwned + Serialize,
 {
     name: String,
-    pool: Pool<RedisConnectionManager>,
+    layout: KeyLayout,
+    cluster: bool,
+    pub(crate) metrics: Metrics,
+    pool: Pool<RedisManager>,
+    pub(crate) replicas: Option<Replicas>,
+    pub(crate) compressor: Compressor,
+    update_script: redis::Script,
+    swap_script: redis::Script,
     key_type: std::marker::PhantomData<K>,
     value_type: std::marker::PhantomData<V>,
 }
 
 impl<K, V> RedisHashMapCache<K, V>
 where
-    K: DeserializeOwned + Serialize,
+    K: DeserializeOwned + Serialize + EntityKey,
     V: DeserializeOwned + Serialize,
 {
     pub fn new(connection_str: &str, map_name: String) -> RedisHashMapCache<K, V> {
-        // let client = redis::Client::open(connection_str).unwrap();
-        // let manager = RedisConnectionManager::new(client);
-        // let pool = Pool::builder().max_open(50).build(manager);
+        Self::with_layout(connection_str, map_name, KeyLayout::Blob)
+    }
+
+    pub fn with_layout(
+        connection_str: &str,
+        map_name: String,
+        layout: KeyLayout,
+    ) -> RedisHashMapCache<K, V> {
+        Self::with_manager(RedisManager::new(connection_str), map_name, layout)
+    }
 
-        let client = redis::Client::open(connection_str).unwrap();
-        let manager = RedisConnectionManager::new(client);
+    pub(crate) fn with_manager(
+        manager: RedisManager,
+        map_name: String,
+        layout: KeyLayout,
+    ) -> RedisHashMapCache<K, V> {
+        let cluster = manager.is_cluster();
         let pool = Pool::builder().max_open(50).build(manager);
 
         Self {
             name: map_name,
+            layout,
+            cluster,
+            metrics: Metrics::default(),
             pool,
+            replicas: None,
+            compressor: Compressor::default(),
+            // Only touch entities which exist, so a partial update never
+            // leaves a hash behind which is missing fields.
+            update_script: redis::Script::new(
+                r"
+                if redis.call('EXISTS', KEYS[1]) == 1 then
+                    redis.call('HSET', KEYS[1], unpack(ARGV))
+                    return 1
+                end
+                return 0
+                ",
+            ),
+            // Only write the new value if nobody else changed the old one
+            // since we read it.
+            swap_script: redis::Script::new(
+                r"
+                if redis.call('HGET', KEYS[1], ARGV[1]) == ARGV[2] then
+                    redis.call('HSET', KEYS[1], ARGV[1], ARGV[3])
+                    return 1
+                end
+                return 0
+                ",
+            ),
             key_type: std::marker::PhantomData,
             value_type: std::marker::PhantomData,
         }
     }
 
     pub async fn insert(&self, key: K, item: V) -> Option<()> {
+        if let KeyLayout::Hash = self.layout {
+            return self.insert_multiple(vec![(key, item)]).await;
+        }
+
+        let _timer = self.metrics.operation(&self.name, "hset");
+
         if let Some(mut con) = self.get_con().await {
-            let pack = rmp_serde::to_vec(&item).ok();
+            let pack = self.pack(&item).ok();
+            let shard = self.shard_of(&key);
 
             return match pack {
-                Some(pack) => con
-                    .hset(self.name.clone(), self.to_vec(&key).unwrap(), pack)
-                    .await
-                    .ok(),
+                Some(pack) => {
+                    con.hset::<_, _, _, ()>(self.blob_key(shard), self.to_vec(&key).unwrap(), pack)
+                        .await
+                        .ok()?;
+
+                    self.add_shard(&mut con, shard).await
+                }
                 None => None,
             };
         }
@@ -139,29 +235,101 @@ where
     }
 
     pub async fn insert_multiple(&self, items: Vec<(K, V)>) -> Option<()> {
-        if let Some(mut con) = self.get_con().await {
+        let _timer = self.metrics.operation(&self.name, "hset");
+
+        let mut con = self.get_con().await?;
+
+        if let KeyLayout::Hash = self.layout {
+            // In a cluster a transaction may only touch the keys of one slot.
+            for (_, items) in self.group_by_slot(items, Self::entity_tag) {
+                let shard = self.shard_of(&items[0].0);
+                let in_slot = self.ids_in_slot(shard);
+
+                let mut pipe = redis::pipe();
+                pipe.atomic();
+
+                let mut ids = Vec::with_capacity(items.len());
+
+                for (key, item) in items {
+                    let fields = self.pack_fields(&item).ok()?;
+                    let entity_key = self.entity_key(&key);
+
+                    pipe.del(&entity_key)
+                        .ignore()
+                        .hset_multiple(&entity_key, &fields)
+                        .ignore();
+
+                    ids.push(key.entity_key());
+                }
+
+                if in_slot {
+                    pipe.sadd(self.ids_key(shard), ids).ignore();
+                    pipe.query_async::<_, ()>(&mut *con).await.ok()?;
+                } else {
+                    pipe.query_async::<_, ()>(&mut *con).await.ok()?;
+                    con.sadd::<_, _, ()>(self.ids_key(shard), ids).await.ok()?;
+                }
+
+                self.add_shard(&mut con, shard).await?;
+            }
+
+            return Some(());
+        }
+
+        for (shard, items) in self.group_by_slot(items, Self::shard_of) {
             let packs = items
                 .into_iter()
-                .map(|c| (self.to_vec(&c.0).unwrap(), self.to_vec(&c.1).unwrap()))
+                .map(|c| (self.to_vec(&c.0).unwrap(), self.pack(&c.1).unwrap()))
                 .collect::<Vec<(Vec<u8>, Vec<u8>)>>();
 
-            return con.hset_multiple(self.name.clone(), &packs).await.ok();
+            con.hset_multiple::<_, _, _, ()>(self.blob_key(shard), &packs)
+                .await
+                .ok()?;
+
+            self.add_shard(&mut con, shard).await?;
         }
 
-        None
+        Some(())
     }
 
     pub async fn get(&self, key: K) -> Option<V> {
-        if let Some(mut con) = self.get_con().await {
+        if let KeyLayout::Hash = self.layout {
+            let _timer = self.metrics.operation(&self.name, "hgetall");
+
+            let mut con = self.read_con().await?;
+            let fields: Vec<(String, Vec<u8>)> = con.hgetall(self.entity_key(&key)).await.ok()?;
+
+            if fields.is_empty() {
+                return None;
+            }
+
+            return match layout::decode_fields(&fields) {
+                Ok(value) => Some(value),
+                Err(err) => {
+                    self.quarantine_fields(&key, fields, &err).await;
+
+                    None
+                }
+            };
+        }
+
+        let _timer = self.metrics.operation(&self.name, "hget");
+
+        if let Some(mut con) = self.read_con().await {
+            let field = rmp_serde::to_vec(&key).unwrap();
             let value: Option<Vec<u8>> = con
-                .hget(self.name.clone(), rmp_serde::to_vec(&key).unwrap())
+                .hget(self.blob_key(self.shard_of(&key)), field.as_slice())
                 .await
-                .ok();
+                .ok()?;
 
             return match value {
-                Some(val) => match rmp_serde::from_read(&*val).ok() {
-                    Some(val) => Some(val),
-                    None => None,
+                Some(val) => match Self::unpack(&val) {
+                    Ok(val) => Some(val),
+                    Err(err) => {
+                        self.quarantine_blob(&key, field, val, &err).await;
+
+                        None
+                    }
                 },
                 None => None,
             };
@@ -170,36 +338,211 @@ where
         None
     }
 
-    pub async fn size(&self) -> Option<usize> {
-        if let Some(mut con) = self.get_con().await {
-            return con.hlen(self.name.clone()).await.ok();
+    /// Update single fields of a cached value, returning whether the value
+    /// was cached.
+    ///
+    /// With the [`KeyLayout::Hash`] layout only the changed fields are
+    /// written, otherwise the whole value is read, changed and written back.
+    pub async fn update_fields(&self, key: K, changes: &FieldChanges) -> bool {
+        if changes.is_empty() {
+            return self.includes(key).await;
         }
 
-        None
-    }
+        let name = &self.name;
 
-    pub async fn delete(&self, key: K) -> bool {
-        if let Some(mut con) = self.get_con().await {
-            let del = con
-                .hdel(self.name.clone(), rmp_serde::to_vec(&key).unwrap())
+        match self.layout {
+            KeyLayout::Blob => self
+                .modify(key, |value| match changes.apply_to(value) {
+                    Ok(changed) => {
+                        *value = changed;
+
+                        true
+                    }
+                    Err(err) => {
+                        error!("Failed to update {}: {}", name, err);
+
+                        false
+                    }
+                })
                 .await
-                .ok();
+                .unwrap_or(false),
+            KeyLayout::Hash => {
+                let fields = match changes.fields() {
+                    Ok(fields) => fields,
+                    Err(err) => {
+                        error!("Failed to update {}: {}", self.name, err);
 
-            return match del {
-                Some(val) => val,
-                None => false,
+                        return false;
+                    }
+                };
+
+                let _timer = self.metrics.operation(&self.name, "eval");
+
+                let mut con = match self.get_con().await {
+                    Some(con) => con,
+                    None => return false,
+                };
+
+                let mut invocation = self.update_script.prepare_invoke();
+                invocation.key(self.entity_key(&key));
+
+                for (field, pack) in fields {
+                    invocation.arg(*field).arg(self.compress(pack.clone()));
+                }
+
+                invocation
+                    .invoke_async::<_, bool>(&mut *con)
+                    .await
+                    .unwrap_or(false)
+            }
+        }
+    }
+
+    /// Change a cached value in place, returning whether it was cached.
+    ///
+    /// `f` returns whether it changed the value. The change is applied
+    /// optimistically: if another writer changed the value in the meantime,
+    /// `f` is run again on the new value, up to [`MAX_ATTEMPTS`] times.
+    pub async fn modify<F>(&self, key: K, mut f: F) -> Result<bool, CacheError>
+    where
+        F: FnMut(&mut V) -> bool + Send,
+    {
+        let _timer = self.metrics.operation(&self.name, "modify");
+
+        let mut con = WatchedConnection::new(self.get_con().await.ok_or(CacheError::RedisError)?);
+
+        for _ in 0..MAX_ATTEMPTS {
+            let written = match self.layout {
+                KeyLayout::Blob => {
+                    let blob_key = self.blob_key(self.shard_of(&key));
+                    let field = rmp_serde::to_vec(&key)?;
+                    let old: Option<Vec<u8>> = con.hget(&blob_key, field.as_slice()).await?;
+
+                    let old = match old {
+                        Some(old) => old,
+                        None => return Ok(false),
+                    };
+
+                    let mut value: V = Self::unpack(&old)?;
+
+                    if !f(&mut value) {
+                        return Ok(true);
+                    }
+
+                    self.swap_script
+                        .key(&blob_key)
+                        .arg(field)
+                        .arg(old)
+                        .arg(self.pack(&value)?)
+                        .invoke_async::<_, bool>(&mut **con)
+                        .await?
+                }
+                KeyLayout::Hash => {
+                    let entity_key = self.entity_key(&key);
+
+                    con.watch(&entity_key).await?;
+
+                    // `None` if the entity isn't cached, `Some(None)` if `f`
+                    // left it unchanged.
+                    let changed: Result<Option<Option<EntityFields>>, CacheError> = async {
+                        let fields: Vec<(String, Vec<u8>)> = con.hgetall(&entity_key).await?;
+
+                        if fields.is_empty() {
+                            return Ok(None);
+                        }
+
+                        let mut value: V = layout::decode_fields(&fields)?;
+
+                        if !f(&mut value) {
+                            return Ok(Some(None));
+                        }
+
+                        Ok(Some(Some(self.pack_fields(&value)?)))
+                    }
+                    .await;
+
+                    let fields = match changed {
+                        Ok(Some(Some(fields))) => fields,
+                        // The key is still con, which would abort the
+                        // next transaction on this pooled connection.
+                        done => {
+                            con.unwatch().await?;
+
+                            return done.map(|cached| cached.is_some());
+                        }
+                    };
+
+                    // The transaction is aborted if the entity was written to
+                    // since we started watching it.
+                    let exec: Option<()> = redis::pipe()
+                        .atomic()
+                        .del(&entity_key)
+                        .ignore()
+                        .hset_multiple(&entity_key, &fields)
+                        .ignore()
+                        .query_async(&mut **con)
+                        .await?;
+                    con.executed();
+
+                    exec.is_some()
+                }
             };
+
+            if written {
+                return Ok(true);
+            }
         }
 
-        return false;
+        Err(CacheError::Conflict)
+    }
+
+    pub async fn size(&self) -> Option<usize> {
+        let shards = self.shards().try_collect::<Vec<_>>().await.ok()?;
+
+        let _timer = self
+            .metrics
+            .operation(&self.name, self.layout_op("hlen", "scard"));
+
+        let mut con = self.read_con().await?;
+        let mut size = 0;
+
+        for shard in shards {
+            size += match self.layout {
+                KeyLayout::Blob => con.hlen::<_, usize>(self.blob_key(shard)).await.ok()?,
+                KeyLayout::Hash => con.scard::<_, usize>(self.ids_key(shard)).await.ok()?,
+            };
+        }
+
+        Some(size)
+    }
+
+    pub async fn delete(&self, key: K) -> bool {
+        let _timer = self
+            .metrics
+            .operation(&self.name, self.layout_op("hdel", "del"));
+
+        match self.get_con().await {
+            Some(mut con) => self.remove(&mut con, &key).await.unwrap_or(false),
+            None => false,
+        }
     }
 
     pub async fn includes(&self, key: K) -> bool {
-        if let Some(mut con) = self.get_con().await {
-            let has = con
-                .hexists(self.name.clone(), rmp_serde::to_vec(&key).unwrap())
-                .await
-                .ok();
+        let _timer = self
+            .metrics
+            .operation(&self.name, self.layout_op("hexists", "exists"));
+
+        if let Some(mut con) = self.read_con().await {
+            let has = match self.layout {
+                KeyLayout::Blob => con
+                    .hexists(
+                        self.blob_key(self.shard_of(&key)),
+                        rmp_serde::to_vec(&key).unwrap(),
+                    )
+                    .await
+                    .ok(),
+                KeyLayout::Hash => con.exists(self.entity_key(&key)).await.ok(),
+            };
 
             return match has {
                 Some(val) => val,
@@ -210,7 +553,580 @@ where
         false
     }
 
-    async fn get_con(&self) -> Option<Connection<RedisConnectionManager>> {
+    /// Stream the shards entries are stored in.
+    ///
+    /// Entries are only sharded in a Redis Cluster, by [`EntityKey::shard`].
+    /// Otherwise, and for stores which aren't sharded, this is a single
+    /// `None`.
+    pub fn shards(&self) -> impl Stream<Item = Result<Option<u64>, CacheError>> + '_ {
+        let sharded = self.cluster && K::SHARDED;
+
+        stream::try_unfold(Some(0), move |cursor| async move {
+            let cursor: u64 = match cursor {
+                Some(cursor) => cursor,
+                None => return Ok(None),
+            };
+
+            if !sharded {
+                return Ok(Some((stream::iter(vec![Ok(None)]), None)));
+            }
+
+            let _timer = self.metrics.operation(&self.name, "sscan");
+            let mut con = self.read_con().await.ok_or(CacheError::RedisError)?;
+
+            let (next, shards): (u64, Vec<u64>) = cmd("SSCAN")
+                .arg(self.shards_key())
+                .arg(cursor)
+                .arg("COUNT")
+                .arg(SCAN_COUNT)
+                .query_async(&mut *con)
+                .await?;
+
+            let batch = stream::iter(
+                shards
+                    .into_iter()
+                    .map(|shard| Ok(Some(shard)))
+                    .collect::<Vec<_>>(),
+            );
+
+            Ok::<_, CacheError>(Some((batch, (next != 0).then_some(next))))
+        })
+        .try_flatten()
+    }
+
+    /// Get a batch of keys of a shard, starting at `cursor`.
+    ///
+    /// Returns the cursor to continue at, which is 0 once all keys have been
+    /// returned. Keys which are added or removed during the iteration may or
+    /// may not be returned. See [`shards`] for the shards there are.
+    ///
+    /// [`shards`]: Self::shards
+    pub async fn scan_keys(
+        &self,
+        shard: Option<u64>,
+        cursor: u64,
+        count: usize,
+    ) -> Result<(u64, Vec<K>), CacheError> {
+        let _timer = self
+            .metrics
+            .operation(&self.name, self.layout_op("hscan", "sscan"));
+
+        let mut con = self.read_con().await.ok_or(CacheError::RedisError)?;
+
+        match self.layout {
+            KeyLayout::Blob => {
+                let (next, entries): (u64, ScannedFields) = cmd("HSCAN")
+                    .arg(self.blob_key(shard))
+                    .arg(cursor)
+                    .arg("COUNT")
+                    .arg(count)
+                    .query_async(&mut *con)
+                    .await?;
+
+                let keys = entries
+                    .into_iter()
+                    .map(|(key, _)| rmp_serde::from_read(&*key))
+                    .collect::<Result<Vec<K>, _>>()?;
+
+                Ok((next, keys))
+            }
+            KeyLayout::Hash => {
+                let (next, ids): (u64, Vec<String>) = cmd("SSCAN")
+                    .arg(self.ids_key(shard))
+                    .arg(cursor)
+                    .arg("COUNT")
+                    .arg(count)
+                    .query_async(&mut *con)
+                    .await?;
+
+                let keys = ids.iter().filter_map(|id| K::from_entity_key(id)).collect();
+
+                Ok((next, keys))
+            }
+        }
+    }
+
+    /// Get a batch of entries of a shard, starting at `cursor`.
+    ///
+    /// Works like [`scan_keys`], also returning the values.
+    ///
+    /// [`scan_keys`]: Self::scan_keys
+    pub async fn scan(
+        &self,
+        shard: Option<u64>,
+        cursor: u64,
+        count: usize,
+    ) -> Result<(u64, Vec<(K, V)>), CacheError> {
+        if let KeyLayout::Hash = self.layout {
+            let (next, keys) = self.scan_keys(shard, cursor, count).await?;
+
+            let _timer = self.metrics.operation(&self.name, "hgetall");
+            let mut con = self.read_con().await.ok_or(CacheError::RedisError)?;
+
+            // Entities of different shards, or any entities in a cluster, are
+            // in different slots and can't share a pipeline there.
+            let mut values: Vec<Vec<(String, Vec<u8>)>> = Vec::with_capacity(keys.len());
+
+            if self.cluster {
+                for key in &keys {
+                    values.push(con.hgetall(self.entity_key(key)).await?);
+                }
+            } else {
+                let mut pipe = redis::pipe();
+
+                for key in &keys {
+                    pipe.hgetall(self.entity_key(key));
+                }
+
+                values = pipe.query_async(&mut *con).await?;
+            }
+
+            let mut entries = Vec::with_capacity(keys.len());
+
+            for (key, fields) in keys.into_iter().zip(values) {
+                // Removed since its ID was scanned.
+                if fields.is_empty() {
+                    continue;
+                }
+
+                match layout::decode_fields(&fields) {
+                    Ok(value) => entries.push((key, value)),
+                    Err(err) => self.quarantine_fields(&key, fields, &err).await,
+                }
+            }
+
+            return Ok((next, entries));
+        }
+
+        let _timer = self.metrics.operation(&self.name, "hscan");
+        let mut con = self.read_con().await.ok_or(CacheError::RedisError)?;
+
+        let (next, packs): (u64, ScannedFields) = cmd("HSCAN")
+            .arg(self.blob_key(shard))
+            .arg(cursor)
+            .arg("COUNT")
+            .arg(count)
+            .query_async(&mut *con)
+            .await?;
+
+        let mut entries = Vec::with_capacity(packs.len());
+
+        for (field, value) in packs {
+            let key = match rmp_serde::from_read::<_, K>(&*field) {
+                Ok(key) => key,
+                Err(err) => {
+                    let name = format!("{:?}", field);
+                    let entry = QuarantinedEntry::new(
+                        &self.name,
+                        name,
+                        std::any::type_name::<V>(),
+                        &err,
+                        value,
+                    );
+
+                    let mut pipe = redis::pipe();
+                    pipe.hdel(self.blob_key(shard), field).ignore();
+
+                    if let Some(mut con) = self.get_con().await {
+                        quarantine::quarantine(&mut con, &self.metrics, entry, pipe, !self.cluster)
+                            .await;
+                    }
+
+                    continue;
+                }
+            };
+
+            match Self::unpack(&value) {
+                Ok(value) => entries.push((key, value)),
+                Err(err) => self.quarantine_blob(&key, field, value, &err).await,
+            }
+        }
+
+        Ok((next, entries))
+    }
+
+    /// Stream all entries, [`SCAN_COUNT`] at a time.
+    ///
+    /// Only one batch is held in memory at once. Entries which are added or
+    /// removed during the iteration may or may not be returned.
+    pub fn iter(&self) -> impl Stream<Item = Result<(K, V), CacheError>> + '_ {
+        self.shards()
+            .map_ok(move |shard| {
+                stream::try_unfold(Some(0), move |cursor| async move {
+                    let cursor = match cursor {
+                        Some(cursor) => cursor,
+                        None => return Ok(None),
+                    };
+
+                    let (next, entries) = self.scan(shard, cursor, SCAN_COUNT).await?;
+                    let batch = stream::iter(entries.into_iter().map(Ok));
+
+                    Ok::<_, CacheError>(Some((batch, (next != 0).then_some(next))))
+                })
+                .try_flatten()
+            })
+            .try_flatten()
+    }
+
+    /// Stream all keys, [`SCAN_COUNT`] at a time.
+    ///
+    /// See [`iter`] for the guarantees.
+    ///
+    /// [`iter`]: Self::iter
+    pub fn keys(&self) -> impl Stream<Item = Result<K, CacheError>> + '_ {
+        self.shards()
+            .map_ok(move |shard| {
+                stream::try_unfold(Some(0), move |cursor| async move {
+                    let cursor = match cursor {
+                        Some(cursor) => cursor,
+                        None => return Ok(None),
+                    };
+
+                    let (next, keys) = self.scan_keys(shard, cursor, SCAN_COUNT).await?;
+                    let batch = stream::iter(keys.into_iter().map(Ok));
+
+                    Ok::<_, CacheError>(Some((batch, (next != 0).then_some(next))))
+                })
+                .try_flatten()
+            })
+            .try_flatten()
+    }
+
+    /// Move all entries stored with the [`KeyLayout::Blob`] layout over to
+    /// the [`KeyLayout::Hash`] layout, returning the number of moved entries.
+    ///
+    /// Does nothing if this cache does not use the hash layout.
+    pub async fn migrate_from_blob(&self) -> Result<usize, CacheError> {
+        if let KeyLayout::Blob = self.layout {
+            return Ok(0);
+        }
+
+        let shards = self.shards().try_collect::<Vec<_>>().await?;
+
+        let mut con = self.get_con().await.ok_or(CacheError::RedisError)?;
+        let mut migrated = 0;
+
+        for shard in shards {
+            let blob_key = self.blob_key(shard);
+            let mut cursor: u64 = 0;
+
+            loop {
+                let (next, entries): (u64, ScannedFields) = cmd("HSCAN")
+                    .arg(&blob_key)
+                    .arg(cursor)
+                    .arg("COUNT")
+                    .arg(500)
+                    .query_async(&mut *con)
+                    .await?;
+
+                let mut items = Vec::with_capacity(entries.len());
+
+                for (key, value) in entries {
+                    match (
+                        rmp_serde::from_read::<_, K>(&*key).map_err(CacheError::from),
+                        Self::unpack::<V>(&value),
+                    ) {
+                        (Ok(key), Ok(value)) => items.push((key, value)),
+                        (Err(err), _) | (_, Err(err)) => {
+                            error!("Skipping undecodable entry of {}: {}", self.name, err);
+                        }
+                    }
+                }
+
+                migrated += items.len();
+                self.insert_multiple(items)
+                    .await
+                    .ok_or(CacheError::RedisError)?;
+
+                cursor = next;
+
+                if cursor == 0 {
+                    break;
+                }
+            }
+
+            con.del::<_, ()>(&blob_key).await?;
+        }
+
+        Ok(migrated)
+    }
+
+    /// Move the entries stored under keys of an older key type `O` over to
+    /// the keys returned by `rekey`, returning the old and new keys of the
+    /// moved entries.
+    ///
+    /// Entries which already use keys of this cache are left alone. Does
+    /// nothing in a cluster, which never stored the older keys.
+    pub(crate) async fn migrate_keys<O>(
+        &self,
+        rekey: impl Fn(&O, &V) -> K,
+    ) -> Result<Vec<(O, K)>, CacheError>
+    where
+        K: Clone,
+        O: DeserializeOwned + EntityKey,
+    {
+        if self.cluster {
+            return Ok(Vec::new());
+        }
+
+        let mut con = self.get_con().await.ok_or(CacheError::RedisError)?;
+        let mut moved = Vec::new();
+        let mut cursor: u64 = 0;
+
+        loop {
+            let mut items = Vec::new();
+            let mut pipe = redis::pipe();
+
+            match self.layout {
+                KeyLayout::Blob => {
+                    let (next, entries): (u64, ScannedFields) = cmd("HSCAN")
+                        .arg(self.blob_key(None))
+                        .arg(cursor)
+                        .arg("COUNT")
+                        .arg(SCAN_COUNT)
+                        .query_async(&mut *con)
+                        .await?;
+
+                    for (field, value) in entries {
+                        if rmp_serde::from_read::<_, K>(&*field).is_ok() {
+                            continue;
+                        }
+
+                        let old = match rmp_serde::from_read::<_, O>(&*field) {
+                            Ok(old) => old,
+                            Err(_) => continue,
+                        };
+
+                        match Self::unpack::<V>(&value) {
+                            Ok(value) => {
+                                let key = rekey(&old, &value);
+
+                                items.push((key.clone(), value));
+                                moved.push((old, key));
+                                pipe.hdel(self.blob_key(None), field).ignore();
+                            }
+                            Err(err) => {
+                                error!("Skipping undecodable entry of {}: {}", self.name, err);
+                            }
+                        }
+                    }
+
+                    cursor = next;
+                }
+                KeyLayout::Hash => {
+                    let (next, ids): (u64, Vec<String>) = cmd("SSCAN")
+                        .arg(self.ids_key(None))
+                        .arg(cursor)
+                        .arg("COUNT")
+                        .arg(SCAN_COUNT)
+                        .query_async(&mut *con)
+                        .await?;
+
+                    for id in ids {
+                        if K::from_entity_key(&id).is_some() {
+                            continue;
+                        }
+
+                        let old = match O::from_entity_key(&id) {
+                            Some(old) => old,
+                            None => continue,
+                        };
+
+                        let entity_key = format!("{}-{}", self.name, id);
+                        let fields: Vec<(String, Vec<u8>)> = con.hgetall(&entity_key).await?;
+
+                        if !fields.is_empty() {
+                            match layout::decode_fields::<V>(&fields) {
+                                Ok(value) => {
+                                    let key = rekey(&old, &value);
+
+                                    items.push((key.clone(), value));
+                                    moved.push((old, key));
+                                }
+                                Err(err) => {
+                                    error!("Skipping undecodable entry of {}: {}", self.name, err);
+
+                                    continue;
+                                }
+                            }
+                        }
+
+                        pipe.del(entity_key)
+                            .ignore()
+                            .srem(self.ids_key(None), id)
+                            .ignore();
+                    }
+
+                    cursor = next;
+                }
+            }
+
+            // Written before the old entries are removed, so an interrupted
+            // migration leaves duplicates rather than losing entries.
+            if !items.is_empty() {
+                self.insert_multiple(items)
+                    .await
+                    .ok_or(CacheError::RedisError)?;
+            }
+
+            pipe.query_async::<_, ()>(&mut *con).await?;
+
+            if cursor == 0 {
+                break;
+            }
+        }
+
+        Ok(moved)
+    }
+
+    /// Queue deleting a value as part of a transaction.
+    ///
+    /// Outside of a cluster only, where the keys of the value may be in
+    /// different slots than the other keys of the transaction.
+    pub(crate) fn queue_delete(&self, pipe: &mut redis::Pipeline, key: &K) {
+        match self.layout {
+            KeyLayout::Blob => {
+                pipe.hdel(self.blob_key(None), rmp_serde::to_vec(key).unwrap())
+                    .ignore();
+            }
+            KeyLayout::Hash => {
+                pipe.del(self.entity_key(key))
+                    .ignore()
+                    .srem(self.ids_key(None), key.entity_key())
+                    .ignore();
+            }
+        }
+    }
+
+    /// Queue writing a value as part of a transaction.
+    ///
+    /// Outside of a cluster only, like [`queue_delete`].
+    ///
+    /// [`queue_delete`]: Self::queue_delete
+    pub(crate) fn queue_insert(
+        &self,
+        pipe: &mut redis::Pipeline,
+        key: &K,
+        value: &V,
+    ) -> Result<(), CacheError> {
+        match self.layout {
+            KeyLayout::Blob => {
+                pipe.hset(
+                    self.blob_key(None),
+                    rmp_serde::to_vec(key)?,
+                    self.pack(value)?,
+                )
+                .ignore();
+            }
+            KeyLayout::Hash => {
+                let entity_key = self.entity_key(key);
+
+                pipe.del(&entity_key)
+                    .ignore()
+                    .hset_multiple(&entity_key, &self.pack_fields(value)?)
+                    .ignore()
+                    .sadd(self.ids_key(None), key.entity_key())
+                    .ignore();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Key a transaction has to watch to notice a value changing.
+    pub(crate) fn watch_key(&self, key: &K) -> String {
+        match self.layout {
+            KeyLayout::Blob => self.blob_key(self.shard_of(key)),
+            KeyLayout::Hash => self.entity_key(key),
+        }
+    }
+
+    /// Read values with one pipelined request on `con`, which may be
+    /// watching them, `None` for the ones which are not cached.
+    ///
+    /// In a cluster the values have to be in the same slot.
+    pub(crate) async fn get_multiple(
+        &self,
+        con: &mut Connection<RedisManager>,
+        keys: &[K],
+    ) -> Result<Vec<Option<V>>, CacheError> {
+        let _timer = self
+            .metrics
+            .operation(&self.name, self.layout_op("hget", "hgetall"));
+
+        let mut pipe = redis::pipe();
+
+        match self.layout {
+            KeyLayout::Blob => {
+                for key in keys {
+                    pipe.hget(self.blob_key(self.shard_of(key)), rmp_serde::to_vec(key)?);
+                }
+
+                let packs: Vec<Option<Vec<u8>>> = pipe.query_async(&mut **con).await?;
+
+                packs
+                    .iter()
+                    .map(|pack| pack.as_deref().map(Self::unpack).transpose())
+                    .collect()
+            }
+            KeyLayout::Hash => {
+                for key in keys {
+                    pipe.hgetall(self.entity_key(key));
+                }
+
+                let values: Vec<Vec<(String, Vec<u8>)>> = pipe.query_async(&mut **con).await?;
+
+                values
+                    .iter()
+                    .map(|fields| {
+                        if fields.is_empty() {
+                            Ok(None)
+                        } else {
+                            layout::decode_fields(fields).map(Some)
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Delete a value, returning whether it was cached.
+    async fn remove(
+        &self,
+        con: &mut Connection<RedisManager>,
+        key: &K,
+    ) -> Result<bool, CacheError> {
+        let shard = self.shard_of(key);
+
+        match self.layout {
+            KeyLayout::Blob => Ok(con
+                .hdel(self.blob_key(shard), rmp_serde::to_vec(key)?)
+                .await?),
+            KeyLayout::Hash => {
+                let in_slot = self.ids_in_slot(shard);
+
+                let mut pipe = redis::pipe();
+                pipe.atomic().del(self.entity_key(key));
+
+                if in_slot {
+                    pipe.srem(self.ids_key(shard), key.entity_key()).ignore();
+                }
+
+                let (del,): (bool,) = pipe.query_async(&mut **con).await?;
+
+                if !in_slot {
+                    con.srem::<_, _, ()>(self.ids_key(shard), key.entity_key())
+                        .await?;
+                }
+
+                Ok(del)
+            }
+        }
+    }
+
+    async fn get_con(&self) -> Option<Connection<RedisManager>> {
+        let _timer = self.metrics.pool_wait(&self.name);
+
         // self.pool.get_con().await
         match self.pool.get().await {
             Ok(con) => Some(con),
@@ -218,9 +1134,230 @@ where
         }
     }
 
+    /// Connection for a read, to a replica if there is one fresh enough.
+    async fn read_con(&self) -> Option<Connection<RedisManager>> {
+        if let Some(replicas) = &self.replicas {
+            let con = {
+                let _timer = self.metrics.pool_wait(&self.name);
+
+                replicas.get_con().await
+            };
+
+            if let Some(con) = con {
+                return Some(con);
+            }
+        }
+
+        self.get_con().await
+    }
+
+    /// Name of the operation depending on the layout.
+    const fn layout_op(&self, blob: &'static str, hash: &'static str) -> &'static str {
+        match self.layout {
+            KeyLayout::Blob => blob,
+            KeyLayout::Hash => hash,
+        }
+    }
+
+    /// Entries which failed to decode, newest first.
+    ///
+    /// See [`QuarantinedEntry`].
+    pub async fn quarantined(&self) -> Result<Vec<QuarantinedEntry>, CacheError> {
+        let mut con = self.get_con().await.ok_or(CacheError::RedisError)?;
+
+        quarantine::quarantined(&mut con, &self.name).await
+    }
+
+    /// Quarantine a value of the blob layout, stored in `field`.
+    ///
+    /// The value may have been read from a replica, it is removed on the
+    /// primary.
+    async fn quarantine_blob(
+        &self,
+        key: &K,
+        field: Vec<u8>,
+        value: Vec<u8>,
+        err: &(dyn std::fmt::Display + Sync),
+    ) {
+        let entry = QuarantinedEntry::new(
+            &self.name,
+            key.entity_key(),
+            std::any::type_name::<V>(),
+            err,
+            value,
+        );
+
+        let mut pipe = redis::pipe();
+        pipe.hdel(self.blob_key(self.shard_of(key)), field).ignore();
+
+        if let Some(mut con) = self.get_con().await {
+            quarantine::quarantine(&mut con, &self.metrics, entry, pipe, !self.cluster).await;
+        }
+    }
+
+    /// Quarantine an entity of the hash layout, on the primary like
+    /// [`quarantine_blob`].
+    ///
+    /// [`quarantine_blob`]: Self::quarantine_blob
+    async fn quarantine_fields(
+        &self,
+        key: &K,
+        fields: Vec<(String, Vec<u8>)>,
+        err: &(dyn std::fmt::Display + Sync),
+    ) {
+        let value = rmp_serde::to_vec(&fields).unwrap_or_default();
+        let entry = QuarantinedEntry::new(
+            &self.name,
+            key.entity_key(),
+            std::any::type_name::<V>(),
+            err,
+            value,
+        );
+
+        let mut con = match self.get_con().await {
+            Some(con) => con,
+            None => return,
+        };
+        let mut pipe = redis::pipe();
+
+        // The keys of the entity may be in different slots in a cluster.
+        if self.cluster {
+            if let Err(err) = self.remove(&mut con, key).await {
+                error!("Failed to remove {} from {}: {}", entry.key, self.name, err);
+            }
+        } else {
+            self.queue_delete(&mut pipe, key);
+        }
+
+        quarantine::quarantine(&mut con, &self.metrics, entry, pipe, !self.cluster).await;
+    }
+
     fn to_vec<T: Serialize + ?Sized>(&self, val: &T) -> Option<Vec<u8>> {
         rmp_serde::to_vec(val).ok()
     }
+
+    /// Encode a value of the blob layout, compressed if it is large.
+    fn pack(&self, value: &V) -> Result<Vec<u8>, CacheError> {
+        Ok(self.compress(rmp_serde::to_vec(value)?))
+    }
+
+    /// Decode a value of the blob layout.
+    fn unpack<T: DeserializeOwned>(stored: &[u8]) -> Result<T, CacheError> {
+        Ok(rmp_serde::from_read(&*compress::decompress(stored)?)?)
+    }
+
+    /// Encode the fields of a value of the hash layout, compressing the large
+    /// ones.
+    ///
+    /// They are decompressed by [`layout::decode_fields`].
+    fn pack_fields(&self, value: &V) -> Result<Vec<(String, Vec<u8>)>, CacheError> {
+        let mut fields = layout::encode_fields(value)?;
+
+        for (_, pack) in &mut fields {
+            *pack = self.compress(std::mem::take(pack));
+        }
+
+        Ok(fields)
+    }
+
+    fn compress(&self, pack: Vec<u8>) -> Vec<u8> {
+        if !self.compressor.is_enabled() {
+            return pack;
+        }
+
+        let original = pack.len();
+        let stored = self.compressor.compress(pack);
+
+        self.metrics.compression(&self.name, original, stored.len());
+
+        stored
+    }
+
+    /// Shard of an entry, only sharded in a cluster.
+    fn shard_of(&self, key: &K) -> Option<u64> {
+        if self.cluster {
+            key.shard()
+        } else {
+            None
+        }
+    }
+
+    /// Hash tag the hash of an entity is in, only used in a cluster.
+    ///
+    /// Entities of a shard share the tag of the shard, others have their own.
+    fn entity_tag(&self, key: &K) -> Option<String> {
+        if !self.cluster {
+            return None;
+        }
+
+        Some(match key.shard() {
+            Some(shard) => connection::hash_tag(shard),
+            None => connection::hash_tag(key.entity_key()),
+        })
+    }
+
+    /// Group entries by the slot they are stored in.
+    fn group_by_slot<T: Ord>(
+        &self,
+        items: Vec<(K, V)>,
+        slot: impl Fn(&Self, &K) -> T,
+    ) -> BTreeMap<T, Vec<(K, V)>> {
+        let mut groups = BTreeMap::<_, Vec<_>>::new();
+
+        for item in items {
+            groups.entry(slot(self, &item.0)).or_default().push(item);
+        }
+
+        groups
+    }
+
+    /// Whether the IDs of the hash layout are in the slot of their entities.
+    ///
+    /// Outside of a shard every entity has its own slot in a cluster.
+    const fn ids_in_slot(&self, shard: Option<u64>) -> bool {
+        !self.cluster || shard.is_some()
+    }
+
+    /// Remember a shard, so [`shards`] returns it.
+    ///
+    /// [`shards`]: Self::shards
+    async fn add_shard(
+        &self,
+        con: &mut Connection<RedisManager>,
+        shard: Option<u64>,
+    ) -> Option<()> {
+        match shard {
+            Some(shard) => con.sadd::<_, _, ()>(self.shards_key(), shard).await.ok(),
+            None => Some(()),
+        }
+    }
+
+    /// The hash of the blob layout.
+    fn blob_key(&self, shard: Option<u64>) -> String {
+        match shard {
+            Some(shard) => format!("{}-{}", self.name, connection::hash_tag(shard)),
+            None => self.name.clone(),
+        }
+    }
+
+    fn entity_key(&self, key: &K) -> String {
+        match (self.entity_tag(key), key.shard()) {
+            (Some(tag), Some(_)) => format!("{}-{}-{}", self.name, tag, key.entity_key()),
+            (Some(tag), None) => format!("{}-{}", self.name, tag),
+            (None, _) => format!("{}-{}", self.name, key.entity_key()),
+        }
+    }
+
+    fn ids_key(&self, shard: Option<u64>) -> String {
+        match shard {
+            Some(shard) => format!("{}-ids-{}", self.name, connection::hash_tag(shard)),
+            None => format!("{}-ids", self.name),
+        }
+    }
+
+    fn shards_key(&self) -> String {
+        format!("{}-shards", self.name)
+    }
 }
 
 pub struct RedisSetCache<K, V>
@@ -229,7 +1366,11 @@ where
     V: DeserializeOwned + Serialize,
 {
     prefix: String,
-    pool: Pool<RedisConnectionManager>,
+    cluster: bool,
+    pub(crate) metrics: Metrics,
+    pool: Pool<RedisManager>,
+    pub(crate) replicas: Option<Replicas>,
+    pub(crate) compressor: Compressor,
     key_type: std::marker::PhantomData<K>,
     value_type: std::marker::PhantomData<V>,
 }
@@ -240,61 +1381,112 @@ where
     V: DeserializeOwned + Serialize,
 {
     pub fn new(connection_str: &str, prefix: String) -> RedisSetCache<K, V> {
-        let client = redis::Client::open(connection_str).unwrap();
-        let manager = RedisConnectionManager::new(client);
+        Self::with_manager(RedisManager::new(connection_str), prefix)
+    }
+
+    pub(crate) fn with_manager(manager: RedisManager, prefix: String) -> RedisSetCache<K, V> {
+        let cluster = manager.is_cluster();
         let pool = Pool::builder().max_open(50).build(manager);
 
         Self {
             prefix,
+            cluster,
+            metrics: Metrics::default(),
             pool,
+            replicas: None,
+            compressor: Compressor::default(),
             key_type: std::marker::PhantomData,
             value_type: std::marker::PhantomData,
         }
     }
 
     pub async fn insert(&self, key: K, item: &V) -> Result<(), CacheError> {
+        let _timer = self.metrics.operation(&self.prefix, "sadd");
+
         let mut con = self.get_con().await?;
 
-        let pack = rmp_serde::to_vec(&item)?;
+        let pack = self.pack(item)?;
 
-        con.sadd(self.get_key(key), pack).await?;
+        con.sadd::<_, _, ()>(self.get_key(key), pack).await?;
 
         Ok(())
     }
 
     pub async fn insert_multiple(&self, key: K, items: Vec<V>) -> Result<(), CacheError> {
+        let _timer = self.metrics.operation(&self.prefix, "sadd");
+
         let con = self.get_con().await?;
 
         let packs = items
             .into_iter()
-            .map(|c| rmp_serde::to_vec(&c).unwrap())
+            .map(|c| self.pack(&c).unwrap())
             .collect::<Vec<Vec<u8>>>();
 
         // con.set_multiple(self.get_key(key), &packs).await?;
         cmd("SADD")
             .arg(self.get_key(key))
             .arg(packs)
-            .query_async(&mut con.into_inner())
+            .query_async::<_, ()>(&mut con.into_inner())
             .await?;
 
         Ok(())
     }
 
     pub async fn get(&self, key: K) -> Result<Vec<V>, CacheError> {
-        let mut con = self.get_con().await?;
+        let _timer = self.metrics.operation(&self.prefix, "smembers");
 
-        let value: Vec<Vec<u8>> = con.smembers(self.get_key(key)).await?;
+        let mut con = self.read_con().await?;
+        let key = self.get_key(key);
 
-        let dec = value
-            .into_iter()
-            .map(|v| rmp_serde::from_read(&*v).unwrap())
-            .collect::<Vec<V>>();
+        let value: Vec<Vec<u8>> = con.smembers(&key).await?;
+
+        Ok(self.decode_members(&key, value).await)
+    }
 
-        Ok(dec)
+    /// Stream the members of a set, [`SCAN_COUNT`] at a time.
+    ///
+    /// Unlike [`get`], only one batch is held in memory at once and Redis is
+    /// not blocked by huge sets. Members which are added or removed during the
+    /// iteration may or may not be returned, and a member may be returned
+    /// more than once.
+    ///
+    /// [`get`]: Self::get
+    pub fn iter(&self, key: K) -> impl Stream<Item = Result<V, CacheError>> + '_ {
+        let key = self.get_key(key);
+
+        stream::try_unfold(Some(0), move |cursor| {
+            let key = key.clone();
+
+            async move {
+                let cursor: u64 = match cursor {
+                    Some(cursor) => cursor,
+                    None => return Ok(None),
+                };
+
+                let _timer = self.metrics.operation(&self.prefix, "sscan");
+                let mut con = self.read_con().await?;
+
+                let (next, packs): (u64, Vec<Vec<u8>>) = cmd("SSCAN")
+                    .arg(&key)
+                    .arg(cursor)
+                    .arg("COUNT")
+                    .arg(SCAN_COUNT)
+                    .query_async(&mut *con)
+                    .await?;
+
+                let members = self.decode_members(&key, packs).await;
+                let batch = stream::iter(members.into_iter().map(Ok));
+
+                Ok::<_, CacheError>(Some((batch, (next != 0).then_some(next))))
+            }
+        })
+        .try_flatten()
     }
 
     pub async fn size(&self, key: K) -> Result<usize, CacheError> {
-        let mut con = self.get_con().await?;
+        let _timer = self.metrics.operation(&self.prefix, "scard");
+
+        let mut con = self.read_con().await?;
 
         let length: usize = con.scard(self.get_key(key)).await?;
 
@@ -302,14 +1494,46 @@ where
     }
 
     pub async fn remove(&self, key: K, item: V) -> Result<bool, CacheError> {
+        let _timer = self.metrics.operation(&self.prefix, "srem");
+
         let mut con = self.get_con().await?;
 
-        let pack = rmp_serde::to_vec(&item).unwrap();
-        let del = con.srem(self.get_key(key), pack).await?;
+        let packs = self.member_packs(&item)?;
+        let del = con.srem(self.get_key(key), packs).await?;
         Ok(del)
     }
 
+    /// Replace a member stored as a value of an older type `T` with `item`,
+    /// in one transaction.
+    pub(crate) async fn replace<T: Serialize>(
+        &self,
+        key: K,
+        old: &T,
+        item: &V,
+    ) -> Result<(), CacheError> {
+        let _timer = self.metrics.operation(&self.prefix, "srem");
+
+        let mut con = self.get_con().await?;
+
+        let old = rmp_serde::to_vec(old)?;
+        let old = vec![self.compressor.compress(old.clone()), old];
+        let key = self.get_key(key);
+
+        redis::pipe()
+            .atomic()
+            .srem(&key, old)
+            .ignore()
+            .sadd(&key, self.pack(item)?)
+            .ignore()
+            .query_async::<_, ()>(&mut *con)
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn delete(&self, key: K) -> Result<bool, CacheError> {
+        let _timer = self.metrics.operation(&self.prefix, "del");
+
         let mut con = self.get_con().await?;
 
         let del = con.del(self.get_key(key)).await?;
@@ -318,17 +1542,25 @@ where
     }
 
     pub async fn includes(&self, key: K, item: V) -> Result<bool, CacheError> {
-        let mut con = self.get_con().await?;
+        let _timer = self.metrics.operation(&self.prefix, "sismember");
 
-        // TODO: check if this is correct
-        let member: bool = con
-            .sismember(self.get_key(key), rmp_serde::to_vec(&item).unwrap())
-            .await?;
+        let mut con = self.read_con().await?;
+
+        let key = self.get_key(key);
+        let mut pipe = redis::pipe();
 
-        Ok(member)
+        for pack in self.member_packs(&item)? {
+            pipe.sismember(&key, pack);
+        }
+
+        let members: Vec<bool> = pipe.query_async(&mut *con).await?;
+
+        Ok(members.contains(&true))
     }
 
-    async fn get_con(&self) -> Result<Connection<RedisConnectionManager>, CacheError> {
+    async fn get_con(&self) -> Result<Connection<RedisManager>, CacheError> {
+        let _timer = self.metrics.pool_wait(&self.prefix);
+
         // Ok(self.pool.get_con().await.unwrap())
         // match self.pool.get().await {
         //     Ok(con) => Some(con),
@@ -337,8 +1569,352 @@ where
         Ok(self.pool.get().await.unwrap())
     }
 
+    /// Connection for a read, to a replica if there is one fresh enough.
+    async fn read_con(&self) -> Result<Connection<RedisManager>, CacheError> {
+        if let Some(replicas) = &self.replicas {
+            let con = {
+                let _timer = self.metrics.pool_wait(&self.prefix);
+
+                replicas.get_con().await
+            };
+
+            if let Some(con) = con {
+                return Ok(con);
+            }
+        }
+
+        self.get_con().await
+    }
+
+    /// Entries which failed to decode, newest first.
+    ///
+    /// See [`QuarantinedEntry`].
+    pub async fn quarantined(&self) -> Result<Vec<QuarantinedEntry>, CacheError> {
+        let mut con = self.get_con().await?;
+
+        quarantine::quarantined(&mut con, &self.prefix).await
+    }
+
+    /// Decode the members of a set, quarantining the ones which fail to
+    /// decode on the primary.
+    async fn decode_members(&self, key: &str, packs: Vec<Vec<u8>>) -> Vec<V> {
+        let mut members = Vec::with_capacity(packs.len());
+
+        for pack in packs {
+            let member =
+                compress::decompress(&pack).and_then(|member| Ok(rmp_serde::from_read(&*member)?));
+
+            match member {
+                Ok(member) => members.push(member),
+                Err(err) => {
+                    let mut pipe = redis::pipe();
+                    pipe.srem(key, pack.as_slice()).ignore();
+
+                    let entry = QuarantinedEntry::new(
+                        &self.prefix,
+                        key.to_owned(),
+                        std::any::type_name::<V>(),
+                        &err,
+                        pack,
+                    );
+
+                    match self.get_con().await {
+                        Ok(mut con) => {
+                            quarantine::quarantine(
+                                &mut con,
+                                &self.metrics,
+                                entry,
+                                pipe,
+                                !self.cluster,
+                            )
+                            .await
+                        }
+                        Err(err) => error!("Failed to quarantine {}: {}", entry.key, err),
+                    }
+                }
+            }
+        }
+
+        members
+    }
+
+    /// Encode a member, compressed if it is large.
+    fn pack(&self, item: &V) -> Result<Vec<u8>, CacheError> {
+        let pack = rmp_serde::to_vec(item)?;
+
+        if !self.compressor.is_enabled() {
+            return Ok(pack);
+        }
+
+        let original = pack.len();
+        let stored = self.compressor.compress(pack);
+
+        self.metrics
+            .compression(&self.prefix, original, stored.len());
+
+        Ok(stored)
+    }
+
+    /// The ways a member may be stored, uncompressed and compressed with the
+    /// current configuration.
+    fn member_packs(&self, item: &V) -> Result<Vec<Vec<u8>>, CacheError> {
+        let pack = rmp_serde::to_vec(item)?;
+        let stored = self.compressor.compress(pack.clone());
+
+        if stored == pack {
+            Ok(vec![pack])
+        } else {
+            Ok(vec![pack, stored])
+        }
+    }
+
+    /// The set of `key`, in the slot of `key` in a cluster.
+    fn get_key(&self, key: K) -> String {
+        if self.cluster {
+            format!("{}-{}", self.prefix, connection::hash_tag(key))
+        } else {
+            format!("{}-{}", self.prefix, key)
+        }
+    }
+}
+
+pub struct RedisListCache<K, V>
+where
+    K: std::fmt::Display + std::marker::Sync + std::marker::Send,
+    V: DeserializeOwned + Serialize,
+{
+    prefix: String,
+    cluster: bool,
+    pub(crate) metrics: Metrics,
+    pool: Pool<RedisManager>,
+    pub(crate) replicas: Option<Replicas>,
+    key_type: std::marker::PhantomData<K>,
+    value_type: std::marker::PhantomData<V>,
+}
+
+impl<K, V> RedisListCache<K, V>
+where
+    K: std::fmt::Display + std::marker::Sync + std::marker::Send,
+    V: DeserializeOwned + Serialize,
+{
+    pub fn new(connection_str: &str, prefix: String) -> RedisListCache<K, V> {
+        Self::with_manager(RedisManager::new(connection_str), prefix)
+    }
+
+    pub(crate) fn with_manager(manager: RedisManager, prefix: String) -> RedisListCache<K, V> {
+        let cluster = manager.is_cluster();
+        let pool = Pool::builder().max_open(50).build(manager);
+
+        Self {
+            prefix,
+            cluster,
+            metrics: Metrics::default(),
+            pool,
+            replicas: None,
+            key_type: std::marker::PhantomData,
+            value_type: std::marker::PhantomData,
+        }
+    }
+
+    /// Push an item to the front of the list, dropping the oldest items once
+    /// the list is longer than `max_len`.
+    pub async fn push(&self, key: K, item: &V, max_len: usize) -> Result<(), CacheError> {
+        let mut con = self.get_con().await?;
+
+        let key = self.get_key(key);
+        let pack = rmp_serde::to_vec(&item)?;
+
+        con.lpush::<_, _, ()>(&key, pack).await?;
+        con.ltrim::<_, ()>(&key, 0, max_len as isize - 1).await?;
+
+        if self.cluster {
+            con.sadd::<_, _, ()>(self.index_key(), &key).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Get all items of the list, newest first.
+    ///
+    /// Items which fail to decode are left out and quarantined.
+    pub async fn get(&self, key: K) -> Result<Vec<V>, CacheError> {
+        let mut con = self.read_con().await?;
+
+        let key = self.get_key(key);
+        let packs: Vec<Vec<u8>> = con.lrange(&key, 0, -1).await?;
+
+        Ok(self.decode_items(&key, packs).await)
+    }
+
+    pub async fn size(&self, key: K) -> Result<usize, CacheError> {
+        let mut con = self.read_con().await?;
+
+        let length: usize = con.llen(self.get_key(key)).await?;
+
+        Ok(length)
+    }
+
+    pub async fn expire(&self, key: K, seconds: usize) -> Result<bool, CacheError> {
+        let mut con = self.get_con().await?;
+
+        let set = con.expire(self.get_key(key), seconds).await?;
+
+        Ok(set)
+    }
+
+    pub async fn delete(&self, key: K) -> Result<bool, CacheError> {
+        let mut con = self.get_con().await?;
+
+        let key = self.get_key(key);
+        let del = con.del(&key).await?;
+
+        if self.cluster {
+            con.srem::<_, _, ()>(self.index_key(), &key).await?;
+        }
+
+        Ok(del)
+    }
+
+    /// Remove the items of all lists for which `f` returns `false`,
+    /// returning the number of removed items.
+    ///
+    /// Lists are found with `SCAN`, [`SCAN_COUNT`] keys at a time. Items
+    /// which fail to decode are kept.
+    ///
+    /// `SCAN` only covers a single node of a cluster, there the lists are
+    /// found through an index of them instead.
+    pub async fn retain_all<F>(&self, mut f: F) -> Result<usize, CacheError>
+    where
+        F: FnMut(&V) -> bool,
+    {
+        let mut con = self.get_con().await?;
+        let pattern = format!("{}-*", self.prefix);
+
+        let mut cursor = 0;
+        let mut removed = 0;
+
+        loop {
+            let mut scan = if self.cluster {
+                let mut scan = cmd("SSCAN");
+                scan.arg(self.index_key()).arg(cursor);
+
+                scan
+            } else {
+                let mut scan = cmd("SCAN");
+                scan.arg(cursor).arg("MATCH").arg(&pattern);
+
+                scan
+            };
+
+            let (next, keys): (u64, Vec<String>) = scan
+                .arg("COUNT")
+                .arg(SCAN_COUNT)
+                .query_async(&mut *con)
+                .await?;
+
+            for key in keys {
+                let packs: Vec<Vec<u8>> = con.lrange(&key, 0, -1).await?;
+
+                // Expired or emptied since it was indexed.
+                if self.cluster && packs.is_empty() {
+                    con.srem::<_, _, ()>(self.index_key(), &key).await?;
+                }
+
+                for pack in packs {
+                    let keep = rmp_serde::from_read(&*pack)
+                        .map(|value| f(&value))
+                        .unwrap_or(true);
+
+                    if !keep {
+                        removed += con.lrem::<_, _, usize>(&key, 0, pack).await?;
+                    }
+                }
+            }
+
+            if next == 0 {
+                return Ok(removed);
+            }
+
+            cursor = next;
+        }
+    }
+
+    async fn get_con(&self) -> Result<Connection<RedisManager>, CacheError> {
+        Ok(self.pool.get().await?)
+    }
+
+    /// Connection for a read, to a replica if there is one fresh enough.
+    async fn read_con(&self) -> Result<Connection<RedisManager>, CacheError> {
+        if let Some(replicas) = &self.replicas {
+            if let Some(con) = replicas.get_con().await {
+                return Ok(con);
+            }
+        }
+
+        self.get_con().await
+    }
+
+    /// Entries which failed to decode, newest first.
+    ///
+    /// See [`QuarantinedEntry`].
+    pub async fn quarantined(&self) -> Result<Vec<QuarantinedEntry>, CacheError> {
+        let mut con = self.get_con().await?;
+
+        quarantine::quarantined(&mut con, &self.prefix).await
+    }
+
+    /// Decode the items of a list, quarantining the ones which fail to
+    /// decode on the primary.
+    async fn decode_items(&self, key: &str, packs: Vec<Vec<u8>>) -> Vec<V> {
+        let mut items = Vec::with_capacity(packs.len());
+
+        for pack in packs {
+            match rmp_serde::from_read(&*pack) {
+                Ok(item) => items.push(item),
+                Err(err) => {
+                    let mut pipe = redis::pipe();
+                    pipe.lrem(key, 1, pack.as_slice()).ignore();
+
+                    let entry = QuarantinedEntry::new(
+                        &self.prefix,
+                        key.to_owned(),
+                        std::any::type_name::<V>(),
+                        &err,
+                        pack,
+                    );
+
+                    match self.get_con().await {
+                        Ok(mut con) => {
+                            quarantine::quarantine(
+                                &mut con,
+                                &self.metrics,
+                                entry,
+                                pipe,
+                                !self.cluster,
+                            )
+                            .await
+                        }
+                        Err(err) => error!("Failed to quarantine {}: {}", entry.key, err),
+                    }
+                }
+            }
+        }
+
+        items
+    }
+
+    /// The list of `key`, in the slot of `key` in a cluster.
     fn get_key(&self, key: K) -> String {
-        format!("{}-{}", self.prefix, key)
+        if self.cluster {
+            format!("{}-{}", self.prefix, connection::hash_tag(key))
+        } else {
+            format!("{}-{}", self.prefix, key)
+        }
+    }
+
+    /// Set of the keys of all lists, only kept in a cluster.
+    fn index_key(&self) -> String {
+        format!("{}-lists", self.prefix)
     }
 }
 
@@ -364,12 +1940,20 @@ impl<T> GuildResource<T> {
 
 pub struct InRedisCache {
     config: Config,
+    fetcher: Option<Arc<dyn Fetcher>>,
+    in_flight: InFlight,
+    metrics: Metrics,
+    readiness: Readiness,
+    recorder: Option<Arc<EventRecorder>>,
+    replicas: Option<Replicas>,
 
     pub channels_guild: RedisHashMapCache<Snowflake, GuildResource<GuildChannel>>,
     pub channels_private: RedisHashMapCache<Snowflake, PrivateChannel>,
     pub channel_messages: RedisSetCache<Snowflake, Snowflake>,
+    /// Mapping of channels and the most recently deleted messages in them.
+    pub channel_deleted_messages: RedisListCache<Snowflake, CachedMessage>,
     // So long as the lock isn't held across await or panic points this is fine.
-    // current_user: Mutex<Option<CurrentUser>>,
+    current_user: Mutex<Option<CurrentUser>>,
     pub emojis: RedisHashMapCache<Snowflake, GuildResource<CachedEmoji>>,
     pub groups: RedisHashMapCache<Snowflake, Group>,
     pub guilds: RedisHashMapCache<Snowflake, CachedGuild>,
@@ -378,19 +1962,38 @@ pub struct InRedisCache {
     pub guild_integrations: RedisSetCache<Snowflake, Snowflake>,
     pub guild_members: RedisSetCache<Snowflake, Snowflake>,
     pub guild_presences: RedisSetCache<Snowflake, Snowflake>,
+    /// Mapping of guilds and how many of their cached presences have each
+    /// status.
+    pub guild_presence_counts: RedisHashMapCache<Snowflake, PresenceCounts>,
     pub guild_roles: RedisSetCache<Snowflake, Snowflake>,
     pub guild_stage_instances: RedisSetCache<Snowflake, Snowflake>,
     pub guild_stickers: RedisSetCache<Snowflake, Snowflake>,
     pub integrations: RedisHashMapCache<(Snowflake, Snowflake), GuildResource<GuildIntegration>>,
     pub members: RedisHashMapCache<(Snowflake, Snowflake), CachedMember>,
-    pub messages: RedisHashMapCache<Snowflake, CachedMessage>,
+    /// Mapping of guild ID, or 0 in private channels, and message ID pairs
+    /// to messages.
+    pub messages: RedisHashMapCache<(Snowflake, Snowflake), CachedMessage>,
+    /// Mapping of message IDs and the emojis users are known to have
+    /// reacted with.
+    pub message_reactions: RedisSetCache<Snowflake, String>,
+    /// Mapping of message IDs and their previous revisions, newest first.
+    pub message_revisions: RedisListCache<Snowflake, CachedMessage>,
     pub presences: RedisHashMapCache<(Snowflake, Snowflake), CachedPresence>,
+    /// Mapping of reactions, as `message_id:emoji`, and the users who added
+    /// them.
+    pub reaction_users: RedisSetCache<String, Snowflake>,
     pub roles: RedisHashMapCache<Snowflake, GuildResource<Role>>,
     pub stage_instances: RedisHashMapCache<Snowflake, StageInstance>,
     pub stickers: RedisHashMapCache<Snowflake, GuildResource<Sticker>>,
     pub unavailable_guilds: RedisSetCache<String, Snowflake>,
     pub users: RedisHashMapCache<Snowflake, User>,
     pub user_guilds: RedisSetCache<Snowflake, Snowflake>,
+    /// Mapping of users and the keys of their cached messages.
+    pub user_messages: RedisSetCache<Snowflake, (Snowflake, Snowflake)>,
+    /// Mapping of users and the reactions they added, as `message_id:emoji`.
+    pub user_reactions: RedisSetCache<Snowflake, String>,
+    /// Mapping of users and the IDs of the private channels they are in.
+    pub user_private_channels: RedisSetCache<Snowflake, Snowflake>,
     /// Mapping of channels and the users currently connected.
     pub voice_state_channels: RedisSetCache<Snowflake, (Snowflake, Snowflake)>,
     /// Mapping of guilds and users currently connected to its voice channels.
@@ -401,76 +2004,215 @@ pub struct InRedisCache {
 
 impl InRedisCache {
     /// Creates a new, empty cache.
+    pub fn new() -> Self {
+        let mut config = Config::new();
+        config.resource_types = ResourceType::all();
+
+        Self::with_config(config)
+    }
+
+    /// Creates a new, empty cache with a custom configuration.
     ///
     /// # Examples
     ///
-    /// Creating a new `InMemoryCache` with a custom configuration, limiting
-    /// the message cache to 50 messages per channel:
+    /// Creating a new `InRedisCache` which keeps the last 5 revisions of
+    /// edited messages and the last 50 deleted messages per channel:
     ///
-    /// ```
-    /// use twilight_cache_inmemory::InMemoryCache;
+    /// ```no_run
+    /// use cache::{Config, InRedisCache};
+    ///
+    /// let mut config = Config::new();
+    /// *config.message_revision_size_mut() = 5;
+    /// *config.deleted_message_cache_size_mut() = 50;
     ///
-    /// let cache = InMemoryCache::builder().message_cache_size(50).build();
+    /// let cache = InRedisCache::with_config(config);
     /// ```
-    pub fn new() -> Self {
-        let mut config = Config::new();
-        config.resource_types = ResourceType::all();
+    pub fn with_config(config: Config) -> Self {
+        let layout = config.key_layout();
+        let manager = if config.cluster_nodes().is_empty() {
+            RedisManager::new(config.node())
+        } else {
+            RedisManager::cluster(config.cluster_nodes())
+        };
+        let compressor = Compressor::new(config.compression(), config.compression_threshold());
+
+        if manager.is_cluster() && !config.replica_nodes().is_empty() {
+            warn!("Ignoring the replica nodes, a Redis Cluster serves reads from its primaries");
+        }
 
-        // let pool = Arc::new(RedisPool::new("redis://127.0.0.1"));
+        let replicas = (!manager.is_cluster() && !config.replica_nodes().is_empty()).then(|| {
+            Replicas::new(
+                manager.clone(),
+                config.replica_nodes(),
+                config.replica_max_lag(),
+                config.read_your_writes(),
+            )
+        });
 
-        Self {
+        let mut cache = Self {
             config,
-            channels_guild: RedisHashMapCache::new("redis://127.0.0.1", "channels_guild".into()),
-            channels_private: RedisHashMapCache::new(
-                "redis://127.0.0.1",
+            current_user: Mutex::new(None),
+            fetcher: None,
+            in_flight: InFlight::default(),
+            metrics: Metrics::default(),
+            readiness: Readiness::default(),
+            recorder: None,
+            replicas: None,
+            channels_guild: RedisHashMapCache::with_manager(
+                manager.clone(),
+                "channels_guild".into(),
+                layout,
+            ),
+            channels_private: RedisHashMapCache::with_manager(
+                manager.clone(),
                 "channels_private".into(),
+                layout,
+            ),
+            emojis: RedisHashMapCache::with_manager(manager.clone(), "emojis".into(), layout),
+            groups: RedisHashMapCache::with_manager(manager.clone(), "groups".into(), layout),
+            guilds: RedisHashMapCache::with_manager(manager.clone(), "guilds".into(), layout),
+            // Every presence write watches the counts of its guild, which
+            // need a key of their own so guilds don't abort each other.
+            guild_presence_counts: RedisHashMapCache::with_manager(
+                manager.clone(),
+                "guild_presence_counts".into(),
+                KeyLayout::Hash,
             ),
-            emojis: RedisHashMapCache::new("redis://127.0.0.1", "emojis".into()),
-            groups: RedisHashMapCache::new("redis://127.0.0.1", "groups".into()),
-            guilds: RedisHashMapCache::new("redis://127.0.0.1", "guilds".into()),
-            integrations: RedisHashMapCache::new("redis://127.0.0.1", "integrations".into()),
-            members: RedisHashMapCache::new("redis://127.0.0.1", "members".into()),
-            messages: RedisHashMapCache::new("redis://127.0.0.1", "messages".into()),
-            presences: RedisHashMapCache::new("redis://127.0.0.1", "presences".into()),
-            roles: RedisHashMapCache::new("redis://127.0.0.1", "roles".into()),
-            stage_instances: RedisHashMapCache::new("redis://127.0.0.1", "stage_instances".into()),
-            stickers: RedisHashMapCache::new("redis://127.0.0.1", "stickers".into()),
-            users: RedisHashMapCache::new("redis://127.0.0.1", "users".into()),
-            voice_states: RedisHashMapCache::new("redis://127.0.0.1", "voice_states".into()),
-            channel_messages: RedisSetCache::new("redis://127.0.0.1", "channel_messages".into()),
-            guild_channels: RedisSetCache::new("redis://127.0.0.1", "guild_channels".into()),
-            guild_emojis: RedisSetCache::new("redis://127.0.0.1", "guild_emojis".into()),
-            guild_integrations: RedisSetCache::new(
-                "redis://127.0.0.1",
+            integrations: RedisHashMapCache::with_manager(
+                manager.clone(),
+                "integrations".into(),
+                layout,
+            ),
+            members: RedisHashMapCache::with_manager(manager.clone(), "members".into(), layout),
+            messages: RedisHashMapCache::with_manager(manager.clone(), "messages".into(), layout),
+            message_reactions: RedisSetCache::with_manager(
+                manager.clone(),
+                "message_reactions".into(),
+            ),
+            message_revisions: RedisListCache::with_manager(
+                manager.clone(),
+                "message_revisions".into(),
+            ),
+            presences: RedisHashMapCache::with_manager(manager.clone(), "presences".into(), layout),
+            reaction_users: RedisSetCache::with_manager(manager.clone(), "reaction_users".into()),
+            roles: RedisHashMapCache::with_manager(manager.clone(), "roles".into(), layout),
+            stage_instances: RedisHashMapCache::with_manager(
+                manager.clone(),
+                "stage_instances".into(),
+                layout,
+            ),
+            stickers: RedisHashMapCache::with_manager(manager.clone(), "stickers".into(), layout),
+            users: RedisHashMapCache::with_manager(manager.clone(), "users".into(), layout),
+            voice_states: RedisHashMapCache::with_manager(
+                manager.clone(),
+                "voice_states".into(),
+                layout,
+            ),
+            channel_messages: RedisSetCache::with_manager(
+                manager.clone(),
+                "channel_messages".into(),
+            ),
+            channel_deleted_messages: RedisListCache::with_manager(
+                manager.clone(),
+                "channel_deleted_messages".into(),
+            ),
+            guild_channels: RedisSetCache::with_manager(manager.clone(), "guild_channels".into()),
+            guild_emojis: RedisSetCache::with_manager(manager.clone(), "guild_emojis".into()),
+            guild_integrations: RedisSetCache::with_manager(
+                manager.clone(),
                 "guild_integrations".into(),
             ),
-            guild_members: RedisSetCache::new("redis://127.0.0.1", "guild_members".into()),
-            guild_presences: RedisSetCache::new("redis://127.0.0.1", "guild_presences".into()),
-            guild_roles: RedisSetCache::new("redis://127.0.0.1", "guild_roles".into()),
-            guild_stage_instances: RedisSetCache::new(
-                "redis://127.0.0.1",
+            guild_members: RedisSetCache::with_manager(manager.clone(), "guild_members".into()),
+            guild_presences: RedisSetCache::with_manager(manager.clone(), "guild_presences".into()),
+            guild_roles: RedisSetCache::with_manager(manager.clone(), "guild_roles".into()),
+            guild_stage_instances: RedisSetCache::with_manager(
+                manager.clone(),
                 "guild_stage_instances".into(),
             ),
-            guild_stickers: RedisSetCache::new("redis://127.0.0.1", "guild_stickers".into()),
-            unavailable_guilds: RedisSetCache::new(
-                "redis://127.0.0.1",
+            guild_stickers: RedisSetCache::with_manager(manager.clone(), "guild_stickers".into()),
+            unavailable_guilds: RedisSetCache::with_manager(
+                manager.clone(),
                 "unavailable_guilds".into(),
             ),
-            user_guilds: RedisSetCache::new("redis://127.0.0.1", "user_guilds".into()),
-            voice_state_channels: RedisSetCache::new(
-                "redis://127.0.0.1",
+            user_guilds: RedisSetCache::with_manager(manager.clone(), "user_guilds".into()),
+            user_messages: RedisSetCache::with_manager(manager.clone(), "user_messages".into()),
+            user_reactions: RedisSetCache::with_manager(manager.clone(), "user_reactions".into()),
+            user_private_channels: RedisSetCache::with_manager(
+                manager.clone(),
+                "user_private_channels".into(),
+            ),
+            voice_state_channels: RedisSetCache::with_manager(
+                manager.clone(),
                 "voice_state_channels".into(),
             ),
-            voice_state_guilds: RedisSetCache::new(
-                "redis://127.0.0.1",
+            voice_state_guilds: RedisSetCache::with_manager(
+                manager.clone(),
                 "voice_state_guilds".into(),
             ),
+        };
+
+        if let Some(replicas) = replicas {
+            cache.set_replicas(replicas);
+        }
+
+        if compressor.is_enabled() {
+            cache.set_compressor(compressor);
         }
+
+        cache
+    }
+
+    /// Move all entries stored with the [`KeyLayout::Blob`] layout over to the
+    /// [`KeyLayout::Hash`] layout, returning the number of moved entries.
+    ///
+    /// Does nothing unless the cache is configured to use the hash layout,
+    /// except for presence counts, which always use it.
+    pub async fn migrate_key_layout(&self) -> Result<usize, CacheError> {
+        let mut migrated = 0;
+
+        migrated += self.channels_guild.migrate_from_blob().await?;
+        migrated += self.channels_private.migrate_from_blob().await?;
+        migrated += self.emojis.migrate_from_blob().await?;
+        migrated += self.groups.migrate_from_blob().await?;
+        migrated += self.guilds.migrate_from_blob().await?;
+        migrated += self.guild_presence_counts.migrate_from_blob().await?;
+        migrated += self.integrations.migrate_from_blob().await?;
+        migrated += self.members.migrate_from_blob().await?;
+        migrated += self.messages.migrate_from_blob().await?;
+        migrated += self.presences.migrate_from_blob().await?;
+        migrated += self.roles.migrate_from_blob().await?;
+        migrated += self.stage_instances.migrate_from_blob().await?;
+        migrated += self.stickers.migrate_from_blob().await?;
+        migrated += self.users.migrate_from_blob().await?;
+        migrated += self.voice_states.migrate_from_blob().await?;
+
+        Ok(migrated)
+    }
+
+    /// Gets the current user.
+    ///
+    /// This is set by the `Ready` event and kept up to date by `UserUpdate`.
+    pub fn current_user(&self) -> Option<CurrentUser> {
+        self.current_user
+            .lock()
+            .expect("current user poisoned")
+            .clone()
     }
 
     /// Update the cache with an event from the gateway.
-    pub async fn update(&self, value: &impl UpdateCache) {
-        value.update(&self).await;
+    ///
+    /// Everything the event reads and writes goes to the primary, also when
+    /// there are replicas.
+    pub async fn update<T: UpdateCache>(&self, value: &T) {
+        // `Event` records the time of the event it contains.
+        let type_name = std::any::type_name::<T>();
+        let _timer = (type_name != std::any::type_name::<Event>()).then(|| {
+            let event = type_name.rsplit("::").next().unwrap_or(type_name);
+
+            self.metrics.event(event)
+        });
+
+        replica::writing(value.update(self)).await;
     }
 
     /// Determine whether the configured cache wants a specific resource to be
@@ -489,9 +2231,38 @@ pub trait UpdateCache {
     async fn update(&self, cache: &InRedisCache);
 }
 
+mod compress;
 mod config;
+mod connection;
+mod crypt;
+mod dispatch;
 mod event;
+mod fetch;
+mod filter;
+mod layout;
+mod metrics;
 mod model;
+mod purge;
+mod quarantine;
+mod ready;
+mod rebuild;
+mod record;
+mod replica;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+pub use compress::Compression;
+pub use config::{CacheRule, Config, KeyLayout, ResourceType};
+pub use crypt::MessageEncryption;
+pub use dispatch::{Dispatcher, DispatcherStats};
+pub use fetch::{FetchError, Fetcher};
+pub use layout::FieldChanges;
+pub use metrics::{MetricsRecorder, PrometheusRecorder};
+pub use purge::PurgeReport;
+pub use quarantine::{QuarantinedEntry, QUARANTINE_SIZE};
+pub use rebuild::{Missing, Rebuilt};
+pub use record::{read_file, read_stream, EventRecorder, RecordedEvent};
 
 #[async_trait::async_trait]
 impl UpdateCache for Event {
@@ -538,7 +2309,7 @@ impl UpdateCache for Event {
             ReactionRemove(v) => c.update(v.deref()).await,
             ReactionRemoveAll(v) => c.update(v).await,
             ReactionRemoveEmoji(v) => c.update(v).await,
-            // Ready(v) => c.update(v.deref()).await,
+            Ready(v) => c.update(v.deref()).await,
             Resumed => {}
             RoleCreate(v) => c.update(v).await,
             RoleDelete(v) => c.update(v).await,
@@ -560,8 +2331,8 @@ impl UpdateCache for Event {
             ThreadMemberUpdate(_) => {}
             ThreadMembersUpdate(_) => {}
             TypingStart(_) => {}
-            // UnavailableGuild(v) => c.update(v).await,
-            // UserUpdate(v) => c.update(v).await,
+            UnavailableGuild(v) => c.update(v).await,
+            UserUpdate(v) => c.update(v).await,
             VoiceServerUpdate(_) => {}
             // VoiceStateUpdate(v) => c.update(v.deref()).await,
             WebhooksUpdate(_) => {}
@@ -569,3 +2340,26 @@ impl UpdateCache for Event {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[tokio::test]
+    #[ignore = "needs the Redis server from docker-compose.yml"]
+    async fn test_set_iter() {
+        let set = RedisSetCache::<u64, u64>::new("redis://127.0.0.1", "test_set_iter".into());
+        let members = (0..SCAN_COUNT as u64 * 2 + 10).collect::<Vec<_>>();
+
+        set.delete(1).await.unwrap();
+        set.insert_multiple(1, members.clone()).await.unwrap();
+
+        // Members may be returned more than once, but none is missed.
+        let walked = set.iter(1).try_collect::<HashSet<_>>().await.unwrap();
+        assert_eq!(walked, members.into_iter().collect());
+
+        set.delete(1).await.unwrap();
+    }
+}
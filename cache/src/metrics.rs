@@ -0,0 +1,510 @@
+//! Metrics about cache operations.
+//!
+//! Recording is disabled until a [`MetricsRecorder`] is set with
+//! [`InRedisCache::set_metrics_recorder`]. [`PrometheusRecorder`] collects
+//! the metrics and renders them in the Prometheus text format.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::InRedisCache;
+
+/// Receives the metrics of a cache.
+///
+/// Called on every operation, so implementations should be cheap.
+pub trait MetricsRecorder: Send + Sync {
+    /// A Redis operation, like `hget` or `sadd`, was run on a store.
+    fn record_operation(&self, store: &str, operation: &str, duration: Duration);
+
+    /// A value read from a store failed to decode.
+    fn record_decode_failure(&self, store: &str);
+
+    /// An event of a type, like `MessageCreate`, was applied to the cache.
+    fn record_event(&self, event: &str, duration: Duration);
+
+    /// A store waited for a connection from its pool.
+    fn record_pool_wait(&self, store: &str, duration: Duration);
+
+    /// A store encoded a value of `original` bytes with compression enabled,
+    /// storing `stored` bytes.
+    fn record_compression(&self, store: &str, original: usize, stored: usize);
+}
+
+/// Handle to the optional recorder, shared by the cache and its stores.
+#[derive(Clone, Default)]
+pub(crate) struct Metrics(Option<Arc<dyn MetricsRecorder>>);
+
+impl Metrics {
+    pub(crate) fn new(recorder: Arc<dyn MetricsRecorder>) -> Self {
+        Self(Some(recorder))
+    }
+
+    /// Time an operation until the returned guard is dropped.
+    pub(crate) fn operation<'a>(&'a self, store: &'a str, operation: &'static str) -> Timer<'a> {
+        Timer(
+            self.0
+                .as_deref()
+                .map(|recorder| (recorder, Kind::Operation(store, operation), Instant::now())),
+        )
+    }
+
+    /// Time the application of an event until the returned guard is dropped.
+    pub(crate) fn event<'a>(&'a self, event: &'static str) -> Timer<'a> {
+        Timer(
+            self.0
+                .as_deref()
+                .map(|recorder| (recorder, Kind::Event(event), Instant::now())),
+        )
+    }
+
+    /// Time waiting for a pool connection until the returned guard is
+    /// dropped.
+    pub(crate) fn pool_wait<'a>(&'a self, store: &'a str) -> Timer<'a> {
+        Timer(
+            self.0
+                .as_deref()
+                .map(|recorder| (recorder, Kind::PoolWait(store), Instant::now())),
+        )
+    }
+
+    pub(crate) fn compression(&self, store: &str, original: usize, stored: usize) {
+        if let Some(recorder) = &self.0 {
+            recorder.record_compression(store, original, stored);
+        }
+    }
+
+    pub(crate) fn decode_failure(&self, store: &str) {
+        if let Some(recorder) = &self.0 {
+            recorder.record_decode_failure(store);
+        }
+    }
+}
+
+enum Kind<'a> {
+    Event(&'static str),
+    Operation(&'a str, &'static str),
+    PoolWait(&'a str),
+}
+
+/// Records the time since it was created when dropped.
+pub(crate) struct Timer<'a>(Option<(&'a dyn MetricsRecorder, Kind<'a>, Instant)>);
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        if let Some((recorder, kind, start)) = &self.0 {
+            let elapsed = start.elapsed();
+
+            match kind {
+                Kind::Event(event) => recorder.record_event(event, elapsed),
+                Kind::Operation(store, operation) => {
+                    recorder.record_operation(store, operation, elapsed)
+                }
+                Kind::PoolWait(store) => recorder.record_pool_wait(store, elapsed),
+            }
+        }
+    }
+}
+
+/// Upper bounds of the histogram buckets, in seconds.
+const BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+
+        for (bucket, le) in self.buckets.iter_mut().zip(BUCKETS) {
+            if seconds <= le {
+                *bucket += 1;
+            }
+        }
+
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (count, le) in self.buckets.iter().zip(BUCKETS) {
+            writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, count).ok();
+        }
+
+        writeln!(
+            out,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, self.count
+        )
+        .ok();
+        writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum).ok();
+        writeln!(out, "{}_count{{{}}} {}", name, labels, self.count).ok();
+    }
+}
+
+/// Escape a label value of the text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Sizes of the values a store encoded.
+#[derive(Clone, Copy, Debug, Default)]
+struct Sizes {
+    values: u64,
+    compressed: u64,
+    original_bytes: u64,
+    stored_bytes: u64,
+}
+
+#[derive(Debug, Default)]
+struct Collected {
+    compression: BTreeMap<String, Sizes>,
+    decode_failures: BTreeMap<String, u64>,
+    events: BTreeMap<String, Histogram>,
+    operations: BTreeMap<(String, String), Histogram>,
+    pool_waits: BTreeMap<String, Histogram>,
+}
+
+/// Collects metrics and renders them in the Prometheus text format.
+///
+/// # Examples
+///
+/// ```ignore
+/// let recorder = Arc::new(PrometheusRecorder::new());
+/// cache.set_metrics_recorder(recorder.clone());
+///
+/// // In the handler of the `/metrics` endpoint:
+/// let body = recorder.render();
+/// ```
+#[derive(Debug, Default)]
+pub struct PrometheusRecorder(Mutex<Collected>);
+
+impl PrometheusRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Render all collected metrics.
+    ///
+    /// The `_count` of the histograms counts the operations, events and
+    /// pool waits.
+    pub fn render(&self) -> String {
+        let collected = self.0.lock().expect("metrics poisoned");
+        let mut out = String::new();
+
+        out.push_str("# HELP cache_operation_duration_seconds Time taken by Redis operations.\n");
+        out.push_str("# TYPE cache_operation_duration_seconds histogram\n");
+
+        for ((store, operation), histogram) in &collected.operations {
+            histogram.render(
+                &mut out,
+                "cache_operation_duration_seconds",
+                &format!(
+                    "store=\"{}\",operation=\"{}\"",
+                    escape(store),
+                    escape(operation)
+                ),
+            );
+        }
+
+        out.push_str("# HELP cache_decode_failures_total Stored values which failed to decode.\n");
+        out.push_str("# TYPE cache_decode_failures_total counter\n");
+
+        for (store, count) in &collected.decode_failures {
+            writeln!(
+                out,
+                "cache_decode_failures_total{{store=\"{}\"}} {}",
+                escape(store),
+                count
+            )
+            .ok();
+        }
+
+        out.push_str("# HELP cache_event_duration_seconds Time taken to apply events.\n");
+        out.push_str("# TYPE cache_event_duration_seconds histogram\n");
+
+        for (event, histogram) in &collected.events {
+            histogram.render(
+                &mut out,
+                "cache_event_duration_seconds",
+                &format!("event=\"{}\"", escape(event)),
+            );
+        }
+
+        out.push_str(
+            "# HELP cache_pool_wait_duration_seconds Time spent waiting for a connection.\n",
+        );
+        out.push_str("# TYPE cache_pool_wait_duration_seconds histogram\n");
+
+        for (store, histogram) in &collected.pool_waits {
+            histogram.render(
+                &mut out,
+                "cache_pool_wait_duration_seconds",
+                &format!("store=\"{}\"", escape(store)),
+            );
+        }
+
+        out.push_str(
+            "# HELP cache_encoded_values_total Values encoded with compression enabled.\n",
+        );
+        out.push_str("# TYPE cache_encoded_values_total counter\n");
+
+        for (store, sizes) in &collected.compression {
+            writeln!(
+                out,
+                "cache_encoded_values_total{{store=\"{}\"}} {}",
+                escape(store),
+                sizes.values
+            )
+            .ok();
+        }
+
+        out.push_str("# HELP cache_compressed_values_total Values stored compressed.\n");
+        out.push_str("# TYPE cache_compressed_values_total counter\n");
+
+        for (store, sizes) in &collected.compression {
+            writeln!(
+                out,
+                "cache_compressed_values_total{{store=\"{}\"}} {}",
+                escape(store),
+                sizes.compressed
+            )
+            .ok();
+        }
+
+        out.push_str("# HELP cache_encoded_bytes_total Size of values before compression.\n");
+        out.push_str("# TYPE cache_encoded_bytes_total counter\n");
+
+        for (store, sizes) in &collected.compression {
+            writeln!(
+                out,
+                "cache_encoded_bytes_total{{store=\"{}\"}} {}",
+                escape(store),
+                sizes.original_bytes
+            )
+            .ok();
+        }
+
+        out.push_str("# HELP cache_stored_bytes_total Size of values as stored.\n");
+        out.push_str("# TYPE cache_stored_bytes_total counter\n");
+
+        for (store, sizes) in &collected.compression {
+            writeln!(
+                out,
+                "cache_stored_bytes_total{{store=\"{}\"}} {}",
+                escape(store),
+                sizes.stored_bytes
+            )
+            .ok();
+        }
+
+        out
+    }
+
+    /// Compression ratio of every store, the size of the values it encoded
+    /// divided by their size as stored.
+    ///
+    /// Stores only show up once they encoded a value with compression
+    /// enabled.
+    pub fn compression_ratios(&self) -> BTreeMap<String, f64> {
+        self.0
+            .lock()
+            .expect("metrics poisoned")
+            .compression
+            .iter()
+            .filter(|(_, sizes)| sizes.stored_bytes > 0)
+            .map(|(store, sizes)| {
+                let ratio = sizes.original_bytes as f64 / sizes.stored_bytes as f64;
+
+                (store.clone(), ratio)
+            })
+            .collect()
+    }
+}
+
+impl MetricsRecorder for PrometheusRecorder {
+    fn record_operation(&self, store: &str, operation: &str, duration: Duration) {
+        self.0
+            .lock()
+            .expect("metrics poisoned")
+            .operations
+            .entry((store.to_owned(), operation.to_owned()))
+            .or_default()
+            .observe(duration);
+    }
+
+    fn record_compression(&self, store: &str, original: usize, stored: usize) {
+        let mut collected = self.0.lock().expect("metrics poisoned");
+        let sizes = collected.compression.entry(store.to_owned()).or_default();
+
+        sizes.values += 1;
+        sizes.compressed += u64::from(stored < original);
+        sizes.original_bytes += original as u64;
+        sizes.stored_bytes += stored as u64;
+    }
+
+    fn record_decode_failure(&self, store: &str) {
+        *self
+            .0
+            .lock()
+            .expect("metrics poisoned")
+            .decode_failures
+            .entry(store.to_owned())
+            .or_default() += 1;
+    }
+
+    fn record_event(&self, event: &str, duration: Duration) {
+        self.0
+            .lock()
+            .expect("metrics poisoned")
+            .events
+            .entry(event.to_owned())
+            .or_default()
+            .observe(duration);
+    }
+
+    fn record_pool_wait(&self, store: &str, duration: Duration) {
+        self.0
+            .lock()
+            .expect("metrics poisoned")
+            .pool_waits
+            .entry(store.to_owned())
+            .or_default()
+            .observe(duration);
+    }
+}
+
+impl InRedisCache {
+    /// Set the [`MetricsRecorder`] receiving the metrics of the cache and
+    /// all of its stores.
+    pub fn set_metrics_recorder(&mut self, recorder: Arc<dyn MetricsRecorder>) {
+        let metrics = Metrics::new(recorder);
+
+        self.metrics = metrics.clone();
+
+        self.channels_guild.metrics = metrics.clone();
+        self.channels_private.metrics = metrics.clone();
+        self.channel_deleted_messages.metrics = metrics.clone();
+        self.channel_messages.metrics = metrics.clone();
+        self.emojis.metrics = metrics.clone();
+        self.groups.metrics = metrics.clone();
+        self.guilds.metrics = metrics.clone();
+        self.guild_channels.metrics = metrics.clone();
+        self.guild_emojis.metrics = metrics.clone();
+        self.guild_integrations.metrics = metrics.clone();
+        self.guild_members.metrics = metrics.clone();
+        self.guild_presences.metrics = metrics.clone();
+        self.guild_presence_counts.metrics = metrics.clone();
+        self.guild_roles.metrics = metrics.clone();
+        self.guild_stage_instances.metrics = metrics.clone();
+        self.guild_stickers.metrics = metrics.clone();
+        self.integrations.metrics = metrics.clone();
+        self.members.metrics = metrics.clone();
+        self.messages.metrics = metrics.clone();
+        self.message_reactions.metrics = metrics.clone();
+        self.message_revisions.metrics = metrics.clone();
+        self.presences.metrics = metrics.clone();
+        self.reaction_users.metrics = metrics.clone();
+        self.roles.metrics = metrics.clone();
+        self.stage_instances.metrics = metrics.clone();
+        self.stickers.metrics = metrics.clone();
+        self.unavailable_guilds.metrics = metrics.clone();
+        self.users.metrics = metrics.clone();
+        self.user_guilds.metrics = metrics.clone();
+        self.user_messages.metrics = metrics.clone();
+        self.user_reactions.metrics = metrics.clone();
+        self.user_private_channels.metrics = metrics.clone();
+        self.voice_state_channels.metrics = metrics.clone();
+        self.voice_state_guilds.metrics = metrics.clone();
+        self.voice_states.metrics = metrics;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let recorder = PrometheusRecorder::new();
+        recorder.record_operation("users", "hget", Duration::from_micros(300));
+        recorder.record_operation("users", "hget", Duration::from_secs(2));
+        recorder.record_event("GuildCreate", Duration::from_millis(3));
+        recorder.record_decode_failure("say \"hi\"\\\n");
+
+        let rendered = recorder.render();
+        let lines = rendered.lines().collect::<Vec<_>>();
+
+        for line in [
+            "# TYPE cache_operation_duration_seconds histogram",
+            "cache_operation_duration_seconds_bucket{store=\"users\",operation=\"hget\",le=\"0.0001\"} 0",
+            "cache_operation_duration_seconds_bucket{store=\"users\",operation=\"hget\",le=\"0.0005\"} 1",
+            "cache_operation_duration_seconds_bucket{store=\"users\",operation=\"hget\",le=\"1\"} 1",
+            "cache_operation_duration_seconds_bucket{store=\"users\",operation=\"hget\",le=\"+Inf\"} 2",
+            "cache_operation_duration_seconds_sum{store=\"users\",operation=\"hget\"} 2.0003",
+            "cache_operation_duration_seconds_count{store=\"users\",operation=\"hget\"} 2",
+            "cache_event_duration_seconds_count{event=\"GuildCreate\"} 1",
+            "# TYPE cache_decode_failures_total counter",
+            "cache_decode_failures_total{store=\"say \\\"hi\\\"\\\\\\n\"} 1",
+        ] {
+            assert!(lines.contains(&line), "missing {:?} in\n{}", line, rendered);
+        }
+
+        // Every sample belongs to a metric with HELP and TYPE.
+        for line in lines.iter().filter(|line| !line.starts_with('#')) {
+            let name = line.split(['{', ' ']).next().unwrap();
+            let family = ["_bucket", "_sum", "_count"]
+                .iter()
+                .find_map(|suffix| name.strip_suffix(suffix))
+                .filter(|family| rendered.contains(&format!("# TYPE {} histogram", family)))
+                .unwrap_or(name);
+
+            assert!(
+                rendered.contains(&format!("# HELP {} ", family))
+                    && rendered.contains(&format!("# TYPE {} ", family)),
+                "no HELP or TYPE for {:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_render_compression() {
+        let recorder = PrometheusRecorder::new();
+        recorder.record_compression("messages", 1000, 250);
+        recorder.record_compression("messages", 100, 100);
+
+        let rendered = recorder.render();
+
+        for line in [
+            "cache_encoded_values_total{store=\"messages\"} 2",
+            "cache_compressed_values_total{store=\"messages\"} 1",
+            "cache_encoded_bytes_total{store=\"messages\"} 1100",
+            "cache_stored_bytes_total{store=\"messages\"} 350",
+        ] {
+            assert!(
+                rendered.lines().any(|rendered| rendered == line),
+                "missing {:?} in\n{}",
+                line,
+                rendered
+            );
+        }
+
+        assert_eq!(
+            recorder.compression_ratios(),
+            BTreeMap::from([("messages".to_owned(), 1100.0 / 350.0)])
+        );
+    }
+}
@@ -13,6 +13,8 @@ use twilight_model::{
     id::{ChannelId, GuildId, MessageId, RoleId, UserId, WebhookId},
 };
 
+use crate::crypt::Sealed;
+
 /// Represents a cached [`Message`].
 ///
 /// [`Message`]: twilight_model::channel::Message
@@ -42,6 +44,10 @@ pub struct CachedMessage {
     pub(crate) timestamp: Timestamp,
     pub(crate) tts: bool,
     webhook_id: Option<WebhookId>,
+    /// Encrypted content, attachments and embeds, which are empty while it
+    /// is set.
+    #[serde(default)]
+    pub(crate) sealed: Option<Sealed>,
 }
 
 impl CachedMessage {
@@ -195,6 +201,7 @@ impl From<Message> for CachedMessage {
             timestamp: msg.timestamp,
             tts: msg.tts,
             webhook_id: msg.webhook_id,
+            sealed: None,
         }
     }
 }
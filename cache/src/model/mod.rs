@@ -9,8 +9,13 @@ mod sticker;
 mod voice_state;
 
 pub use self::{
-    emoji::CachedEmoji, guild::CachedGuild, member::CachedMember, message::CachedMessage,
-    presence::CachedPresence, sticker::CachedSticker, voice_state::CachedVoiceState,
+    emoji::CachedEmoji,
+    guild::CachedGuild,
+    member::CachedMember,
+    message::CachedMessage,
+    presence::{CachedPresence, PresenceCounts},
+    sticker::CachedSticker,
+    voice_state::CachedVoiceState,
 };
 
 #[cfg(tests)]
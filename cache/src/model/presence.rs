@@ -79,3 +79,110 @@ const fn presence_user_id(user: &UserOrId) -> UserId {
         UserOrId::UserId { id } => *id,
     }
 }
+
+/// Number of members of a guild with each status.
+///
+/// Members who are invisible count as offline, like Discord shows them to
+/// everyone else.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PresenceCounts {
+    pub(crate) online: u64,
+    pub(crate) idle: u64,
+    pub(crate) dnd: u64,
+    pub(crate) offline: u64,
+}
+
+impl PresenceCounts {
+    /// Number of members who are online.
+    pub const fn online(&self) -> u64 {
+        self.online
+    }
+
+    /// Number of members who are idle.
+    pub const fn idle(&self) -> u64 {
+        self.idle
+    }
+
+    /// Number of members who don't want to be disturbed.
+    pub const fn dnd(&self) -> u64 {
+        self.dnd
+    }
+
+    /// Number of members who are offline or invisible.
+    pub const fn offline(&self) -> u64 {
+        self.offline
+    }
+
+    /// Move a member from the count of their old status to the one of their
+    /// new status, `None` if they had or have no presence.
+    pub(crate) fn change(&mut self, old: Option<Status>, new: Option<Status>) {
+        if let Some(old) = old {
+            let count = self.count_mut(old);
+            *count = count.saturating_sub(1);
+        }
+
+        if let Some(new) = new {
+            *self.count_mut(new) += 1;
+        }
+    }
+
+    fn count_mut(&mut self, status: Status) -> &mut u64 {
+        match status {
+            Status::Online => &mut self.online,
+            Status::Idle => &mut self.idle,
+            Status::DoNotDisturb => &mut self.dnd,
+            Status::Invisible | Status::Offline => &mut self.offline,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PresenceCounts;
+    use twilight_model::gateway::presence::Status;
+
+    #[test]
+    fn test_change_presence_counts() {
+        let mut counts = PresenceCounts::default();
+
+        counts.change(None, Some(Status::Online));
+        counts.change(None, Some(Status::Online));
+        counts.change(None, Some(Status::Invisible));
+        assert_eq!((counts.online(), counts.offline()), (2, 1));
+
+        counts.change(Some(Status::Online), Some(Status::Idle));
+        counts.change(Some(Status::Online), Some(Status::DoNotDisturb));
+        assert_eq!(
+            counts,
+            PresenceCounts {
+                online: 0,
+                idle: 1,
+                dnd: 1,
+                offline: 1,
+            }
+        );
+
+        counts.change(Some(Status::Idle), Some(Status::Idle));
+        assert_eq!(counts.idle(), 1);
+
+        counts.change(Some(Status::Offline), None);
+        counts.change(Some(Status::DoNotDisturb), None);
+        assert_eq!((counts.dnd(), counts.offline()), (0, 0));
+    }
+
+    #[test]
+    fn test_change_presence_counts_saturates() {
+        let mut counts = PresenceCounts::default();
+
+        counts.change(Some(Status::Online), Some(Status::Idle));
+        counts.change(Some(Status::Offline), None);
+
+        assert_eq!(
+            counts,
+            PresenceCounts {
+                idle: 1,
+                ..PresenceCounts::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,267 @@
+//! Erasure of everything cached about a user.
+
+use std::collections::HashSet;
+
+use futures::{pin_mut, TryStreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+use twilight_model::id::{GuildId, MessageId, UserId};
+
+use crate::{replica, CacheError, InRedisCache, RedisHashMapCache};
+
+/// What [`InRedisCache::purge_user`] removed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PurgeReport {
+    /// Whether the user itself was cached.
+    pub user: bool,
+    /// Number of guild members.
+    pub members: usize,
+    /// Number of presences.
+    pub presences: usize,
+    /// Number of voice states.
+    pub voice_states: usize,
+    /// Number of private channels with the user.
+    pub private_channels: usize,
+    /// Number of messages authored by the user.
+    pub messages: usize,
+    /// Number of archived revisions of their messages.
+    pub message_revisions: usize,
+    /// Number of their messages in the deleted message archives.
+    pub deleted_messages: usize,
+    /// Number of reactions attributed to the user.
+    pub reactions: usize,
+}
+
+impl InRedisCache {
+    /// Remove everything cached about a user, returning what was removed.
+    ///
+    /// This removes the user, their members, presences and voice states in
+    /// every guild, their private channels and the messages they authored,
+    /// including archived revisions, deleted messages and who reacted to them.
+    ///
+    /// Their reactions are no longer attributed to them, but are still
+    /// counted on the messages. Events about the user which are applied while
+    /// purging may cache them again.
+    ///
+    /// Presences and the members of interactions don't link the user to their
+    /// guild, so all cached members, presences and voice states are scanned
+    /// for the user.
+    ///
+    /// Recorded events are not changed, see the [`EventRecorder`] for what
+    /// they keep about the user.
+    ///
+    /// [`EventRecorder`]: crate::EventRecorder
+    pub async fn purge_user(&self, user_id: UserId) -> Result<PurgeReport, CacheError> {
+        // Read what to remove from the primary, replicas may miss some of it.
+        replica::writing(self.purge(user_id)).await
+    }
+
+    async fn purge(&self, user_id: UserId) -> Result<PurgeReport, CacheError> {
+        let id = user_id.get();
+        let mut report = PurgeReport {
+            user: self.users.includes(id).await,
+            ..PurgeReport::default()
+        };
+
+        let mut guild_ids = HashSet::new();
+
+        {
+            let linked = self.user_guilds.iter(id);
+            pin_mut!(linked);
+
+            while let Some(guild_id) = linked.try_next().await? {
+                guild_ids.insert(guild_id);
+            }
+        }
+
+        scan_guilds(&self.members, id, &mut guild_ids).await?;
+        scan_guilds(&self.presences, id, &mut guild_ids).await?;
+        scan_guilds(&self.voice_states, id, &mut guild_ids).await?;
+
+        for guild_id in guild_ids {
+            let guild_id = match GuildId::new(guild_id) {
+                Some(guild_id) => guild_id,
+                None => continue,
+            };
+            let key = (guild_id.get(), id);
+
+            report.members += usize::from(self.members.includes(key).await);
+            report.presences += usize::from(self.presences.includes(key).await);
+            report.voice_states += usize::from(self.voice_states.includes(key).await);
+
+            self.remove_member(guild_id, user_id).await;
+        }
+
+        let channel_ids = self.user_private_channels.iter(id);
+        pin_mut!(channel_ids);
+
+        while let Some(channel_id) = channel_ids.try_next().await? {
+            if let Some(channel) = self.channels_private.get(channel_id).await {
+                self.delete_private_channel(&channel).await;
+                report.private_channels += 1;
+            }
+        }
+
+        let mut message_ids = Vec::new();
+
+        let keys = self.user_messages.iter(id);
+        pin_mut!(keys);
+
+        while let Some(key) = keys.try_next().await? {
+            let message_id = key.1;
+            message_ids.push(message_id);
+
+            if let Some(message) = self.messages.get(key).await {
+                self.channel_messages
+                    .remove(message.channel_id().get(), message_id)
+                    .await?;
+            }
+
+            if self.messages.delete(key).await {
+                report.messages += 1;
+            }
+
+            report.message_revisions += self.message_revisions.size(message_id).await?;
+            self.message_revisions.delete(message_id).await?;
+        }
+
+        let mut deleted_ids = Vec::new();
+
+        report.deleted_messages = self
+            .channel_deleted_messages
+            .retain_all(|message| {
+                if message.author() != user_id {
+                    return true;
+                }
+
+                deleted_ids.push(message.id().get());
+
+                false
+            })
+            .await?;
+
+        for message_id in deleted_ids {
+            report.message_revisions += self.message_revisions.size(message_id).await?;
+            self.message_revisions.delete(message_id).await?;
+        }
+
+        let keys = self.user_reactions.iter(id);
+        pin_mut!(keys);
+
+        while let Some(key) = keys.try_next().await? {
+            if self.remove_reaction_user(key, id).await? {
+                report.reactions += 1;
+            }
+        }
+
+        // Also who else reacted to their messages.
+        for message_id in message_ids.into_iter().filter_map(MessageId::new) {
+            self.clear_reaction_users(message_id, None).await?;
+        }
+
+        self.user_guilds.delete(id).await?;
+        self.user_messages.delete(id).await?;
+        self.user_reactions.delete(id).await?;
+        self.user_private_channels.delete(id).await?;
+        self.users.delete(id).await;
+
+        Ok(report)
+    }
+}
+
+/// Add the guilds in which `map` has an entry of a user to `guild_ids`.
+async fn scan_guilds<V>(
+    map: &RedisHashMapCache<(u64, u64), V>,
+    user_id: u64,
+    guild_ids: &mut HashSet<u64>,
+) -> Result<(), CacheError>
+where
+    V: DeserializeOwned + Serialize,
+{
+    let keys = map.keys();
+    pin_mut!(keys);
+
+    while let Some((guild_id, id)) = keys.try_next().await? {
+        if id == user_id {
+            guild_ids.insert(guild_id);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, MemberBuilder, MessageBuilder, PresenceBuilder};
+
+    #[tokio::test]
+    #[ignore = "needs the Redis server from docker-compose.yml"]
+    async fn test_purge_user() {
+        let cache = InRedisCache::new();
+        let member_guild = testing::guild_id(4_000_001);
+        let presence_guild = testing::guild_id(4_000_002);
+        let interaction_guild = testing::guild_id(4_000_003);
+        let channel_id = testing::channel_id(4_000_004);
+        let message_id = testing::message_id(4_000_005);
+        let user_id = testing::user_id(4_000_006);
+        let other_id = testing::user_id(4_000_007);
+
+        cache
+            .update(&MemberBuilder::new(member_guild, user_id).add())
+            .await;
+        cache
+            .update(&MemberBuilder::new(member_guild, other_id).add())
+            .await;
+        cache
+            .update(&MessageBuilder::new(member_guild, channel_id, message_id, user_id).create())
+            .await;
+
+        // Neither of them links the user to their guild.
+        cache
+            .update(&PresenceBuilder::new(presence_guild, user_id).update())
+            .await;
+        cache
+            .cache_borrowed_interaction_member(
+                interaction_guild,
+                &MemberBuilder::new(interaction_guild, user_id).interaction(),
+            )
+            .await;
+
+        let report = cache.purge_user(user_id).await.unwrap();
+
+        assert!(report.user);
+        assert_eq!(report.members, 2);
+        assert_eq!(report.presences, 1);
+        assert_eq!(report.messages, 1);
+
+        for guild_id in [member_guild, presence_guild, interaction_guild] {
+            let key = (guild_id.get(), user_id.get());
+
+            assert!(!cache.members.includes(key).await);
+            assert!(!cache.presences.includes(key).await);
+        }
+        assert!(cache
+            .message(Some(member_guild), message_id)
+            .await
+            .is_none());
+        assert!(!cache.users.includes(user_id.get()).await);
+
+        // Nobody else is touched.
+        assert!(
+            cache
+                .members
+                .includes((member_guild.get(), other_id.get()))
+                .await
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the Redis server from docker-compose.yml"]
+    async fn test_purge_unknown_user() {
+        let cache = InRedisCache::new();
+
+        let report = cache.purge_user(testing::user_id(4_000_011)).await.unwrap();
+
+        assert_eq!(report, PurgeReport::default());
+    }
+}
@@ -0,0 +1,126 @@
+//! Quarantine for stored values which fail to decode.
+//!
+//! A value which can't be decoded, e.g. because it was written by an older
+//! version of a model, is moved out of its store into a list next to it, so
+//! it is not read again and can be inspected later.
+
+use std::fmt::Display;
+
+use log::{error, warn};
+use mobc::Connection;
+use serde::{Deserialize, Serialize};
+
+use crate::{connection::RedisManager, metrics::Metrics, CacheError};
+
+/// Number of entries kept in the quarantine of a store, older ones are
+/// dropped.
+pub const QUARANTINE_SIZE: usize = 1000;
+
+/// A stored value which failed to decode.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct QuarantinedEntry {
+    /// Name of the store the value was in.
+    pub store: String,
+    /// Key the value was stored under.
+    pub key: String,
+    /// Name of the type the value failed to decode as.
+    pub type_name: String,
+    /// Error the value failed to decode with.
+    pub error: String,
+    /// The value as it was stored.
+    pub value: Vec<u8>,
+}
+
+impl QuarantinedEntry {
+    pub(crate) fn new(
+        store: &str,
+        key: String,
+        type_name: &str,
+        error: &dyn Display,
+        value: Vec<u8>,
+    ) -> Self {
+        Self {
+            store: store.to_owned(),
+            key,
+            type_name: type_name.to_owned(),
+            error: error.to_string(),
+            value,
+        }
+    }
+}
+
+pub(crate) fn quarantine_key(store: &str) -> String {
+    format!("{}-quarantine", store)
+}
+
+/// Move a value into the quarantine of its store.
+///
+/// `pipe` removes the value from the store. It is run atomically with adding
+/// the value to the quarantine if `atomic` is set, which it can't be in a
+/// cluster where the quarantine is in another slot than the value.
+pub(crate) async fn quarantine(
+    con: &mut Connection<RedisManager>,
+    metrics: &Metrics,
+    entry: QuarantinedEntry,
+    mut pipe: redis::Pipeline,
+    atomic: bool,
+) {
+    metrics.decode_failure(&entry.store);
+    warn!(
+        "Quarantined {} in {} which failed to decode as {}: {}",
+        entry.key, entry.store, entry.type_name, entry.error
+    );
+
+    let key = quarantine_key(&entry.store);
+
+    let pack = match rmp_serde::to_vec_named(&entry) {
+        Ok(pack) => pack,
+        Err(err) => {
+            error!("Failed to encode quarantined entry: {}", err);
+
+            return;
+        }
+    };
+
+    if !atomic {
+        if let Err(err) = pipe.query_async::<_, ()>(&mut **con).await {
+            error!(
+                "Failed to remove {} from {}: {}",
+                entry.key, entry.store, err
+            );
+        }
+
+        pipe = redis::pipe();
+    }
+
+    pipe.atomic()
+        .lpush(&key, pack)
+        .ignore()
+        .ltrim(&key, 0, QUARANTINE_SIZE as isize - 1)
+        .ignore();
+
+    if let Err(err) = pipe.query_async::<_, ()>(&mut **con).await {
+        error!(
+            "Failed to quarantine {} in {}: {}",
+            entry.key, entry.store, err
+        );
+    }
+}
+
+/// Read the quarantine of a store, newest first.
+pub(crate) async fn quarantined(
+    con: &mut Connection<RedisManager>,
+    store: &str,
+) -> Result<Vec<QuarantinedEntry>, CacheError> {
+    let packs: Vec<Vec<u8>> = redis::cmd("LRANGE")
+        .arg(quarantine_key(store))
+        .arg(0)
+        .arg(-1)
+        .query_async(&mut **con)
+        .await?;
+
+    packs
+        .iter()
+        .map(|pack| Ok(rmp_serde::from_read(&**pack)?))
+        .collect()
+}
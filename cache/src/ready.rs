@@ -0,0 +1,307 @@
+//! Tracking of which guilds are completely cached.
+//!
+//! A guild is ready once its `GuildCreate` has been applied and all of its
+//! members are cached, either because they were all part of the
+//! `GuildCreate` or because every `MemberChunk` of a request for all members
+//! has been applied.
+//!
+//! Requests for specific members can't be told apart from requests for all
+//! members by their chunks, so requests for all members are registered by
+//! their nonce before they are sent.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use tokio::sync::watch;
+use twilight_model::id::GuildId;
+
+use crate::InRedisCache;
+
+#[derive(Debug)]
+struct GuildState {
+    created: bool,
+    /// Whether every member has been received since the `GuildCreate`.
+    members_complete: bool,
+    /// Received chunk indices and chunk count of each unfinished member
+    /// request by nonce.
+    chunks: HashMap<Option<String>, (HashSet<u32>, u32)>,
+    /// Whether each registered member request is for all members, by nonce.
+    requests: HashMap<String, bool>,
+    tx: watch::Sender<bool>,
+}
+
+impl GuildState {
+    fn new() -> Self {
+        Self {
+            created: false,
+            members_complete: false,
+            chunks: HashMap::new(),
+            requests: HashMap::new(),
+            tx: watch::channel(false).0,
+        }
+    }
+
+    fn is_ready(&self) -> bool {
+        self.created && self.members_complete && self.chunks.is_empty()
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Readiness(Mutex<HashMap<GuildId, GuildState>>);
+
+impl Readiness {
+    /// A `GuildCreate` was applied, `members_complete` tells whether it
+    /// contained every member of the guild.
+    pub(crate) fn guild_created(&self, guild_id: GuildId, members_complete: bool) {
+        self.update(guild_id, |state| {
+            state.created = true;
+            state.members_complete = members_complete;
+            state.chunks.clear();
+        });
+    }
+
+    /// A member request with `nonce` is about to be sent, `full` tells
+    /// whether it is for all members.
+    pub(crate) fn request_registered(&self, guild_id: GuildId, nonce: String, full: bool) {
+        self.update(guild_id, |state| {
+            state.requests.insert(nonce, full);
+        });
+    }
+
+    /// A `MemberChunk` was applied.
+    pub(crate) fn chunk_applied(
+        &self,
+        guild_id: GuildId,
+        nonce: Option<String>,
+        chunk_index: u32,
+        chunk_count: u32,
+    ) {
+        self.update(guild_id, |state| {
+            let (received, count) = state
+                .chunks
+                .entry(nonce.clone())
+                .or_insert_with(|| (HashSet::new(), chunk_count));
+
+            received.insert(chunk_index);
+
+            if received.len() as u32 >= *count {
+                state.chunks.remove(&nonce);
+
+                // Only a registered request for all members completes them.
+                let full = nonce.and_then(|nonce| state.requests.remove(&nonce));
+
+                if full == Some(true) {
+                    state.members_complete = true;
+                }
+            }
+        });
+    }
+
+    /// The guild was removed or became unavailable.
+    pub(crate) fn guild_removed(&self, guild_id: GuildId) {
+        self.update(guild_id, |state| {
+            state.created = false;
+            state.members_complete = false;
+            state.chunks.clear();
+            state.requests.clear();
+        });
+    }
+
+    fn is_ready(&self, guild_id: GuildId) -> bool {
+        let guilds = self.0.lock().expect("readiness poisoned");
+
+        matches!(guilds.get(&guild_id), Some(state) if state.is_ready())
+    }
+
+    async fn wait(&self, guild_id: GuildId) {
+        let mut rx = {
+            let mut guilds = self.0.lock().expect("readiness poisoned");
+            let state = guilds.entry(guild_id).or_insert_with(GuildState::new);
+
+            if state.is_ready() {
+                return;
+            }
+
+            state.tx.subscribe()
+        };
+
+        while !*rx.borrow() {
+            // The sender lives as long as the cache.
+            if rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    fn update(&self, guild_id: GuildId, f: impl FnOnce(&mut GuildState)) {
+        let mut guilds = self.0.lock().expect("readiness poisoned");
+        let state = guilds.entry(guild_id).or_insert_with(GuildState::new);
+
+        f(state);
+
+        // Also kept while nobody is waiting, for the next subscriber.
+        state.tx.send_replace(state.is_ready());
+    }
+}
+
+impl InRedisCache {
+    /// Whether a guild and all of its members are cached.
+    ///
+    /// See [`wait_until_ready`] for when a guild is considered ready.
+    ///
+    /// [`wait_until_ready`]: Self::wait_until_ready
+    pub fn is_guild_ready(&self, guild_id: GuildId) -> bool {
+        self.readiness.is_ready(guild_id)
+    }
+
+    /// Register a request for members of a guild by its nonce, before it is
+    /// sent.
+    ///
+    /// `full` tells whether the request is for all members. Only the chunks
+    /// of such a registered request make the guild ready, see
+    /// [`wait_until_ready`].
+    ///
+    /// ```ignore
+    /// cache.register_member_request(guild_id, "all", true);
+    ///
+    /// let request = RequestGuildMembers::builder(guild_id)
+    ///     .nonce("all")
+    ///     .query("", None);
+    /// shard.command(&request).await?;
+    /// ```
+    ///
+    /// [`wait_until_ready`]: Self::wait_until_ready
+    pub fn register_member_request(&self, guild_id: GuildId, nonce: impl Into<String>, full: bool) {
+        self.readiness
+            .request_registered(guild_id, nonce.into(), full);
+    }
+
+    /// Wait until a guild and all of its members are cached.
+    ///
+    /// A guild is ready once its `GuildCreate` has been applied and either
+    /// contained every member, or every `MemberChunk` of a request for all
+    /// members has been applied since. Such requests have to be registered
+    /// with [`register_member_request`], the chunks of other requests don't
+    /// contain every member. It stops being ready when it is deleted or
+    /// becomes unavailable, or while the chunks of a new member request are
+    /// still being applied.
+    ///
+    /// Readiness is tracked by the process applying the events, so this only
+    /// resolves for events passed to [`update`] of this cache. Guilds of which
+    /// the members are never requested may never become ready, so combine
+    /// this with a timeout:
+    ///
+    /// ```ignore
+    /// if timeout(Duration::from_secs(10), cache.wait_until_ready(guild_id))
+    ///     .await
+    ///     .is_err()
+    /// {
+    ///     return Err("members are still loading");
+    /// }
+    /// ```
+    ///
+    /// [`register_member_request`]: Self::register_member_request
+    /// [`update`]: Self::update
+    pub async fn wait_until_ready(&self, guild_id: GuildId) {
+        self.readiness.wait(guild_id).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::Readiness;
+    use crate::testing;
+
+    #[test]
+    fn test_guild_create_with_members() {
+        let readiness = Readiness::default();
+        let guild_id = testing::guild_id(1);
+
+        readiness.guild_created(guild_id, true);
+        assert!(readiness.is_ready(guild_id));
+
+        readiness.guild_removed(guild_id);
+        assert!(!readiness.is_ready(guild_id));
+    }
+
+    #[test]
+    fn test_member_request() {
+        let readiness = Readiness::default();
+        let guild_id = testing::guild_id(1);
+
+        readiness.guild_created(guild_id, false);
+        readiness.request_registered(guild_id, "all".to_owned(), true);
+        readiness.chunk_applied(guild_id, Some("all".to_owned()), 1, 2);
+        assert!(!readiness.is_ready(guild_id));
+
+        readiness.chunk_applied(guild_id, Some("all".to_owned()), 0, 2);
+        assert!(readiness.is_ready(guild_id));
+    }
+
+    #[test]
+    fn test_targeted_member_requests() {
+        let readiness = Readiness::default();
+        let guild_id = testing::guild_id(1);
+
+        readiness.guild_created(guild_id, false);
+        readiness.request_registered(guild_id, "user".to_owned(), false);
+        readiness.chunk_applied(guild_id, Some("user".to_owned()), 0, 1);
+        assert!(!readiness.is_ready(guild_id));
+
+        // Requests which were not registered could be for anyone.
+        readiness.chunk_applied(guild_id, None, 0, 2);
+        readiness.chunk_applied(guild_id, None, 1, 2);
+        readiness.chunk_applied(guild_id, Some("other".to_owned()), 0, 1);
+        assert!(!readiness.is_ready(guild_id));
+
+        // The registration is used up by the request it was for.
+        readiness.request_registered(guild_id, "all".to_owned(), true);
+        readiness.chunk_applied(guild_id, Some("all".to_owned()), 0, 1);
+        assert!(readiness.is_ready(guild_id));
+
+        readiness.guild_created(guild_id, false);
+        readiness.chunk_applied(guild_id, Some("all".to_owned()), 0, 1);
+        assert!(!readiness.is_ready(guild_id));
+    }
+
+    #[test]
+    fn test_request_in_flight() {
+        let readiness = Readiness::default();
+        let guild_id = testing::guild_id(1);
+
+        readiness.guild_created(guild_id, true);
+        readiness.chunk_applied(guild_id, Some("user".to_owned()), 0, 2);
+        assert!(!readiness.is_ready(guild_id));
+
+        readiness.chunk_applied(guild_id, Some("user".to_owned()), 1, 2);
+        assert!(readiness.is_ready(guild_id));
+    }
+
+    #[tokio::test]
+    async fn test_wait() {
+        let readiness = Readiness::default();
+        let guild_id = testing::guild_id(1);
+
+        readiness.guild_created(guild_id, false);
+        readiness.request_registered(guild_id, "all".to_owned(), true);
+
+        let wait = readiness.wait(guild_id);
+        tokio::pin!(wait);
+        assert!(timeout(Duration::from_millis(10), &mut wait).await.is_err());
+
+        readiness.chunk_applied(guild_id, Some("all".to_owned()), 0, 1);
+        timeout(Duration::from_secs(1), wait).await.expect("ready");
+
+        // Nobody is waiting while the guild stops being ready.
+        readiness.guild_removed(guild_id);
+
+        let wait = timeout(Duration::from_millis(10), readiness.wait(guild_id));
+        assert!(wait.await.is_err());
+    }
+}
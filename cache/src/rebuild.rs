@@ -0,0 +1,500 @@
+//! Reconstruction of complete twilight models from the cached ones.
+//!
+//! The cache splits models up: a guild is stored without its channels,
+//! members and roles, which are stored on their own, and a message only
+//! keeps the IDs of its author and mentioned users. Rebuilding a model puts
+//! these parts back together, and reports every part which isn't cached
+//! instead of making one up.
+
+use std::fmt::{self, Display, Formatter};
+
+use twilight_model::{
+    channel::{
+        message::{Mention, Message},
+        GuildChannel,
+    },
+    gateway::presence::{Presence, UserOrId},
+    guild::{Emoji, Guild, Member, PartialMember},
+    id::{ChannelId, EmojiId, GuildId, MessageId, RoleId, StageId, UserId},
+    user::UserFlags,
+};
+
+use crate::{
+    config::ResourceType,
+    model::{CachedMember, CachedMessage},
+    CacheError, InRedisCache,
+};
+
+/// Part of a model which isn't cached.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Missing {
+    /// A field of the model the cache doesn't keep.
+    Field(&'static str),
+    Channel(ChannelId),
+    Emoji(EmojiId),
+    Member(UserId),
+    Message(MessageId),
+    Presence(UserId),
+    Role(RoleId),
+    StageInstance(StageId),
+    User(UserId),
+    VoiceState(UserId),
+}
+
+impl Display for Missing {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Field(name) => write!(f, "field {}", name),
+            Self::Channel(id) => write!(f, "channel {}", id),
+            Self::Emoji(id) => write!(f, "emoji {}", id),
+            Self::Member(id) => write!(f, "member {}", id),
+            Self::Message(id) => write!(f, "message {}", id),
+            Self::Presence(id) => write!(f, "presence of user {}", id),
+            Self::Role(id) => write!(f, "role {}", id),
+            Self::StageInstance(id) => write!(f, "stage instance {}", id),
+            Self::User(id) => write!(f, "user {}", id),
+            Self::VoiceState(id) => write!(f, "voice state of user {}", id),
+        }
+    }
+}
+
+/// A model rebuilt from the cache.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rebuilt<T> {
+    /// The model, without the parts listed in `missing`.
+    pub model: T,
+    /// Parts of the model which aren't cached. Missing entities are left out
+    /// of the lists of the model, missing fields are empty or `None`.
+    pub missing: Vec<Missing>,
+}
+
+impl<T> Rebuilt<T> {
+    /// Whether nothing of the model is missing.
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+impl InRedisCache {
+    /// Rebuild a complete [`Guild`] with its channels, emojis, members,
+    /// presences, roles and stage instances.
+    ///
+    /// Returns `None` if the guild isn't cached. Entities the cache links to
+    /// the guild but doesn't have, and lists of resource types the cache is
+    /// configured not to keep, are reported missing. Stickers and the maximum
+    /// number of users in a video channel are always missing, the cache
+    /// doesn't keep them from guild events. The approximate counts are
+    /// `None`, like in guilds sent by the gateway.
+    pub async fn rebuild_guild(
+        &self,
+        guild_id: GuildId,
+    ) -> Result<Option<Rebuilt<Guild>>, CacheError> {
+        let id = guild_id.get();
+
+        let guild = match self.guilds.get(id).await {
+            Some(guild) => guild,
+            None => return Ok(None),
+        };
+
+        let mut missing = vec![
+            Missing::Field("max_video_channel_users"),
+            Missing::Field("stickers"),
+        ];
+
+        for (resource_type, field) in [
+            (ResourceType::CHANNEL, "channels"),
+            (ResourceType::EMOJI, "emojis"),
+            (ResourceType::MEMBER, "members"),
+            (ResourceType::PRESENCE, "presences"),
+            (ResourceType::ROLE, "roles"),
+            (ResourceType::STAGE_INSTANCE, "stage_instances"),
+            (ResourceType::VOICE_STATE, "voice_states"),
+        ] {
+            if !self.wants(resource_type) {
+                missing.push(Missing::Field(field));
+            }
+        }
+
+        let mut channels = Vec::new();
+        let mut threads = Vec::new();
+
+        for channel_id in self.guild_channels.get(id).await? {
+            let channel = match self.channels_guild.get(channel_id).await {
+                Some(channel) => channel.value,
+                None => {
+                    missing.extend(ChannelId::new(channel_id).map(Missing::Channel));
+
+                    continue;
+                }
+            };
+
+            if matches!(
+                channel,
+                GuildChannel::NewsThread(_)
+                    | GuildChannel::PrivateThread(_)
+                    | GuildChannel::PublicThread(_)
+            ) {
+                threads.push(channel);
+            } else {
+                channels.push(channel);
+            }
+        }
+
+        let mut emojis = Vec::new();
+
+        for emoji_id in self.guild_emojis.get(id).await? {
+            let emoji = match self.emojis.get(emoji_id).await {
+                Some(emoji) => emoji.value,
+                None => {
+                    missing.extend(EmojiId::new(emoji_id).map(Missing::Emoji));
+
+                    continue;
+                }
+            };
+
+            let user = match emoji.user_id {
+                Some(user_id) => {
+                    let user = self.users.get(user_id.get()).await;
+
+                    if user.is_none() {
+                        missing.push(Missing::User(user_id));
+                    }
+
+                    user
+                }
+                None => None,
+            };
+
+            emojis.push(Emoji {
+                animated: emoji.animated,
+                available: emoji.available,
+                id: emoji.id,
+                managed: emoji.managed,
+                name: emoji.name,
+                require_colons: emoji.require_colons,
+                roles: emoji.roles,
+                user,
+            });
+        }
+
+        let mut members = Vec::new();
+
+        for user_id in self.guild_members.get(id).await? {
+            let member = match self.members.get((id, user_id)).await {
+                Some(member) => self.assemble_member(member).await,
+                None => {
+                    missing.extend(UserId::new(user_id).map(Missing::Member));
+
+                    continue;
+                }
+            };
+
+            match member {
+                Ok(member) => members.push(member),
+                Err(part) => missing.push(part),
+            }
+        }
+
+        let mut presences = Vec::new();
+
+        for user_id in self.guild_presences.get(id).await? {
+            match self.presences.get((id, user_id)).await {
+                Some(presence) => presences.push(Presence {
+                    activities: presence.activities,
+                    client_status: presence.client_status,
+                    guild_id: presence.guild_id,
+                    status: presence.status,
+                    user: UserOrId::UserId {
+                        id: presence.user_id,
+                    },
+                }),
+                None => missing.extend(UserId::new(user_id).map(Missing::Presence)),
+            }
+        }
+
+        let mut roles = Vec::new();
+
+        for role_id in self.guild_roles.get(id).await? {
+            match self.roles.get(role_id).await {
+                Some(role) => roles.push(role.value),
+                None => missing.extend(RoleId::new(role_id).map(Missing::Role)),
+            }
+        }
+
+        let mut stage_instances = Vec::new();
+
+        for stage_id in self.guild_stage_instances.get(id).await? {
+            match self.stage_instances.get(stage_id).await {
+                Some(stage_instance) => stage_instances.push(stage_instance),
+                None => missing.extend(StageId::new(stage_id).map(Missing::StageInstance)),
+            }
+        }
+
+        let mut voice_states = Vec::new();
+
+        for user_id in self.voice_state_guilds.get(id).await? {
+            match self.voice_states.get((id, user_id)).await {
+                Some(voice_state) => voice_states.push(voice_state),
+                None => missing.extend(UserId::new(user_id).map(Missing::VoiceState)),
+            }
+        }
+
+        let model = Guild {
+            afk_channel_id: guild.afk_channel_id,
+            afk_timeout: guild.afk_timeout,
+            application_id: guild.application_id,
+            approximate_member_count: None,
+            approximate_presence_count: None,
+            banner: guild.banner,
+            channels,
+            default_message_notifications: guild.default_message_notifications,
+            description: guild.description,
+            discovery_splash: guild.discovery_splash,
+            emojis,
+            explicit_content_filter: guild.explicit_content_filter,
+            features: guild.features,
+            icon: guild.icon,
+            id: guild.id,
+            joined_at: guild.joined_at,
+            large: guild.large,
+            max_members: guild.max_members,
+            max_presences: guild.max_presences,
+            max_video_channel_users: None,
+            member_count: guild.member_count,
+            members,
+            mfa_level: guild.mfa_level,
+            name: guild.name,
+            nsfw_level: guild.nsfw_level,
+            owner: guild.owner,
+            owner_id: guild.owner_id,
+            permissions: guild.permissions,
+            preferred_locale: guild.preferred_locale,
+            premium_subscription_count: guild.premium_subscription_count,
+            premium_tier: guild.premium_tier,
+            presences,
+            roles,
+            rules_channel_id: guild.rules_channel_id,
+            splash: guild.splash,
+            stage_instances,
+            stickers: Vec::new(),
+            system_channel_flags: guild.system_channel_flags,
+            system_channel_id: guild.system_channel_id,
+            threads,
+            unavailable: guild.unavailable,
+            vanity_url_code: guild.vanity_url_code,
+            verification_level: guild.verification_level,
+            voice_states,
+            widget_channel_id: guild.widget_channel_id,
+            widget_enabled: guild.widget_enabled,
+        };
+
+        Ok(Some(Rebuilt { model, missing }))
+    }
+
+    /// Rebuild a complete [`Member`] with their user.
+    ///
+    /// Returns `None` if the member isn't cached, and
+    /// [`CacheError::Incomplete`] if their user isn't cached or whether they
+    /// are deafened or muted isn't known, which is the case for members only
+    /// seen in interactions.
+    pub async fn rebuild_member(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+    ) -> Result<Option<Member>, CacheError> {
+        match self.members.get((guild_id.get(), user_id.get())).await {
+            Some(member) => self
+                .assemble_member(member)
+                .await
+                .map(Some)
+                .map_err(CacheError::Incomplete),
+            None => Ok(None),
+        }
+    }
+
+    /// Rebuild a complete [`Message`] with its author, mentioned users and
+    /// the message it replies to.
+    ///
+    /// Returns `None` if the message isn't cached, and
+    /// [`CacheError::Incomplete`] if its author isn't cached. Mentioned users
+    /// and members and the replied to message which aren't cached are
+    /// reported missing. The application ID, components, interaction and
+    /// thread of messages are always missing, the cache doesn't keep them.
+    pub async fn rebuild_message(
+        &self,
+        guild_id: Option<GuildId>,
+        message_id: MessageId,
+    ) -> Result<Option<Rebuilt<Message>>, CacheError> {
+        let message = match self.message(guild_id, message_id).await {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+
+        let mut missing = vec![
+            Missing::Field("application_id"),
+            Missing::Field("components"),
+            Missing::Field("interaction"),
+            Missing::Field("thread"),
+        ];
+
+        let referenced = match message.reference() {
+            Some(reference) => reference
+                .message_id
+                .map(|id| (reference.guild_id.or_else(|| message.guild_id()), id)),
+            None => None,
+        };
+
+        let mut model = self
+            .assemble_message(message, &mut missing)
+            .await
+            .map_err(CacheError::Incomplete)?;
+
+        if let Some((guild_id, referenced_id)) = referenced {
+            let referenced = match self.message(guild_id, referenced_id).await {
+                Some(referenced) => self.assemble_message(referenced, &mut missing).await.ok(),
+                None => None,
+            };
+
+            match referenced {
+                Some(referenced) => model.referenced_message = Some(Box::new(referenced)),
+                None => missing.push(Missing::Message(referenced_id)),
+            }
+        }
+
+        Ok(Some(Rebuilt { model, missing }))
+    }
+
+    async fn assemble_member(&self, member: CachedMember) -> Result<Member, Missing> {
+        let user = self
+            .users
+            .get(member.user_id.get())
+            .await
+            .ok_or(Missing::User(member.user_id))?;
+        let deaf = member.deaf.ok_or(Missing::Field("deaf"))?;
+        let mute = member.mute.ok_or(Missing::Field("mute"))?;
+
+        Ok(Member {
+            deaf,
+            guild_id: member.guild_id,
+            joined_at: member.joined_at,
+            mute,
+            nick: member.nick,
+            pending: member.pending,
+            premium_since: member.premium_since,
+            roles: member.roles,
+            user,
+        })
+    }
+
+    /// Rebuild a message without the message it replies to, adding the
+    /// mentions which aren't cached to `missing`.
+    async fn assemble_message(
+        &self,
+        message: CachedMessage,
+        missing: &mut Vec<Missing>,
+    ) -> Result<Message, Missing> {
+        let author = self
+            .users
+            .get(message.author().get())
+            .await
+            .ok_or(Missing::User(message.author()))?;
+
+        let mut mentions = Vec::with_capacity(message.mentions().len());
+
+        for &user_id in message.mentions() {
+            let user = match self.users.get(user_id.get()).await {
+                Some(user) => user,
+                None => {
+                    missing.push(Missing::User(user_id));
+
+                    continue;
+                }
+            };
+
+            let member = match message.guild_id() {
+                Some(guild_id) => {
+                    let member = self
+                        .members
+                        .get((guild_id.get(), user_id.get()))
+                        .await
+                        .and_then(partial_member);
+
+                    if member.is_none() {
+                        missing.push(Missing::Member(user_id));
+                    }
+
+                    member
+                }
+                None => None,
+            };
+
+            let public_flags = match user.public_flags {
+                Some(public_flags) => public_flags,
+                // The user is only partially cached, which `Field` can't
+                // tell apart from the other mentions.
+                None => {
+                    missing.push(Missing::User(user_id));
+
+                    UserFlags::empty()
+                }
+            };
+
+            mentions.push(Mention {
+                avatar: user.avatar,
+                bot: user.bot,
+                discriminator: user.discriminator,
+                id: user.id,
+                member,
+                name: user.name,
+                public_flags,
+            });
+        }
+
+        Ok(Message {
+            activity: message.activity().cloned(),
+            application: message.application().cloned(),
+            application_id: None,
+            attachments: message.attachments().to_vec(),
+            author,
+            channel_id: message.channel_id(),
+            components: Vec::new(),
+            content: message.content().to_owned(),
+            edited_timestamp: message.edited_timestamp(),
+            embeds: message.embeds().to_vec(),
+            flags: message.flags(),
+            guild_id: message.guild_id(),
+            id: message.id(),
+            interaction: None,
+            kind: message.kind(),
+            member: message.member().cloned(),
+            mention_channels: message.mention_channels().to_vec(),
+            mention_everyone: message.mention_everyone(),
+            mention_roles: message.mention_roles().to_vec(),
+            mentions,
+            pinned: message.pinned(),
+            reactions: message.reactions().to_vec(),
+            reference: message.reference().cloned(),
+            referenced_message: None,
+            sticker_items: message.sticker_items().to_vec(),
+            thread: None,
+            timestamp: message.timestamp(),
+            tts: message.tts(),
+            webhook_id: message.webhook_id(),
+        })
+    }
+}
+
+/// A cached member as it is sent with the users mentioned in messages,
+/// `None` if whether they are deafened or muted isn't known.
+fn partial_member(member: CachedMember) -> Option<PartialMember> {
+    Some(PartialMember {
+        deaf: member.deaf?,
+        joined_at: member.joined_at,
+        mute: member.mute?,
+        nick: member.nick,
+        permissions: None,
+        premium_since: member.premium_since,
+        roles: member.roles,
+        user: None,
+    })
+}
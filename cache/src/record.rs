@@ -0,0 +1,518 @@
+//! Recording of gateway events and replaying them into a cache.
+//!
+//! An [`EventRecorder`] appends dispatch events, with the shard they were
+//! received on, to a capped Redis stream or a file of JSON lines. A recorded
+//! session can be read back with [`read_stream`] or [`read_file`] and fed
+//! through [`InRedisCache::replay`], to reproduce a bug or to benchmark the
+//! cache against real traffic.
+//!
+//! When [message encryption] is enabled, the content, attachments and embeds
+//! of recorded messages are sealed with it, like cached messages are, and
+//! [`InRedisCache::replay`] decrypts them again. Recordings are not touched
+//! by [`InRedisCache::purge_user`]: sealed content can't be read without the
+//! keys, but everything else about a message, like its author and mentions,
+//! stays in the recording until it is trimmed or deleted.
+//!
+//! [message encryption]: crate::MessageEncryption
+
+use std::{
+    convert::TryFrom,
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures::{future, pin_mut, stream, Stream, TryStreamExt};
+use log::error;
+use mobc::Pool;
+use redis::{self, cmd, FromRedisValue};
+use serde::{de::DeserializeSeed, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::Mutex,
+};
+use twilight_model::{
+    gateway::event::{DispatchEvent, DispatchEventWithTypeDeserializer, Event},
+    id::MessageId,
+};
+
+use crate::{
+    connection::RedisManager,
+    crypt::{self, MessageEncryption, Sealed},
+    CacheError, InRedisCache, SCAN_COUNT,
+};
+
+/// Field of a stream entry the event is stored in.
+const STREAM_FIELD: &str = "event";
+
+/// Fields of a message payload which are sealed when encryption is enabled.
+const SEALED_FIELDS: [&str; 3] = ["attachments", "content", "embeds"];
+
+/// Field of a message payload its sealed fields are stored in.
+const SEALED_FIELD: &str = "sealed";
+
+/// A dispatch event as it was recorded.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RecordedEvent {
+    /// ID of the shard the event was received on.
+    pub shard_id: u64,
+    /// Milliseconds since the Unix epoch when the event was recorded.
+    pub timestamp: u64,
+    /// Name of the event type, like `MESSAGE_CREATE`.
+    pub kind: String,
+    /// The payload of the event.
+    pub payload: serde_json::Value,
+}
+
+impl RecordedEvent {
+    /// Record an event, returning `None` for events which are not sent by
+    /// Discord, like `ShardConnected`.
+    pub fn new(shard_id: u64, event: &Event) -> Result<Option<Self>, CacheError> {
+        let dispatch = match DispatchEvent::try_from(event.clone()) {
+            Ok(dispatch) => dispatch,
+            Err(_) => return Ok(None),
+        };
+
+        let kind = match dispatch.kind().name() {
+            Some(kind) => kind.to_owned(),
+            None => return Ok(None),
+        };
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+
+        Ok(Some(Self {
+            shard_id,
+            timestamp,
+            kind,
+            payload: serde_json::to_value(&dispatch)?,
+        }))
+    }
+
+    /// Turn the recording back into the event.
+    ///
+    /// Sealed message content is left empty, [`InRedisCache::replay`]
+    /// decrypts it first.
+    pub fn event(&self) -> Result<Event, CacheError> {
+        let dispatch =
+            DispatchEventWithTypeDeserializer::new(&self.kind).deserialize(self.payload.clone())?;
+
+        Ok(Event::from(Box::new(dispatch)))
+    }
+
+    /// ID of the message in the payload, if it is a message event.
+    fn message_id(&self) -> Option<MessageId> {
+        if self.kind != "MESSAGE_CREATE" && self.kind != "MESSAGE_UPDATE" {
+            return None;
+        }
+
+        MessageId::new(self.payload.get("id")?.as_str()?.parse().ok()?)
+    }
+
+    /// Encrypt the content, attachments and embeds of a message payload.
+    ///
+    /// They are left empty in created messages, which require them, and
+    /// removed from updates, so they read as unchanged.
+    pub(crate) fn seal(&mut self, encryption: &MessageEncryption) -> Result<(), CacheError> {
+        let message_id = match self.message_id() {
+            Some(message_id) => message_id,
+            None => return Ok(()),
+        };
+        let create = self.kind == "MESSAGE_CREATE";
+
+        let payload = match self.payload.as_object_mut() {
+            Some(payload) if !payload.contains_key(SEALED_FIELD) => payload,
+            _ => return Ok(()),
+        };
+
+        let mut fields = Map::new();
+
+        for field in SEALED_FIELDS {
+            let value = match payload.get_mut(field) {
+                Some(value) if create => match value {
+                    Value::String(_) => std::mem::replace(value, Value::String(String::new())),
+                    Value::Array(_) => std::mem::replace(value, Value::Array(Vec::new())),
+                    _ => continue,
+                },
+                Some(_) => payload.remove(field).unwrap_or_default(),
+                None => continue,
+            };
+
+            fields.insert(field.to_owned(), value);
+        }
+
+        if fields.is_empty() {
+            return Ok(());
+        }
+
+        let sealed = encryption.encrypt(&serde_json::to_vec(&fields)?, &crypt::aad(message_id))?;
+        payload.insert(SEALED_FIELD.to_owned(), serde_json::to_value(&sealed)?);
+
+        Ok(())
+    }
+
+    /// Decrypt the sealed fields of a message payload back into it.
+    pub(crate) fn unseal(
+        &mut self,
+        encryption: Option<&MessageEncryption>,
+    ) -> Result<(), CacheError> {
+        let payload = match self.payload.as_object_mut() {
+            Some(payload) if payload.contains_key(SEALED_FIELD) => payload,
+            _ => return Ok(()),
+        };
+
+        let message_id = payload
+            .get("id")
+            .and_then(Value::as_str)
+            .and_then(|id| MessageId::new(id.parse().ok()?))
+            .ok_or(CacheError::Encryption)?;
+        let encryption = encryption.ok_or(CacheError::Encryption)?;
+
+        let sealed: Sealed = serde_json::from_value(payload[SEALED_FIELD].take())?;
+        let plaintext = encryption.decrypt(&sealed, &crypt::aad(message_id))?;
+        let fields: Map<String, Value> = serde_json::from_slice(&plaintext)?;
+
+        payload.remove(SEALED_FIELD);
+        payload.extend(fields);
+
+        Ok(())
+    }
+}
+
+enum Sink {
+    File(Mutex<File>),
+    Stream {
+        pool: Pool<RedisManager>,
+        key: String,
+        max_len: usize,
+    },
+}
+
+/// Appends events to a Redis stream or a file.
+///
+/// # Examples
+///
+/// ```ignore
+/// let recorder = EventRecorder::stream("redis://127.0.0.1", "events".into(), 1_000_000);
+/// cache.set_event_recorder(Arc::new(recorder));
+///
+/// while let Some((shard_id, event)) = events.next().await {
+///     cache.update_from_shard(shard_id, &event).await;
+/// }
+/// ```
+pub struct EventRecorder(Sink);
+
+impl EventRecorder {
+    /// Record to a Redis stream, keeping about the last `max_len` events.
+    pub fn stream(connection_str: &str, key: String, max_len: usize) -> Self {
+        let manager = RedisManager::new(connection_str);
+        let pool = Pool::builder().max_open(10).build(manager);
+
+        Self(Sink::Stream { pool, key, max_len })
+    }
+
+    /// Record to a file, one JSON object per line, appending if it exists.
+    pub async fn file(path: impl AsRef<Path>) -> Result<Self, CacheError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        Ok(Self(Sink::File(Mutex::new(file))))
+    }
+
+    /// Record an event received on a shard.
+    ///
+    /// Message content is recorded as it is, events recorded through
+    /// [`InRedisCache::update_from_shard`] are sealed with the message
+    /// encryption of the cache.
+    pub async fn record(&self, shard_id: u64, event: &Event) -> Result<(), CacheError> {
+        self.record_sealed(shard_id, event, None).await
+    }
+
+    /// Record an event, sealing message content with `encryption` if set.
+    pub(crate) async fn record_sealed(
+        &self,
+        shard_id: u64,
+        event: &Event,
+        encryption: Option<&MessageEncryption>,
+    ) -> Result<(), CacheError> {
+        let mut recorded = match RecordedEvent::new(shard_id, event)? {
+            Some(recorded) => recorded,
+            None => return Ok(()),
+        };
+
+        if let Some(encryption) = encryption {
+            recorded.seal(encryption)?;
+        }
+
+        let mut line = serde_json::to_vec(&recorded)?;
+
+        match &self.0 {
+            Sink::File(file) => {
+                line.push(b'\n');
+
+                let mut file = file.lock().await;
+                file.write_all(&line).await?;
+                file.flush().await?;
+            }
+            Sink::Stream { pool, key, max_len } => {
+                let mut con = pool.get().await?;
+
+                cmd("XADD")
+                    .arg(key)
+                    .arg("MAXLEN")
+                    .arg("~")
+                    .arg(*max_len)
+                    .arg("*")
+                    .arg(STREAM_FIELD)
+                    .arg(line)
+                    .query_async::<_, String>(&mut *con)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the events recorded to a Redis stream, oldest first.
+pub fn read_stream(
+    connection_str: &str,
+    key: String,
+) -> impl Stream<Item = Result<RecordedEvent, CacheError>> {
+    let manager = RedisManager::new(connection_str);
+    let pool = Pool::builder().max_open(1).build(manager);
+
+    stream::try_unfold(Some("-".to_owned()), move |start| {
+        let pool = pool.clone();
+        let key = key.clone();
+
+        async move {
+            let start = match start {
+                Some(start) => start,
+                None => return Ok(None),
+            };
+
+            let mut con = pool.get().await?;
+
+            // Each entry is its ID followed by a flat list of fields and values.
+            let entries: Vec<Vec<redis::Value>> = cmd("XRANGE")
+                .arg(&key)
+                .arg(&start)
+                .arg("+")
+                .arg("COUNT")
+                .arg(SCAN_COUNT)
+                .query_async(&mut *con)
+                .await?;
+
+            let mut next = None;
+            let mut events = Vec::new();
+
+            for entry in &entries {
+                let (id, fields) = match entry.as_slice() {
+                    [id, fields] => (
+                        String::from_redis_value(id)?,
+                        Vec::<Vec<u8>>::from_redis_value(fields)?,
+                    ),
+                    _ => return Err(CacheError::RedisError),
+                };
+
+                for pair in fields.chunks_exact(2) {
+                    if pair[0] == STREAM_FIELD.as_bytes() {
+                        events.push(serde_json::from_slice(&pair[1]).map_err(CacheError::from));
+                    }
+                }
+
+                // The range is inclusive, continue right after the last entry.
+                if entries.len() == SCAN_COUNT {
+                    next = Some(next_stream_id(&id));
+                }
+            }
+
+            Ok::<_, CacheError>(Some((stream::iter(events), next)))
+        }
+    })
+    .try_flatten()
+}
+
+/// The smallest stream ID after `id`.
+fn next_stream_id(id: &str) -> String {
+    match id.split_once('-') {
+        Some((ms, seq)) => match seq.parse::<u64>() {
+            Ok(seq) => format!("{}-{}", ms, seq + 1),
+            Err(_) => id.to_owned(),
+        },
+        None => id.to_owned(),
+    }
+}
+
+/// Read the events recorded to a file, oldest first.
+pub async fn read_file(
+    path: impl AsRef<Path>,
+) -> Result<impl Stream<Item = Result<RecordedEvent, CacheError>>, CacheError> {
+    let lines = BufReader::new(File::open(path).await?).lines();
+
+    Ok(stream::unfold(lines, |mut lines| async move {
+        match lines.next_line().await {
+            Ok(Some(line)) => Some((Ok(line), lines)),
+            Ok(None) => None,
+            Err(err) => Some((Err(CacheError::from(err)), lines)),
+        }
+    })
+    .try_filter(|line| future::ready(!line.trim().is_empty()))
+    .and_then(|line| future::ready(serde_json::from_str(&line).map_err(CacheError::from))))
+}
+
+impl InRedisCache {
+    /// Set the [`EventRecorder`] events passed to [`update_from_shard`] are
+    /// recorded with.
+    ///
+    /// [`update_from_shard`]: Self::update_from_shard
+    pub fn set_event_recorder(&mut self, recorder: Arc<EventRecorder>) {
+        self.recorder.replace(recorder);
+    }
+
+    /// Record an event received on a shard, if a recorder is set, and update
+    /// the cache with it.
+    ///
+    /// A failure to record is logged and does not stop the update.
+    pub async fn update_from_shard(&self, shard_id: u64, event: &Event) {
+        self.record(shard_id, event).await;
+        self.update(event).await;
+    }
+
+    /// Record an event if a recorder is set, logging failures.
+    pub(crate) async fn record(&self, shard_id: u64, event: &Event) {
+        if let Some(recorder) = &self.recorder {
+            let encryption = self.config.message_encryption();
+
+            if let Err(err) = recorder.record_sealed(shard_id, event, encryption).await {
+                error!("Failed to record event: {}", err);
+            }
+        }
+    }
+
+    /// Apply recorded events to the cache one after another, in the order
+    /// they are read, returning the number of applied events.
+    ///
+    /// Replayed events are not recorded again. Sealed message content is
+    /// decrypted with the message encryption of the cache, replaying it
+    /// without the key it was sealed with fails with
+    /// [`CacheError::Encryption`].
+    pub async fn replay(
+        &self,
+        events: impl Stream<Item = Result<RecordedEvent, CacheError>>,
+    ) -> Result<usize, CacheError> {
+        pin_mut!(events);
+
+        let mut applied = 0;
+
+        while let Some(mut recorded) = events.try_next().await? {
+            recorded.unseal(self.config.message_encryption())?;
+            self.update(&recorded.event()?).await;
+            applied += 1;
+        }
+
+        Ok(applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, MessageBuilder, MessageUpdateBuilder};
+
+    fn created(content: &str) -> Event {
+        Event::MessageCreate(Box::new(
+            MessageBuilder::new(
+                testing::guild_id(1),
+                testing::channel_id(2),
+                testing::message_id(3),
+                testing::user_id(4),
+            )
+            .content(content)
+            .create(),
+        ))
+    }
+
+    fn content(event: &Event) -> Option<String> {
+        match event {
+            Event::MessageCreate(message) => Some(message.content.clone()),
+            Event::MessageUpdate(update) => update.content.clone(),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_seal_message_create() {
+        let encryption = MessageEncryption::new(1, [7; 32]);
+        let mut recorded = RecordedEvent::new(0, &created("secret")).unwrap().unwrap();
+
+        recorded.seal(&encryption).unwrap();
+
+        assert!(!recorded.payload.to_string().contains("secret"));
+        assert_eq!(content(&recorded.event().unwrap()).unwrap(), "");
+
+        recorded.unseal(Some(&encryption)).unwrap();
+
+        assert!(recorded.payload.get(SEALED_FIELD).is_none());
+        assert_eq!(content(&recorded.event().unwrap()).unwrap(), "secret");
+    }
+
+    #[test]
+    fn test_seal_message_update() {
+        let encryption = MessageEncryption::new(1, [7; 32]);
+        let update = MessageUpdateBuilder::new(
+            testing::guild_id(1),
+            testing::channel_id(2),
+            testing::message_id(3),
+        )
+        .content("secret")
+        .build();
+        let mut recorded = RecordedEvent::new(0, &Event::MessageUpdate(Box::new(update)))
+            .unwrap()
+            .unwrap();
+
+        recorded.seal(&encryption).unwrap();
+
+        assert!(!recorded.payload.to_string().contains("secret"));
+        assert_eq!(content(&recorded.event().unwrap()), None);
+
+        recorded.unseal(Some(&encryption)).unwrap();
+
+        assert_eq!(
+            content(&recorded.event().unwrap()).as_deref(),
+            Some("secret")
+        );
+    }
+
+    #[test]
+    fn test_unseal_requires_key() {
+        let mut recorded = RecordedEvent::new(0, &created("secret")).unwrap().unwrap();
+        recorded.seal(&MessageEncryption::new(1, [7; 32])).unwrap();
+
+        assert!(matches!(
+            recorded.clone().unseal(None),
+            Err(CacheError::Encryption)
+        ));
+        assert!(matches!(
+            recorded.unseal(Some(&MessageEncryption::new(2, [8; 32]))),
+            Err(CacheError::Encryption)
+        ));
+    }
+
+    #[test]
+    fn test_unsealed_untouched() {
+        let mut recorded = RecordedEvent::new(0, &created("public")).unwrap().unwrap();
+        let payload = recorded.payload.clone();
+
+        recorded.unseal(None).unwrap();
+
+        assert_eq!(recorded.payload, payload);
+    }
+}
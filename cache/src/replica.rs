@@ -0,0 +1,509 @@
+//! Routing of reads to replicas of the primary Redis node.
+//!
+//! Reads through the stores are served by replicas, so they don't contend
+//! with applying gateway events, which only ever talks to the primary. Reads
+//! made while applying an event stay on the primary as well, they decide
+//! what gets written.
+//!
+//! How stale a replica is gets measured with replication offsets: the offset
+//! of the primary is sampled next to the offset of the replica, and the
+//! replica is as fresh as the newest sample its offset has reached.
+
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use mobc::{Connection, Pool};
+
+use crate::{connection::RedisManager, CacheError, InRedisCache};
+
+/// How often the lag of a replica is measured when reads may come from
+/// replicas however stale they are, for [`InRedisCache::replica_lag`].
+const UNBOUNDED_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Samples of the primary kept for a replica which doesn't catch up, older
+/// ones are dropped, making the replica look staler than it is.
+const MAX_SAMPLES: usize = 1_000;
+
+tokio::task_local! {
+    /// Set while the task writes to the cache, its reads go to the primary.
+    static WRITING: ();
+
+    /// Time the writes of the current session were done at.
+    static LAST_WRITE: Cell<Option<Instant>>;
+}
+
+/// Replicas reads are routed to, shared by the cache and its stores.
+#[derive(Clone)]
+pub(crate) struct Replicas(Arc<Inner>);
+
+struct Inner {
+    primary: Pool<RedisManager>,
+    replicas: Vec<Replica>,
+    max_lag: Option<Duration>,
+    read_your_writes: bool,
+    /// Replica the next read starts looking at.
+    next: AtomicUsize,
+}
+
+struct Replica {
+    node: String,
+    pool: Pool<RedisManager>,
+    lag: Mutex<Lag>,
+    /// Set while the lag is being checked, reads meanwhile use what is
+    /// already known instead of waiting for the check.
+    checking: AtomicBool,
+}
+
+/// What is known about how far a replica lags behind.
+#[derive(Default)]
+struct Lag {
+    /// Replication offsets of the primary the replica hasn't reached at the
+    /// last check, oldest first.
+    samples: VecDeque<(Instant, u64)>,
+    /// The replica had everything the primary had at this time.
+    fresh_as_of: Option<Instant>,
+    checked_at: Option<Instant>,
+}
+
+impl Replicas {
+    pub(crate) fn new(
+        primary: RedisManager,
+        nodes: &[String],
+        max_lag: Option<Duration>,
+        read_your_writes: bool,
+    ) -> Self {
+        let replicas = nodes
+            .iter()
+            .map(|node| Replica {
+                node: node.clone(),
+                pool: Pool::builder().max_open(50).build(RedisManager::new(node)),
+                lag: Mutex::default(),
+                checking: AtomicBool::new(false),
+            })
+            .collect();
+
+        Self(Arc::new(Inner {
+            primary: Pool::builder().max_open(2).build(primary),
+            replicas,
+            max_lag,
+            read_your_writes,
+            next: AtomicUsize::new(0),
+        }))
+    }
+
+    /// Get a connection to a replica which is fresh enough for a read of the
+    /// current task, or `None` if the read has to go to the primary.
+    pub(crate) async fn get_con(&self) -> Option<Connection<RedisManager>> {
+        if WRITING.try_with(|_| ()).is_ok() {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut required = self.0.max_lag.and_then(|lag| now.checked_sub(lag));
+
+        if self.0.read_your_writes {
+            if let Ok(Some(written)) = LAST_WRITE.try_with(Cell::get) {
+                required = Some(required.map_or(written, |required| required.max(written)));
+            }
+        }
+
+        let count = self.0.replicas.len();
+        let start = self.0.next.fetch_add(1, Ordering::Relaxed);
+
+        for offset in 0..count {
+            let replica = &self.0.replicas[(start + offset) % count];
+
+            if !self.is_fresh(replica, required, now).await {
+                continue;
+            }
+
+            match replica.pool.get().await {
+                Ok(con) => return Some(con),
+                Err(err) => warn!("Failed to connect to replica {}: {}", replica.node, err),
+            }
+        }
+
+        None
+    }
+
+    /// How far the replicas lagged behind the primary at their last check,
+    /// `None` for the ones which never caught up since.
+    pub(crate) async fn lags(&self) -> Vec<(String, Option<Duration>)> {
+        let mut lags = Vec::with_capacity(self.0.replicas.len());
+
+        for replica in &self.0.replicas {
+            let behind = replica.lag.lock().expect("replica lag poisoned").behind();
+
+            lags.push((replica.node.clone(), behind));
+        }
+
+        lags
+    }
+
+    /// Whether a replica has everything the primary had at `required`,
+    /// measuring its lag again if that could tell.
+    ///
+    /// Only one check of a replica runs at once, other reads meanwhile go by
+    /// what was known before it.
+    async fn is_fresh(&self, replica: &Replica, required: Option<Instant>, now: Instant) -> bool {
+        let interval = self
+            .0
+            .max_lag
+            .map_or(UNBOUNDED_CHECK_INTERVAL, |max_lag| max_lag / 2);
+        let needs_check = replica
+            .lag
+            .lock()
+            .expect("replica lag poisoned")
+            .needs_check(required, interval, now);
+
+        let checking = if needs_check {
+            replica.start_check()
+        } else {
+            None
+        };
+
+        if let Some(_checking) = checking {
+            let checked = self.check(replica).await;
+            let mut lag = replica.lag.lock().expect("replica lag poisoned");
+
+            match checked {
+                Ok((sampled_at, primary_offset, replica_offset)) => {
+                    lag.advance(sampled_at, primary_offset, replica_offset, Instant::now());
+                }
+                Err(err) => {
+                    warn!(
+                        "Failed to check the lag of replica {}: {}",
+                        replica.node, err
+                    );
+
+                    // Not known to be any fresher than before.
+                    lag.checked_at = Some(now);
+                }
+            }
+        }
+
+        replica
+            .lag
+            .lock()
+            .expect("replica lag poisoned")
+            .is_fresh(required)
+    }
+
+    /// Sample the replication offset of the primary, then read the offset
+    /// the replica has reached, returning the time of the sample and both
+    /// offsets.
+    async fn check(&self, replica: &Replica) -> Result<(Instant, u64, u64), CacheError> {
+        let mut primary = self.0.primary.get().await?;
+        let sampled_at = Instant::now();
+        let primary_offset = replication_offset(&mut primary, "master_repl_offset").await?;
+
+        let mut con = replica.pool.get().await?;
+        let replica_offset = replication_offset(&mut con, "slave_repl_offset").await?;
+
+        Ok((sampled_at, primary_offset, replica_offset))
+    }
+}
+
+impl Replica {
+    /// Mark the lag of the replica as being checked, unless it already is.
+    ///
+    /// The mark is cleared once the returned guard is dropped, also if the
+    /// read checking the lag is cancelled.
+    fn start_check(&self) -> Option<Checking<'_>> {
+        self.checking
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| Checking(&self.checking))
+    }
+}
+
+/// Clears the mark of a replica being checked when dropped.
+struct Checking<'a>(&'a AtomicBool);
+
+impl Drop for Checking<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+impl Lag {
+    /// Whether checking the lag again could change what a read needing the
+    /// replica to be fresh as of `required` sees.
+    fn needs_check(&self, required: Option<Instant>, interval: Duration, now: Instant) -> bool {
+        let outdated = match self.checked_at {
+            Some(checked_at) => now.saturating_duration_since(checked_at) >= interval,
+            None => true,
+        };
+        // A check after the time the read needs the replica to have caught up
+        // with could show it did.
+        let could_catch_up = match (required, self.checked_at) {
+            (Some(required), Some(checked_at)) => {
+                checked_at < required && !self.is_fresh(Some(required))
+            }
+            _ => false,
+        };
+
+        outdated || could_catch_up
+    }
+
+    /// Record a check: the primary was at `primary_offset` at `sampled_at`,
+    /// and the replica reached `replica_offset` after that.
+    fn advance(
+        &mut self,
+        sampled_at: Instant,
+        primary_offset: u64,
+        replica_offset: u64,
+        checked_at: Instant,
+    ) {
+        self.samples.push_back((sampled_at, primary_offset));
+
+        if self.samples.len() > MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+
+        while let Some(&(at, offset)) = self.samples.front() {
+            if offset > replica_offset {
+                break;
+            }
+
+            self.fresh_as_of = Some(at);
+            self.samples.pop_front();
+        }
+
+        self.checked_at = Some(checked_at);
+    }
+
+    /// Whether the replica is known to have everything the primary had at
+    /// `required`.
+    fn is_fresh(&self, required: Option<Instant>) -> bool {
+        match (required, self.fresh_as_of) {
+            (None, _) => true,
+            (Some(required), Some(fresh_as_of)) => fresh_as_of >= required,
+            (Some(_), None) => false,
+        }
+    }
+
+    /// How far the replica lagged behind at the last check, `None` if it
+    /// never caught up.
+    fn behind(&self) -> Option<Duration> {
+        match (self.checked_at, self.fresh_as_of) {
+            (Some(checked_at), Some(fresh_as_of)) => {
+                Some(checked_at.saturating_duration_since(fresh_as_of))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Read a replication offset from `INFO replication`.
+async fn replication_offset(
+    con: &mut Connection<RedisManager>,
+    field: &str,
+) -> Result<u64, CacheError> {
+    let info: String = redis::cmd("INFO")
+        .arg("replication")
+        .query_async(&mut **con)
+        .await?;
+
+    info.lines()
+        .filter_map(|line| line.trim().split_once(':'))
+        .find(|(name, _)| *name == field)
+        .and_then(|(_, value)| value.parse().ok())
+        .ok_or(CacheError::RedisError)
+}
+
+/// Run `fut` as a write to the cache: its reads go to the primary, and a
+/// [`InRedisCache::session`] it runs in reads from the primary until the
+/// replicas caught up with it.
+pub(crate) async fn writing<F: Future>(fut: F) -> F::Output {
+    let output = WRITING.scope((), fut).await;
+
+    LAST_WRITE
+        .try_with(|written| written.set(Some(Instant::now())))
+        .ok();
+
+    output
+}
+
+impl InRedisCache {
+    /// Route the reads of the cache and all of its stores to `replicas`.
+    pub(crate) fn set_replicas(&mut self, replicas: Replicas) {
+        self.channels_guild.replicas = Some(replicas.clone());
+        self.channels_private.replicas = Some(replicas.clone());
+        self.channel_messages.replicas = Some(replicas.clone());
+        self.channel_deleted_messages.replicas = Some(replicas.clone());
+        self.emojis.replicas = Some(replicas.clone());
+        self.groups.replicas = Some(replicas.clone());
+        self.guilds.replicas = Some(replicas.clone());
+        self.guild_channels.replicas = Some(replicas.clone());
+        self.guild_emojis.replicas = Some(replicas.clone());
+        self.guild_integrations.replicas = Some(replicas.clone());
+        self.guild_members.replicas = Some(replicas.clone());
+        self.guild_presences.replicas = Some(replicas.clone());
+        self.guild_presence_counts.replicas = Some(replicas.clone());
+        self.guild_roles.replicas = Some(replicas.clone());
+        self.guild_stage_instances.replicas = Some(replicas.clone());
+        self.guild_stickers.replicas = Some(replicas.clone());
+        self.integrations.replicas = Some(replicas.clone());
+        self.members.replicas = Some(replicas.clone());
+        self.messages.replicas = Some(replicas.clone());
+        self.message_reactions.replicas = Some(replicas.clone());
+        self.message_revisions.replicas = Some(replicas.clone());
+        self.presences.replicas = Some(replicas.clone());
+        self.reaction_users.replicas = Some(replicas.clone());
+        self.roles.replicas = Some(replicas.clone());
+        self.stage_instances.replicas = Some(replicas.clone());
+        self.stickers.replicas = Some(replicas.clone());
+        self.unavailable_guilds.replicas = Some(replicas.clone());
+        self.users.replicas = Some(replicas.clone());
+        self.user_guilds.replicas = Some(replicas.clone());
+        self.user_messages.replicas = Some(replicas.clone());
+        self.user_reactions.replicas = Some(replicas.clone());
+        self.user_private_channels.replicas = Some(replicas.clone());
+        self.voice_state_channels.replicas = Some(replicas.clone());
+        self.voice_state_guilds.replicas = Some(replicas.clone());
+        self.voice_states.replicas = Some(replicas.clone());
+        self.replicas = Some(replicas);
+    }
+
+    /// Run `fut` in a session which reads its own writes.
+    ///
+    /// With [`Config::read_your_writes`] reads in the session go to the
+    /// primary after it updated the cache, until a replica caught up with
+    /// the update. Outside of a session, or without replicas, this does
+    /// nothing.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # async fn example(cache: cache::InRedisCache, event: twilight_model::gateway::event::Event) {
+    /// let guild = cache
+    ///     .session(async {
+    ///         cache.update(&event).await;
+    ///
+    ///         // Sees the guild of the event, even if no replica has it yet.
+    ///         cache.guilds.get(1).await
+    ///     })
+    ///     .await;
+    /// # }
+    /// ```
+    ///
+    /// [`Config::read_your_writes`]: crate::Config::read_your_writes
+    pub async fn session<F: Future>(&self, fut: F) -> F::Output {
+        LAST_WRITE.scope(Cell::new(None), fut).await
+    }
+
+    /// How far each replica lagged behind the primary when it was last
+    /// checked, by its node.
+    ///
+    /// The lag is `None` for replicas which haven't caught up with the
+    /// primary since they were first checked. Replicas are only checked
+    /// while reads are served.
+    pub async fn replica_lag(&self) -> Vec<(String, Option<Duration>)> {
+        match &self.replicas {
+            Some(replicas) => replicas.lags().await,
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cancelled_check() {
+        let replicas = Replicas::new(
+            RedisManager::new("redis://127.0.0.1"),
+            &["redis://127.0.0.1:6380".to_owned()],
+            None,
+            false,
+        );
+        let replica = &replicas.0.replicas[0];
+
+        let check = async {
+            let _checking = replica.start_check().expect("not checking yet");
+            assert!(replica.start_check().is_none());
+
+            futures::future::pending::<()>().await;
+        };
+        let check = tokio::time::timeout(Duration::from_millis(10), check);
+        assert!(check.await.is_err());
+
+        assert!(replica.start_check().is_some());
+    }
+
+    #[test]
+    fn test_advance() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut lag = Lag::default();
+
+        // The replica hasn't reached the first sample yet.
+        lag.advance(at(0), 100, 50, at(0));
+        assert_eq!(lag.fresh_as_of, None);
+        assert_eq!(lag.behind(), None);
+        assert!(!lag.is_fresh(Some(at(0))));
+        assert!(lag.is_fresh(None));
+
+        // Reaching the first sample, but not the second.
+        lag.advance(at(1), 200, 150, at(1));
+        assert_eq!(lag.fresh_as_of, Some(at(0)));
+        assert_eq!(lag.samples, [(at(1), 200)]);
+        assert_eq!(lag.behind(), Some(Duration::from_secs(1)));
+
+        // Catching up with every sample at once.
+        lag.advance(at(2), 300, 300, at(3));
+        assert_eq!(lag.fresh_as_of, Some(at(2)));
+        assert!(lag.samples.is_empty());
+        assert_eq!(lag.behind(), Some(Duration::from_secs(1)));
+        assert!(lag.is_fresh(Some(at(2))));
+        assert!(!lag.is_fresh(Some(at(3))));
+    }
+
+    #[test]
+    fn test_advance_drops_old_samples() {
+        let start = Instant::now();
+        let mut lag = Lag::default();
+
+        for offset in 0..=MAX_SAMPLES as u64 {
+            let at = start + Duration::from_millis(offset);
+
+            lag.advance(at, offset + 1, 0, at);
+        }
+
+        assert_eq!(lag.samples.len(), MAX_SAMPLES);
+        assert_eq!(lag.samples.front().map(|&(_, offset)| offset), Some(2));
+        assert_eq!(lag.fresh_as_of, None);
+    }
+
+    #[test]
+    fn test_needs_check() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let interval = Duration::from_secs(10);
+        let mut lag = Lag::default();
+
+        assert!(lag.needs_check(None, interval, at(0)));
+
+        lag.advance(at(0), 100, 100, at(1));
+        assert!(!lag.needs_check(None, interval, at(2)));
+        assert!(lag.needs_check(None, interval, at(11)));
+
+        // Already fresh enough for the read.
+        assert!(!lag.needs_check(Some(at(0)), interval, at(2)));
+        // Only a check after the required time could show the replica is
+        // fresh enough.
+        assert!(lag.needs_check(Some(at(2)), interval, at(2)));
+        assert!(!lag.needs_check(Some(at(1)), interval, at(2)));
+    }
+}
@@ -0,0 +1,931 @@
+//! Builders for twilight models and gateway payloads, for tests.
+//!
+//! Every builder starts from a minimal but valid model which only needs its
+//! IDs, and has setters for the fields tests usually care about. Payloads
+//! which wrap a model are built from the model's builder, like
+//! [`GuildBuilder::create`] and [`MessageBuilder::create`].
+//!
+//! ```ignore
+//! use cache::testing::{self, GuildBuilder, MemberBuilder, TextChannelBuilder};
+//!
+//! let guild_id = testing::guild_id(1);
+//! let event = GuildBuilder::new(guild_id, testing::user_id(2))
+//!     .channel(TextChannelBuilder::new(guild_id, testing::channel_id(3)).build())
+//!     .member(MemberBuilder::new(guild_id, testing::user_id(2)).build())
+//!     .create();
+//!
+//! cache.update(&event).await;
+//! ```
+//!
+//! Only available with the `testing` feature.
+
+use twilight_model::{
+    application::interaction::application_command::InteractionMember,
+    channel::{
+        message::{Message, MessageFlags, MessageType},
+        ChannelType, GuildChannel, Reaction, ReactionType, TextChannel,
+    },
+    datetime::Timestamp,
+    gateway::{
+        payload::incoming::{
+            GuildCreate, MemberAdd, MemberChunk, MemberUpdate, MessageCreate, MessageUpdate,
+            PresenceUpdate, ReactionAdd, ReactionRemove, Ready, RoleCreate,
+        },
+        presence::{Activity, ClientStatus, Presence, Status, UserOrId},
+    },
+    guild::{
+        DefaultMessageNotificationLevel, Emoji, ExplicitContentFilter, Guild, Member, MfaLevel,
+        NSFWLevel, PartialMember, Permissions, PremiumTier, Role, SystemChannelFlags,
+        UnavailableGuild, VerificationLevel,
+    },
+    id::{ApplicationId, ChannelId, EmojiId, GuildId, MessageId, RoleId, UserId},
+    oauth::{current_application_info::ApplicationFlags, PartialApplication},
+    user::{CurrentUser, User},
+    voice::VoiceState,
+};
+
+/// Timestamp used wherever a model needs one and the test didn't set it.
+pub const TIMESTAMP_SECS: u64 = 1_632_072_645;
+
+/// A timestamp from seconds since the Unix epoch.
+pub fn timestamp(secs: u64) -> Timestamp {
+    Timestamp::from_secs(secs).expect("valid timestamp")
+}
+
+/// A guild ID, panicking if `id` is zero.
+pub fn guild_id(id: u64) -> GuildId {
+    GuildId::new(id).expect("non zero")
+}
+
+/// A channel ID, panicking if `id` is zero.
+pub fn channel_id(id: u64) -> ChannelId {
+    ChannelId::new(id).expect("non zero")
+}
+
+/// An emoji ID, panicking if `id` is zero.
+pub fn emoji_id(id: u64) -> EmojiId {
+    EmojiId::new(id).expect("non zero")
+}
+
+/// A message ID, panicking if `id` is zero.
+pub fn message_id(id: u64) -> MessageId {
+    MessageId::new(id).expect("non zero")
+}
+
+/// A role ID, panicking if `id` is zero.
+pub fn role_id(id: u64) -> RoleId {
+    RoleId::new(id).expect("non zero")
+}
+
+/// A user ID, panicking if `id` is zero.
+pub fn user_id(id: u64) -> UserId {
+    UserId::new(id).expect("non zero")
+}
+
+/// A unicode emoji reaction, like `"👍"`.
+pub fn unicode_emoji(name: &str) -> ReactionType {
+    ReactionType::Unicode {
+        name: name.to_owned(),
+    }
+}
+
+/// Builds a [`User`], named `user {id}`.
+#[derive(Clone, Debug)]
+pub struct UserBuilder(User);
+
+impl UserBuilder {
+    pub fn new(id: UserId) -> Self {
+        Self(User {
+            accent_color: None,
+            avatar: None,
+            banner: None,
+            bot: false,
+            discriminator: 1,
+            email: None,
+            flags: None,
+            id,
+            locale: None,
+            mfa_enabled: None,
+            name: format!("user {}", id),
+            premium_type: None,
+            public_flags: None,
+            system: None,
+            verified: None,
+        })
+    }
+
+    pub fn avatar(mut self, avatar: impl Into<String>) -> Self {
+        self.0.avatar = Some(avatar.into());
+        self
+    }
+
+    pub fn bot(mut self, bot: bool) -> Self {
+        self.0.bot = bot;
+        self
+    }
+
+    pub fn discriminator(mut self, discriminator: u16) -> Self {
+        self.0.discriminator = discriminator;
+        self
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.0.name = name.into();
+        self
+    }
+
+    pub fn build(self) -> User {
+        self.0
+    }
+}
+
+/// Builds a [`Member`] and the payloads about one, joined at
+/// [`TIMESTAMP_SECS`] without roles.
+#[derive(Clone, Debug)]
+pub struct MemberBuilder(Member);
+
+impl MemberBuilder {
+    pub fn new(guild_id: GuildId, user_id: UserId) -> Self {
+        Self(Member {
+            deaf: false,
+            guild_id,
+            joined_at: Some(timestamp(TIMESTAMP_SECS)),
+            mute: false,
+            nick: None,
+            pending: false,
+            premium_since: None,
+            roles: Vec::new(),
+            user: UserBuilder::new(user_id).build(),
+        })
+    }
+
+    pub fn deaf(mut self, deaf: bool) -> Self {
+        self.0.deaf = deaf;
+        self
+    }
+
+    pub fn joined_at(mut self, joined_at: Option<Timestamp>) -> Self {
+        self.0.joined_at = joined_at;
+        self
+    }
+
+    pub fn mute(mut self, mute: bool) -> Self {
+        self.0.mute = mute;
+        self
+    }
+
+    pub fn nick(mut self, nick: impl Into<String>) -> Self {
+        self.0.nick = Some(nick.into());
+        self
+    }
+
+    pub fn pending(mut self, pending: bool) -> Self {
+        self.0.pending = pending;
+        self
+    }
+
+    pub fn premium_since(mut self, premium_since: Option<Timestamp>) -> Self {
+        self.0.premium_since = premium_since;
+        self
+    }
+
+    pub fn roles(mut self, roles: impl IntoIterator<Item = RoleId>) -> Self {
+        self.0.roles = roles.into_iter().collect();
+        self
+    }
+
+    pub fn user(mut self, user: User) -> Self {
+        self.0.user = user;
+        self
+    }
+
+    pub fn build(self) -> Member {
+        self.0
+    }
+
+    /// The member as it is sent with messages and reactions, with or
+    /// without its user.
+    pub fn partial(self, with_user: bool) -> PartialMember {
+        PartialMember {
+            deaf: self.0.deaf,
+            joined_at: self.0.joined_at,
+            mute: self.0.mute,
+            nick: self.0.nick,
+            permissions: None,
+            premium_since: self.0.premium_since,
+            roles: self.0.roles,
+            user: with_user.then_some(self.0.user),
+        }
+    }
+
+    /// The member as it is resolved in application command interactions.
+    pub fn interaction(self) -> InteractionMember {
+        InteractionMember {
+            hoisted_role: None,
+            id: self.0.user.id,
+            joined_at: self.0.joined_at,
+            nick: self.0.nick,
+            premium_since: self.0.premium_since,
+            roles: self.0.roles,
+        }
+    }
+
+    pub fn add(self) -> MemberAdd {
+        MemberAdd(self.0)
+    }
+
+    /// A [`MemberUpdate`] setting the member to this state.
+    pub fn update(self) -> MemberUpdate {
+        MemberUpdate {
+            deaf: Some(self.0.deaf),
+            guild_id: self.0.guild_id,
+            joined_at: self
+                .0
+                .joined_at
+                .unwrap_or_else(|| timestamp(TIMESTAMP_SECS)),
+            mute: Some(self.0.mute),
+            nick: self.0.nick,
+            pending: self.0.pending,
+            premium_since: self.0.premium_since,
+            roles: self.0.roles,
+            user: self.0.user,
+        }
+    }
+}
+
+/// Builds a [`MemberChunk`], the only chunk of its guild.
+#[derive(Clone, Debug)]
+pub struct MemberChunkBuilder(MemberChunk);
+
+impl MemberChunkBuilder {
+    pub fn new(guild_id: GuildId) -> Self {
+        Self(MemberChunk {
+            chunk_count: 1,
+            chunk_index: 0,
+            guild_id,
+            members: Vec::new(),
+            nonce: None,
+            not_found: Vec::new(),
+            presences: Vec::new(),
+        })
+    }
+
+    /// Set the index of this chunk and the number of chunks of the guild.
+    pub fn index(mut self, chunk_index: u32, chunk_count: u32) -> Self {
+        self.0.chunk_index = chunk_index;
+        self.0.chunk_count = chunk_count;
+        self
+    }
+
+    pub fn member(mut self, member: Member) -> Self {
+        self.0.members.push(member);
+        self
+    }
+
+    pub fn members(mut self, members: impl IntoIterator<Item = Member>) -> Self {
+        self.0.members.extend(members);
+        self
+    }
+
+    pub fn nonce(mut self, nonce: impl Into<String>) -> Self {
+        self.0.nonce = Some(nonce.into());
+        self
+    }
+
+    pub fn not_found(mut self, user_id: UserId) -> Self {
+        self.0.not_found.push(user_id);
+        self
+    }
+
+    pub fn presence(mut self, presence: Presence) -> Self {
+        self.0.presences.push(presence);
+        self
+    }
+
+    pub fn build(self) -> MemberChunk {
+        self.0
+    }
+}
+
+/// Builds a [`Role`] without permissions, named `role {id}`.
+#[derive(Clone, Debug)]
+pub struct RoleBuilder(Role);
+
+impl RoleBuilder {
+    pub fn new(id: RoleId) -> Self {
+        Self(Role {
+            color: 0,
+            hoist: false,
+            icon: None,
+            id,
+            managed: false,
+            mentionable: true,
+            name: format!("role {}", id),
+            permissions: Permissions::empty(),
+            position: 0,
+            tags: None,
+            unicode_emoji: None,
+        })
+    }
+
+    pub fn color(mut self, color: u32) -> Self {
+        self.0.color = color;
+        self
+    }
+
+    pub fn hoist(mut self, hoist: bool) -> Self {
+        self.0.hoist = hoist;
+        self
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.0.name = name.into();
+        self
+    }
+
+    pub fn permissions(mut self, permissions: Permissions) -> Self {
+        self.0.permissions = permissions;
+        self
+    }
+
+    pub fn position(mut self, position: i64) -> Self {
+        self.0.position = position;
+        self
+    }
+
+    pub fn build(self) -> Role {
+        self.0
+    }
+
+    pub fn create(self, guild_id: GuildId) -> RoleCreate {
+        RoleCreate {
+            guild_id,
+            role: self.0,
+        }
+    }
+}
+
+/// Builds a guild text channel, named `channel {id}`.
+#[derive(Clone, Debug)]
+pub struct TextChannelBuilder(TextChannel);
+
+impl TextChannelBuilder {
+    pub fn new(guild_id: GuildId, id: ChannelId) -> Self {
+        Self(TextChannel {
+            id,
+            guild_id: Some(guild_id),
+            kind: ChannelType::GuildText,
+            last_message_id: None,
+            last_pin_timestamp: None,
+            name: format!("channel {}", id),
+            nsfw: false,
+            permission_overwrites: Vec::new(),
+            parent_id: None,
+            position: 0,
+            rate_limit_per_user: None,
+            topic: None,
+        })
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.0.name = name.into();
+        self
+    }
+
+    pub fn nsfw(mut self, nsfw: bool) -> Self {
+        self.0.nsfw = nsfw;
+        self
+    }
+
+    pub fn parent_id(mut self, parent_id: ChannelId) -> Self {
+        self.0.parent_id = Some(parent_id);
+        self
+    }
+
+    pub fn position(mut self, position: i64) -> Self {
+        self.0.position = position;
+        self
+    }
+
+    pub fn topic(mut self, topic: impl Into<String>) -> Self {
+        self.0.topic = Some(topic.into());
+        self
+    }
+
+    pub fn build(self) -> GuildChannel {
+        GuildChannel::Text(self.0)
+    }
+}
+
+/// Builds a custom [`Emoji`], named `emoji {id}`.
+#[derive(Clone, Debug)]
+pub struct EmojiBuilder(Emoji);
+
+impl EmojiBuilder {
+    pub fn new(id: EmojiId) -> Self {
+        Self(Emoji {
+            id,
+            animated: false,
+            name: format!("emoji {}", id),
+            managed: false,
+            require_colons: true,
+            roles: Vec::new(),
+            user: None,
+            available: true,
+        })
+    }
+
+    pub fn animated(mut self, animated: bool) -> Self {
+        self.0.animated = animated;
+        self
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.0.name = name.into();
+        self
+    }
+
+    pub fn roles(mut self, roles: impl IntoIterator<Item = RoleId>) -> Self {
+        self.0.roles = roles.into_iter().collect();
+        self
+    }
+
+    pub fn user(mut self, user: User) -> Self {
+        self.0.user = Some(user);
+        self
+    }
+
+    pub fn build(self) -> Emoji {
+        self.0
+    }
+}
+
+/// Builds a [`Guild`], named `guild {id}`, without channels, roles or
+/// members.
+///
+/// The member count follows the members added.
+#[derive(Clone, Debug)]
+pub struct GuildBuilder(Guild);
+
+impl GuildBuilder {
+    pub fn new(id: GuildId, owner_id: UserId) -> Self {
+        Self(Guild {
+            afk_channel_id: None,
+            afk_timeout: 300,
+            application_id: None,
+            approximate_member_count: None,
+            approximate_presence_count: None,
+            banner: None,
+            channels: Vec::new(),
+            default_message_notifications: DefaultMessageNotificationLevel::Mentions,
+            description: None,
+            discovery_splash: None,
+            emojis: Vec::new(),
+            explicit_content_filter: ExplicitContentFilter::None,
+            features: Vec::new(),
+            icon: None,
+            id,
+            joined_at: Some(timestamp(TIMESTAMP_SECS)),
+            large: false,
+            max_members: None,
+            max_presences: None,
+            max_video_channel_users: None,
+            member_count: Some(0),
+            members: Vec::new(),
+            mfa_level: MfaLevel::None,
+            name: format!("guild {}", id),
+            nsfw_level: NSFWLevel::Default,
+            owner: Some(false),
+            owner_id,
+            permissions: None,
+            preferred_locale: "en-US".to_owned(),
+            premium_subscription_count: Some(0),
+            premium_tier: PremiumTier::None,
+            presences: Vec::new(),
+            roles: Vec::new(),
+            rules_channel_id: None,
+            splash: None,
+            stage_instances: Vec::new(),
+            stickers: Vec::new(),
+            system_channel_flags: SystemChannelFlags::empty(),
+            system_channel_id: None,
+            threads: Vec::new(),
+            unavailable: false,
+            vanity_url_code: None,
+            verification_level: VerificationLevel::None,
+            voice_states: Vec::new(),
+            widget_channel_id: None,
+            widget_enabled: None,
+        })
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.0.name = name.into();
+        self
+    }
+
+    pub fn large(mut self, large: bool) -> Self {
+        self.0.large = large;
+        self
+    }
+
+    /// Set the member count, which otherwise follows the members added.
+    pub fn member_count(mut self, member_count: u64) -> Self {
+        self.0.member_count = Some(member_count);
+        self
+    }
+
+    pub fn premium_tier(mut self, premium_tier: PremiumTier) -> Self {
+        self.0.premium_tier = premium_tier;
+        self
+    }
+
+    pub fn verification_level(mut self, verification_level: VerificationLevel) -> Self {
+        self.0.verification_level = verification_level;
+        self
+    }
+
+    pub fn channel(mut self, channel: GuildChannel) -> Self {
+        self.0.channels.push(channel);
+        self
+    }
+
+    pub fn channels(mut self, channels: impl IntoIterator<Item = GuildChannel>) -> Self {
+        self.0.channels.extend(channels);
+        self
+    }
+
+    pub fn emoji(mut self, emoji: Emoji) -> Self {
+        self.0.emojis.push(emoji);
+        self
+    }
+
+    pub fn member(self, member: Member) -> Self {
+        self.members([member])
+    }
+
+    pub fn members(mut self, members: impl IntoIterator<Item = Member>) -> Self {
+        self.0.members.extend(members);
+        self.0.member_count = Some(self.0.members.len() as u64);
+        self
+    }
+
+    pub fn presence(mut self, presence: Presence) -> Self {
+        self.0.presences.push(presence);
+        self
+    }
+
+    pub fn role(mut self, role: Role) -> Self {
+        self.0.roles.push(role);
+        self
+    }
+
+    pub fn roles(mut self, roles: impl IntoIterator<Item = Role>) -> Self {
+        self.0.roles.extend(roles);
+        self
+    }
+
+    pub fn voice_state(mut self, voice_state: VoiceState) -> Self {
+        self.0.voice_states.push(voice_state);
+        self
+    }
+
+    pub fn build(self) -> Guild {
+        self.0
+    }
+
+    pub fn create(self) -> GuildCreate {
+        GuildCreate(self.0)
+    }
+}
+
+/// Builds a regular [`Message`] sent in a guild at [`TIMESTAMP_SECS`].
+#[derive(Clone, Debug)]
+pub struct MessageBuilder(Message);
+
+impl MessageBuilder {
+    pub fn new(guild_id: GuildId, channel_id: ChannelId, id: MessageId, author: UserId) -> Self {
+        Self(Message {
+            activity: None,
+            application: None,
+            application_id: None,
+            attachments: Vec::new(),
+            author: UserBuilder::new(author).build(),
+            channel_id,
+            components: Vec::new(),
+            content: String::new(),
+            edited_timestamp: None,
+            embeds: Vec::new(),
+            flags: Some(MessageFlags::empty()),
+            guild_id: Some(guild_id),
+            id,
+            interaction: None,
+            kind: MessageType::Regular,
+            member: None,
+            mention_channels: Vec::new(),
+            mention_everyone: false,
+            mention_roles: Vec::new(),
+            mentions: Vec::new(),
+            pinned: false,
+            reactions: Vec::new(),
+            reference: None,
+            referenced_message: None,
+            sticker_items: Vec::new(),
+            thread: None,
+            timestamp: timestamp(TIMESTAMP_SECS),
+            tts: false,
+            webhook_id: None,
+        })
+    }
+
+    /// Make the message a direct message, outside of any guild.
+    pub fn private(mut self) -> Self {
+        self.0.guild_id = None;
+        self
+    }
+
+    pub fn author(mut self, author: User) -> Self {
+        self.0.author = author;
+        self
+    }
+
+    pub fn content(mut self, content: impl Into<String>) -> Self {
+        self.0.content = content.into();
+        self
+    }
+
+    pub fn edited_timestamp(mut self, edited_timestamp: Timestamp) -> Self {
+        self.0.edited_timestamp = Some(edited_timestamp);
+        self
+    }
+
+    pub fn member(mut self, member: PartialMember) -> Self {
+        self.0.member = Some(member);
+        self
+    }
+
+    pub fn mention_roles(mut self, roles: impl IntoIterator<Item = RoleId>) -> Self {
+        self.0.mention_roles = roles.into_iter().collect();
+        self
+    }
+
+    pub fn pinned(mut self, pinned: bool) -> Self {
+        self.0.pinned = pinned;
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: Timestamp) -> Self {
+        self.0.timestamp = timestamp;
+        self
+    }
+
+    pub fn build(self) -> Message {
+        self.0
+    }
+
+    pub fn create(self) -> MessageCreate {
+        MessageCreate(self.0)
+    }
+}
+
+/// Builds a [`MessageUpdate`] which changes nothing until fields are set.
+#[derive(Clone, Debug)]
+pub struct MessageUpdateBuilder(MessageUpdate);
+
+impl MessageUpdateBuilder {
+    pub fn new(guild_id: GuildId, channel_id: ChannelId, id: MessageId) -> Self {
+        Self(MessageUpdate {
+            attachments: None,
+            author: None,
+            channel_id,
+            content: None,
+            edited_timestamp: None,
+            embeds: None,
+            guild_id: Some(guild_id),
+            id,
+            kind: None,
+            mention_everyone: None,
+            mention_roles: None,
+            mentions: None,
+            pinned: None,
+            timestamp: None,
+            tts: None,
+        })
+    }
+
+    pub fn content(mut self, content: impl Into<String>) -> Self {
+        self.0.content = Some(content.into());
+        self
+    }
+
+    pub fn edited_timestamp(mut self, edited_timestamp: Timestamp) -> Self {
+        self.0.edited_timestamp = Some(edited_timestamp);
+        self
+    }
+
+    pub fn pinned(mut self, pinned: bool) -> Self {
+        self.0.pinned = Some(pinned);
+        self
+    }
+
+    pub fn build(self) -> MessageUpdate {
+        self.0
+    }
+}
+
+/// Builds a [`Reaction`] and the payloads adding or removing it.
+#[derive(Clone, Debug)]
+pub struct ReactionBuilder(Reaction);
+
+impl ReactionBuilder {
+    pub fn new(
+        guild_id: GuildId,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        user_id: UserId,
+        emoji: ReactionType,
+    ) -> Self {
+        Self(Reaction {
+            channel_id,
+            emoji,
+            guild_id: Some(guild_id),
+            member: None,
+            message_id,
+            user_id,
+        })
+    }
+
+    pub fn member(mut self, member: Member) -> Self {
+        self.0.member = Some(member);
+        self
+    }
+
+    pub fn build(self) -> Reaction {
+        self.0
+    }
+
+    pub fn add(self) -> ReactionAdd {
+        ReactionAdd(self.0)
+    }
+
+    pub fn remove(self) -> ReactionRemove {
+        ReactionRemove(self.0)
+    }
+}
+
+/// Builds a [`Presence`], online on desktop without activities.
+#[derive(Clone, Debug)]
+pub struct PresenceBuilder(Presence);
+
+impl PresenceBuilder {
+    pub fn new(guild_id: GuildId, user_id: UserId) -> Self {
+        Self(Presence {
+            activities: Vec::new(),
+            client_status: ClientStatus {
+                desktop: Some(Status::Online),
+                mobile: None,
+                web: None,
+            },
+            guild_id,
+            status: Status::Online,
+            user: UserOrId::UserId { id: user_id },
+        })
+    }
+
+    pub fn activity(mut self, activity: Activity) -> Self {
+        self.0.activities.push(activity);
+        self
+    }
+
+    /// Set the status, on desktop only.
+    pub fn status(mut self, status: Status) -> Self {
+        self.0.client_status = ClientStatus {
+            desktop: Some(status),
+            mobile: None,
+            web: None,
+        };
+        self.0.status = status;
+        self
+    }
+
+    pub fn user(mut self, user: User) -> Self {
+        self.0.user = UserOrId::User(user);
+        self
+    }
+
+    pub fn build(self) -> Presence {
+        self.0
+    }
+
+    pub fn update(self) -> PresenceUpdate {
+        PresenceUpdate {
+            activities: self.0.activities,
+            client_status: self.0.client_status,
+            game: None,
+            guild_id: self.0.guild_id,
+            status: self.0.status,
+            user: self.0.user,
+        }
+    }
+}
+
+/// Builds a [`VoiceState`] of a user connected to a guild voice channel.
+#[derive(Clone, Debug)]
+pub struct VoiceStateBuilder(VoiceState);
+
+impl VoiceStateBuilder {
+    pub fn new(guild_id: GuildId, channel_id: ChannelId, user_id: UserId) -> Self {
+        Self(VoiceState {
+            channel_id: Some(channel_id),
+            deaf: false,
+            guild_id: Some(guild_id),
+            member: None,
+            mute: false,
+            self_deaf: false,
+            self_mute: false,
+            self_stream: false,
+            session_id: format!("session {}", user_id),
+            suppress: false,
+            token: None,
+            user_id,
+            request_to_speak_timestamp: None,
+        })
+    }
+
+    /// Disconnect the user, as sent when they leave the channel.
+    pub fn disconnected(mut self) -> Self {
+        self.0.channel_id = None;
+        self
+    }
+
+    pub fn member(mut self, member: Member) -> Self {
+        self.0.member = Some(member);
+        self
+    }
+
+    pub fn mute(mut self, mute: bool) -> Self {
+        self.0.mute = mute;
+        self
+    }
+
+    pub fn self_mute(mut self, self_mute: bool) -> Self {
+        self.0.self_mute = self_mute;
+        self
+    }
+
+    pub fn build(self) -> VoiceState {
+        self.0
+    }
+}
+
+/// Builds the [`Ready`] of a bot user, with its guilds unavailable.
+#[derive(Clone, Debug)]
+pub struct ReadyBuilder(Ready);
+
+impl ReadyBuilder {
+    pub fn new(user_id: UserId) -> Self {
+        Self(Ready {
+            application: PartialApplication {
+                flags: ApplicationFlags::empty(),
+                id: ApplicationId::new(user_id.get()).expect("non zero"),
+            },
+            guilds: Vec::new(),
+            session_id: format!("session {}", user_id),
+            shard: Some([0, 1]),
+            user: CurrentUser {
+                accent_color: None,
+                avatar: None,
+                banner: None,
+                bot: true,
+                discriminator: 1,
+                email: None,
+                flags: None,
+                id: user_id,
+                locale: None,
+                mfa_enabled: false,
+                name: format!("bot {}", user_id),
+                premium_type: None,
+                public_flags: None,
+                verified: None,
+            },
+            version: 9,
+        })
+    }
+
+    pub fn guild(mut self, guild_id: GuildId) -> Self {
+        self.0.guilds.push(UnavailableGuild {
+            id: guild_id,
+            unavailable: true,
+        });
+        self
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.0.user.name = name.into();
+        self
+    }
+
+    pub fn build(self) -> Ready {
+        self.0
+    }
+}
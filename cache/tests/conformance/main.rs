@@ -0,0 +1,832 @@
+//! Differential tests of [`InRedisCache`] against twilight's
+//! [`InMemoryCache`], which it is a port of.
+//!
+//! Randomized but valid event sequences are applied to both caches, which
+//! have to agree on every guild, member, role, channel, message and reaction
+//! after each event. A failing run prints its seed, rerun it with
+//! `CONFORMANCE_SEED=<seed>`. `CONFORMANCE_STEPS` sets the number of events.
+
+use std::{
+    collections::HashSet,
+    env,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use cache::{
+    testing::{
+        self, GuildBuilder, MemberBuilder, MessageBuilder, MessageUpdateBuilder, ReactionBuilder,
+        RoleBuilder, TextChannelBuilder, UserBuilder,
+    },
+    InRedisCache,
+};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_model::{
+    channel::{message::MessageReaction, Channel, ReactionType},
+    gateway::{
+        event::Event,
+        payload::incoming::{
+            ChannelCreate, ChannelDelete, ChannelUpdate, GuildDelete, MemberRemove, MemberUpdate,
+            MessageDelete, MessageDeleteBulk, ReactionRemoveAll, ReactionRemoveEmoji, RoleCreate,
+            RoleDelete, RoleUpdate,
+        },
+    },
+    guild::{PremiumTier, VerificationLevel},
+    id::{ChannelId, GuildId, MessageId, RoleId, UserId},
+};
+
+const EMOJIS: [&str; 3] = ["👍", "🎉", "🔥"];
+
+/// Messages kept per channel, below the default message cache size of the
+/// in-memory cache so it doesn't evict any.
+const MESSAGES_PER_CHANNEL: usize = 50;
+
+struct GuildState {
+    id: GuildId,
+    channels: Vec<ChannelId>,
+    roles: Vec<RoleId>,
+    members: Vec<UserId>,
+}
+
+struct MessageState {
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    id: MessageId,
+    /// Users who reacted, per index into [`EMOJIS`].
+    reactions: Vec<(usize, Vec<UserId>)>,
+}
+
+/// What the generated events describe, so every event is valid.
+struct World {
+    rng: StdRng,
+    next_id: u64,
+    guilds: Vec<GuildState>,
+    messages: Vec<MessageState>,
+    seen_guilds: Vec<GuildId>,
+    seen_channels: Vec<ChannelId>,
+    seen_roles: Vec<RoleId>,
+    seen_members: HashSet<(GuildId, UserId)>,
+    seen_messages: Vec<(GuildId, MessageId)>,
+}
+
+impl World {
+    fn new(seed: u64) -> Self {
+        // Fresh IDs for every run, the Redis cache is not flushed.
+        let next_id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time after epoch")
+            .as_millis() as u64
+            * 1_000_000;
+
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            next_id,
+            guilds: Vec::new(),
+            messages: Vec::new(),
+            seen_guilds: Vec::new(),
+            seen_channels: Vec::new(),
+            seen_roles: Vec::new(),
+            seen_members: HashSet::new(),
+            seen_messages: Vec::new(),
+        }
+    }
+
+    fn id(&mut self) -> u64 {
+        self.next_id += 1;
+
+        self.next_id
+    }
+
+    fn channel_id(&mut self) -> ChannelId {
+        let id = ChannelId::new(self.id()).expect("non zero");
+        self.seen_channels.push(id);
+
+        id
+    }
+
+    fn role_id(&mut self) -> RoleId {
+        let id = RoleId::new(self.id()).expect("non zero");
+        self.seen_roles.push(id);
+
+        id
+    }
+
+    fn user_id(&mut self) -> UserId {
+        UserId::new(self.id()).expect("non zero")
+    }
+
+    fn some_roles(&mut self, guild: usize) -> Vec<RoleId> {
+        let roles = self.guilds[guild].roles.clone();
+
+        roles
+            .into_iter()
+            .filter(|_| self.rng.gen_bool(0.3))
+            .collect()
+    }
+
+    /// Number of members of a guild, which the in-memory cache only sets when
+    /// the guild is created.
+    fn member_count(&self, guild_id: GuildId) -> Option<u64> {
+        self.guilds
+            .iter()
+            .find(|guild| guild.id == guild_id)
+            .map(|guild| guild.members.len() as u64)
+    }
+
+    fn pick_guild(&mut self) -> usize {
+        self.rng.gen_range(0..self.guilds.len())
+    }
+
+    fn pick_message(&mut self) -> Option<usize> {
+        if self.messages.is_empty() {
+            return None;
+        }
+
+        Some(self.rng.gen_range(0..self.messages.len()))
+    }
+
+    fn next_event(&mut self) -> Event {
+        if self.guilds.is_empty() {
+            return self.guild_create();
+        }
+
+        let event = match self.rng.gen_range(0..100) {
+            0..=3 => Some(self.guild_create()),
+            4..=5 => Some(self.guild_delete()),
+            6..=17 => Some(self.member_add()),
+            18..=25 => self.member_update(),
+            26..=30 => self.member_remove(),
+            31..=35 => Some(self.role_create()),
+            36..=39 => self.role_update(),
+            40..=42 => self.role_delete(),
+            43..=46 => Some(self.channel_create()),
+            47..=49 => self.channel_update(),
+            50..=51 => self.channel_delete(),
+            52..=66 => self.message_create(),
+            67..=72 => self.message_update(),
+            73..=76 => self.message_delete(),
+            77..=78 => self.message_delete_bulk(),
+            79..=90 => self.reaction_add(),
+            91..=95 => self.reaction_remove(),
+            96..=97 => self.reaction_remove_all(),
+            _ => self.reaction_remove_emoji(),
+        };
+
+        // Not possible right now, e.g. no message to react to.
+        event.unwrap_or_else(|| self.member_add())
+    }
+
+    fn guild_create(&mut self) -> Event {
+        let guild_id = GuildId::new(self.id()).expect("non zero");
+        self.seen_guilds.push(guild_id);
+
+        let mut state = GuildState {
+            id: guild_id,
+            channels: Vec::new(),
+            roles: Vec::new(),
+            members: Vec::new(),
+        };
+        let mut channels = Vec::new();
+        let mut roles = Vec::new();
+        let mut members = Vec::new();
+
+        for position in 0..self.rng.gen_range(1..4) {
+            let id = self.channel_id();
+            state.channels.push(id);
+            channels.push(
+                TextChannelBuilder::new(guild_id, id)
+                    .name(format!("channel {}", position))
+                    .position(position)
+                    .build(),
+            );
+        }
+
+        for position in 0..self.rng.gen_range(0..4) {
+            let id = self.role_id();
+            state.roles.push(id);
+            roles.push(
+                RoleBuilder::new(id)
+                    .name(format!("role {}", position))
+                    .position(position)
+                    .build(),
+            );
+        }
+
+        self.guilds.push(state);
+        let guild = self.guilds.len() - 1;
+
+        for _ in 0..self.rng.gen_range(1..6) {
+            let user_id = self.user_id();
+            let roles = self.some_roles(guild);
+
+            self.guilds[guild].members.push(user_id);
+            self.seen_members.insert((guild_id, user_id));
+            members.push(MemberBuilder::new(guild_id, user_id).roles(roles).build());
+        }
+
+        let owner_id = self.guilds[guild].members[0];
+
+        Event::GuildCreate(Box::new(
+            GuildBuilder::new(guild_id, owner_id)
+                .channels(channels)
+                .roles(roles)
+                .members(members)
+                .create(),
+        ))
+    }
+
+    fn guild_delete(&mut self) -> Event {
+        let guild = self.pick_guild();
+        let state = self.guilds.swap_remove(guild);
+        self.messages.retain(|message| message.guild_id != state.id);
+
+        Event::GuildDelete(Box::new(GuildDelete {
+            id: state.id,
+            unavailable: false,
+        }))
+    }
+
+    fn member_add(&mut self) -> Event {
+        let guild = self.pick_guild();
+        let guild_id = self.guilds[guild].id;
+        let user_id = self.user_id();
+        let roles = self.some_roles(guild);
+
+        self.guilds[guild].members.push(user_id);
+        self.seen_members.insert((guild_id, user_id));
+
+        Event::MemberAdd(Box::new(
+            MemberBuilder::new(guild_id, user_id).roles(roles).add(),
+        ))
+    }
+
+    fn member_update(&mut self) -> Option<Event> {
+        let guild = self.pick_guild();
+        let user_id = *self.guilds[guild].members.choose(&mut self.rng)?;
+        let roles = self.some_roles(guild);
+        let nick = self
+            .rng
+            .gen_bool(0.5)
+            .then(|| format!("nick {}", self.rng.gen::<u16>()));
+
+        Some(Event::MemberUpdate(Box::new(MemberUpdate {
+            deaf: Some(self.rng.gen()),
+            guild_id: self.guilds[guild].id,
+            joined_at: testing::timestamp(1_632_072_645),
+            mute: Some(self.rng.gen()),
+            nick,
+            pending: self.rng.gen_bool(0.1),
+            premium_since: None,
+            roles,
+            user: UserBuilder::new(user_id).build(),
+        })))
+    }
+
+    fn member_remove(&mut self) -> Option<Event> {
+        let guild = self.pick_guild();
+        let members = &mut self.guilds[guild].members;
+
+        if members.is_empty() {
+            return None;
+        }
+
+        let user_id = members.swap_remove(self.rng.gen_range(0..members.len()));
+
+        Some(Event::MemberRemove(MemberRemove {
+            guild_id: self.guilds[guild].id,
+            user: UserBuilder::new(user_id).build(),
+        }))
+    }
+
+    fn role_create(&mut self) -> Event {
+        let guild = self.pick_guild();
+        let id = self.role_id();
+        self.guilds[guild].roles.push(id);
+
+        Event::RoleCreate(RoleCreate {
+            guild_id: self.guilds[guild].id,
+            role: RoleBuilder::new(id)
+                .position(self.rng.gen_range(0..10))
+                .build(),
+        })
+    }
+
+    fn role_update(&mut self) -> Option<Event> {
+        let guild = self.pick_guild();
+        let id = *self.guilds[guild].roles.choose(&mut self.rng)?;
+        let name = format!("renamed {}", self.rng.gen::<u16>());
+
+        Some(Event::RoleUpdate(RoleUpdate {
+            guild_id: self.guilds[guild].id,
+            role: RoleBuilder::new(id)
+                .name(name)
+                .position(self.rng.gen_range(0..10))
+                .build(),
+        }))
+    }
+
+    fn role_delete(&mut self) -> Option<Event> {
+        let guild = self.pick_guild();
+        let roles = &mut self.guilds[guild].roles;
+
+        if roles.is_empty() {
+            return None;
+        }
+
+        let role_id = roles.swap_remove(self.rng.gen_range(0..roles.len()));
+
+        Some(Event::RoleDelete(RoleDelete {
+            guild_id: self.guilds[guild].id,
+            role_id,
+        }))
+    }
+
+    fn channel_create(&mut self) -> Event {
+        let guild = self.pick_guild();
+        let guild_id = self.guilds[guild].id;
+        let id = self.channel_id();
+        self.guilds[guild].channels.push(id);
+
+        let channel = TextChannelBuilder::new(guild_id, id)
+            .position(self.rng.gen_range(0..10))
+            .build();
+
+        Event::ChannelCreate(ChannelCreate(Channel::Guild(channel)))
+    }
+
+    fn channel_update(&mut self) -> Option<Event> {
+        let guild = self.pick_guild();
+        let guild_id = self.guilds[guild].id;
+        let id = *self.guilds[guild].channels.choose(&mut self.rng)?;
+
+        let channel = TextChannelBuilder::new(guild_id, id)
+            .name(format!("renamed {}", self.rng.gen::<u16>()))
+            .position(self.rng.gen_range(0..10))
+            .build();
+
+        Some(Event::ChannelUpdate(ChannelUpdate(Channel::Guild(channel))))
+    }
+
+    fn channel_delete(&mut self) -> Option<Event> {
+        let guild = self.pick_guild();
+        let guild_id = self.guilds[guild].id;
+        let channels = &mut self.guilds[guild].channels;
+
+        if channels.is_empty() {
+            return None;
+        }
+
+        let id = channels.swap_remove(self.rng.gen_range(0..channels.len()));
+        self.messages.retain(|message| message.channel_id != id);
+
+        let channel = TextChannelBuilder::new(guild_id, id).build();
+
+        Some(Event::ChannelDelete(ChannelDelete(Channel::Guild(channel))))
+    }
+
+    fn message_create(&mut self) -> Option<Event> {
+        let guild = self.pick_guild();
+        let guild_id = self.guilds[guild].id;
+        let channel_id = *self.guilds[guild].channels.choose(&mut self.rng)?;
+        let author = *self.guilds[guild].members.choose(&mut self.rng)?;
+
+        let in_channel = self
+            .messages
+            .iter()
+            .filter(|message| message.channel_id == channel_id)
+            .count();
+
+        if in_channel >= MESSAGES_PER_CHANNEL {
+            return self.message_delete();
+        }
+
+        let id = MessageId::new(self.id()).expect("non zero");
+        self.seen_messages.push((guild_id, id));
+        self.messages.push(MessageState {
+            guild_id,
+            channel_id,
+            id,
+            reactions: Vec::new(),
+        });
+
+        let content = format!("message {}", self.rng.gen::<u32>());
+
+        Some(Event::MessageCreate(Box::new(
+            MessageBuilder::new(guild_id, channel_id, id, author)
+                .content(content)
+                .create(),
+        )))
+    }
+
+    fn message_update(&mut self) -> Option<Event> {
+        let index = self.pick_message()?;
+        let message = &self.messages[index];
+        let (guild_id, channel_id, id) = (message.guild_id, message.channel_id, message.id);
+
+        Some(Event::MessageUpdate(Box::new(
+            MessageUpdateBuilder::new(guild_id, channel_id, id)
+                .content(format!("edited {}", self.rng.gen::<u32>()))
+                .edited_timestamp(testing::timestamp(1_632_072_700))
+                .pinned(self.rng.gen())
+                .build(),
+        )))
+    }
+
+    fn message_delete(&mut self) -> Option<Event> {
+        let index = self.pick_message()?;
+        let message = self.messages.swap_remove(index);
+
+        Some(Event::MessageDelete(MessageDelete {
+            channel_id: message.channel_id,
+            guild_id: Some(message.guild_id),
+            id: message.id,
+        }))
+    }
+
+    fn message_delete_bulk(&mut self) -> Option<Event> {
+        let index = self.pick_message()?;
+        let message = &self.messages[index];
+        let (guild_id, channel_id) = (message.guild_id, message.channel_id);
+
+        let mut ids = Vec::new();
+
+        self.messages.retain(|message| {
+            let deleted = message.channel_id == channel_id && ids.len() < 5;
+
+            if deleted {
+                ids.push(message.id);
+            }
+
+            !deleted
+        });
+
+        Some(Event::MessageDeleteBulk(MessageDeleteBulk {
+            channel_id,
+            guild_id: Some(guild_id),
+            ids,
+        }))
+    }
+
+    fn reaction_add(&mut self) -> Option<Event> {
+        let index = self.pick_message()?;
+        let emoji = self.rng.gen_range(0..EMOJIS.len());
+        let user_id = self.user_id();
+
+        let message = &mut self.messages[index];
+
+        match message.reactions.iter_mut().find(|(e, _)| *e == emoji) {
+            Some((_, users)) => users.push(user_id),
+            None => message.reactions.push((emoji, vec![user_id])),
+        }
+
+        Some(Event::ReactionAdd(Box::new(
+            ReactionBuilder::new(
+                message.guild_id,
+                message.channel_id,
+                message.id,
+                user_id,
+                testing::unicode_emoji(EMOJIS[emoji]),
+            )
+            .add(),
+        )))
+    }
+
+    fn reaction_remove(&mut self) -> Option<Event> {
+        let index = self.pick_message()?;
+        let message = &mut self.messages[index];
+
+        if message.reactions.is_empty() {
+            return None;
+        }
+
+        let reaction = self.rng.gen_range(0..message.reactions.len());
+        let (emoji, users) = &mut message.reactions[reaction];
+        let emoji = *emoji;
+        let user_id = users.swap_remove(self.rng.gen_range(0..users.len()));
+
+        if users.is_empty() {
+            message.reactions.remove(reaction);
+        }
+
+        Some(Event::ReactionRemove(Box::new(
+            ReactionBuilder::new(
+                message.guild_id,
+                message.channel_id,
+                message.id,
+                user_id,
+                testing::unicode_emoji(EMOJIS[emoji]),
+            )
+            .remove(),
+        )))
+    }
+
+    fn reaction_remove_all(&mut self) -> Option<Event> {
+        let index = self.pick_message()?;
+        let message = &mut self.messages[index];
+        message.reactions.clear();
+
+        Some(Event::ReactionRemoveAll(ReactionRemoveAll {
+            channel_id: message.channel_id,
+            guild_id: Some(message.guild_id),
+            message_id: message.id,
+        }))
+    }
+
+    fn reaction_remove_emoji(&mut self) -> Option<Event> {
+        let index = self.pick_message()?;
+        let message = &mut self.messages[index];
+
+        if message.reactions.is_empty() {
+            return None;
+        }
+
+        let (emoji, _) = message
+            .reactions
+            .remove(self.rng.gen_range(0..message.reactions.len()));
+
+        Some(Event::ReactionRemoveEmoji(ReactionRemoveEmoji {
+            channel_id: message.channel_id,
+            emoji: testing::unicode_emoji(EMOJIS[emoji]),
+            guild_id: message.guild_id,
+            message_id: message.id,
+        }))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct GuildSnapshot {
+    id: GuildId,
+    name: String,
+    owner_id: UserId,
+    afk_timeout: u64,
+    member_count: Option<u64>,
+    premium_tier: PremiumTier,
+    unavailable: bool,
+    verification_level: VerificationLevel,
+    members: HashSet<UserId>,
+    roles: HashSet<RoleId>,
+    channels: HashSet<ChannelId>,
+}
+
+#[derive(Debug, PartialEq)]
+struct MemberSnapshot {
+    deaf: Option<bool>,
+    mute: Option<bool>,
+    nick: Option<String>,
+    pending: bool,
+    roles: Vec<RoleId>,
+}
+
+#[derive(Debug, PartialEq)]
+struct MessageSnapshot {
+    author: UserId,
+    channel_id: ChannelId,
+    content: String,
+    pinned: bool,
+    reactions: Vec<(ReactionType, u64, bool)>,
+}
+
+fn reactions(reactions: &[MessageReaction]) -> Vec<(ReactionType, u64, bool)> {
+    reactions
+        .iter()
+        .map(|reaction| (reaction.emoji.clone(), reaction.count, reaction.me))
+        .collect()
+}
+
+fn set<T, U: Eq + std::hash::Hash>(ids: Vec<T>, f: impl Fn(T) -> Option<U>) -> HashSet<U> {
+    ids.into_iter().filter_map(f).collect()
+}
+
+async fn assert_equivalent(memory: &InMemoryCache, redis: &InRedisCache, world: &World, ctx: &str) {
+    for &guild_id in &world.seen_guilds {
+        let expected = memory.guild(guild_id).map(|guild| GuildSnapshot {
+            id: guild.id(),
+            name: guild.name().to_owned(),
+            owner_id: guild.owner_id(),
+            afk_timeout: guild.afk_timeout(),
+            member_count: world.member_count(guild_id),
+            premium_tier: guild.premium_tier(),
+            unavailable: guild.unavailable(),
+            verification_level: guild.verification_level(),
+            members: memory
+                .guild_members(guild_id)
+                .map(|ids| ids.value().clone())
+                .unwrap_or_default(),
+            roles: memory
+                .guild_roles(guild_id)
+                .map(|ids| ids.value().clone())
+                .unwrap_or_default(),
+            channels: memory
+                .guild_channels(guild_id)
+                .map(|ids| ids.value().clone())
+                .unwrap_or_default(),
+        });
+
+        let actual = match redis.guilds.get(guild_id.get()).await {
+            Some(guild) => Some(GuildSnapshot {
+                id: guild.id(),
+                name: guild.name().to_owned(),
+                owner_id: guild.owner_id(),
+                afk_timeout: guild.afk_timeout(),
+                member_count: guild.member_count(),
+                premium_tier: guild.premium_tier(),
+                unavailable: guild.unavailable(),
+                verification_level: guild.verification_level(),
+                members: set(
+                    redis.guild_members.get(guild_id.get()).await.expect(ctx),
+                    UserId::new,
+                ),
+                roles: set(
+                    redis.guild_roles.get(guild_id.get()).await.expect(ctx),
+                    RoleId::new,
+                ),
+                channels: set(
+                    redis.guild_channels.get(guild_id.get()).await.expect(ctx),
+                    ChannelId::new,
+                ),
+            }),
+            None => None,
+        };
+
+        assert_eq!(expected, actual, "guild {}, {}", guild_id, ctx);
+    }
+
+    for &(guild_id, user_id) in &world.seen_members {
+        let expected = memory
+            .member(guild_id, user_id)
+            .map(|member| MemberSnapshot {
+                deaf: member.deaf(),
+                mute: member.mute(),
+                nick: member.nick().map(ToOwned::to_owned),
+                pending: member.pending(),
+                roles: member.roles().to_vec(),
+            });
+        let actual = redis
+            .members
+            .get((guild_id.get(), user_id.get()))
+            .await
+            .map(|member| MemberSnapshot {
+                deaf: member.deaf(),
+                mute: member.mute(),
+                nick: member.nick().map(ToOwned::to_owned),
+                pending: member.pending(),
+                roles: member.roles().to_vec(),
+            });
+
+        assert_eq!(
+            expected, actual,
+            "member {} of guild {}, {}",
+            user_id, guild_id, ctx
+        );
+
+        let rebuilt = redis
+            .rebuild_member(guild_id, user_id)
+            .await
+            .expect(ctx)
+            .map(|member| MemberSnapshot {
+                deaf: Some(member.deaf),
+                mute: Some(member.mute),
+                nick: member.nick,
+                pending: member.pending,
+                roles: member.roles,
+            });
+
+        assert_eq!(
+            expected, rebuilt,
+            "rebuilt member {} of guild {}, {}",
+            user_id, guild_id, ctx
+        );
+    }
+
+    for &role_id in &world.seen_roles {
+        let expected = memory
+            .role(role_id)
+            .map(|role| (role.guild_id(), role.resource().clone()));
+        let actual = redis
+            .roles
+            .get(role_id.get())
+            .await
+            .map(|role| (role.guild_id(), role.resource().clone()));
+
+        assert_eq!(expected, actual, "role {}, {}", role_id, ctx);
+    }
+
+    for &channel_id in &world.seen_channels {
+        let expected = memory
+            .guild_channel(channel_id)
+            .map(|channel| (channel.guild_id(), channel.resource().clone()));
+        let actual = redis
+            .channels_guild
+            .get(channel_id.get())
+            .await
+            .map(|channel| (channel.guild_id(), channel.resource().clone()));
+
+        assert_eq!(expected, actual, "channel {}, {}", channel_id, ctx);
+
+        // The in-memory cache doesn't expose its message index, every
+        // message it still has in the channel is in it.
+        let expected = world
+            .seen_messages
+            .iter()
+            .map(|&(_, message_id)| message_id)
+            .filter(|&message_id| {
+                memory
+                    .message(message_id)
+                    .map(|message| message.channel_id())
+                    == Some(channel_id)
+            })
+            .collect::<HashSet<_>>();
+        let actual = set(
+            redis
+                .channel_messages
+                .get(channel_id.get())
+                .await
+                .expect(ctx),
+            MessageId::new,
+        );
+
+        assert_eq!(
+            expected, actual,
+            "messages of channel {}, {}",
+            channel_id, ctx
+        );
+    }
+
+    for &(guild_id, message_id) in &world.seen_messages {
+        let expected = memory.message(message_id).map(|message| MessageSnapshot {
+            author: message.author(),
+            channel_id: message.channel_id(),
+            content: message.content().to_owned(),
+            pinned: message.pinned(),
+            reactions: reactions(message.reactions()),
+        });
+        let actual = redis
+            .message(Some(guild_id), message_id)
+            .await
+            .map(|message| MessageSnapshot {
+                author: message.author(),
+                channel_id: message.channel_id(),
+                content: message.content().to_owned(),
+                pinned: message.pinned(),
+                reactions: reactions(message.reactions()),
+            });
+
+        assert_eq!(expected, actual, "message {}, {}", message_id, ctx);
+    }
+
+    // The in-memory cache only counts reactions, who added them is checked
+    // against the world.
+    for message in &world.messages {
+        for (emoji, name) in EMOJIS.iter().enumerate() {
+            let expected = message
+                .reactions
+                .iter()
+                .find(|(e, _)| *e == emoji)
+                .map(|(_, users)| users.iter().copied().collect::<HashSet<_>>())
+                .unwrap_or_default();
+            let actual = redis
+                .reacted_users(message.id, &testing::unicode_emoji(name))
+                .await
+                .expect(ctx)
+                .into_iter()
+                .collect::<HashSet<_>>();
+
+            assert_eq!(
+                expected, actual,
+                "users reacting to message {} with {}, {}",
+                message.id, name, ctx
+            );
+        }
+    }
+}
+
+async fn run(seed: u64, steps: usize) {
+    let memory = InMemoryCache::new();
+    let redis = InRedisCache::new();
+    let mut world = World::new(seed);
+
+    for step in 0..steps {
+        let event = world.next_event();
+        let ctx = format!("seed {} step {} ({:?})", seed, step, event.kind());
+
+        memory.update(&event);
+        redis.update(&event).await;
+
+        assert_equivalent(&memory, &redis, &world, &ctx).await;
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs the Redis server from docker-compose.yml"]
+async fn test_conformance() {
+    let seed = env::var("CONFORMANCE_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(rand::random);
+    let steps = env::var("CONFORMANCE_STEPS")
+        .ok()
+        .and_then(|steps| steps.parse().ok())
+        .unwrap_or(300);
+
+    run(seed, steps).await;
+}